@@ -0,0 +1,328 @@
+use crate::checksum::ChecksumAlgorithm;
+use crate::error::RBError;
+use crate::s3::{
+    BucketEncryption, CorsRule, DeleteFailure, GetOptions, IncompleteUpload, ObjectEntry,
+    ObjectGrant, ObjectHead, ObjectLockStatus, SelectQuery, Usage, VersionEntry,
+};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use tokio::io::AsyncWrite;
+
+/// Everything the commands need from a remote object store. `RBS3` is the real one; anything else that can hold
+/// buckets of keyed objects can stand in for it.
+///
+/// Keys are full object keys, and prefixes are key prefixes that end in `/` when they name a "directory".
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn list_buckets(&self) -> Result<Vec<String>, RBError>;
+
+    /// Lists the "directories" (with a trailing `/`) and then the objects directly under `prefix`, relative to it, with
+    /// whatever details the backend knows about each one
+    async fn list_entries(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> Result<Vec<ObjectEntry>, RBError>;
+
+    /// Like `list_entries`, but as a stream that yields the entries as they're fetched rather than all at the end, and
+    /// in no particular order across pages. Backends that fetch a page at a time should override this; the default
+    /// lists everything up front.
+    fn list_files_stream(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> BoxStream<'_, Result<ObjectEntry, RBError>> {
+        stream::once(self.list_entries(bucket, prefix))
+            .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Like `list_entries`, but just the names
+    async fn list_files(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> Result<Vec<String>, RBError> {
+        let entries = self.list_entries(bucket, prefix).await?;
+        Ok(entries.into_iter().map(|entry| entry.name).collect())
+    }
+
+    /// Lists the full keys of every object under `prefix`, however deeply nested
+    async fn list_keys(&self, bucket: String, prefix: String) -> Result<Vec<String>, RBError>;
+
+    /// Counts the objects under `prefix` and adds up their sizes, which means listing every one of them. If there turn
+    /// out to be more than `max_objects`, it gives up and returns None.
+    async fn usage(
+        &self,
+        bucket: String,
+        prefix: String,
+        max_objects: Option<u64>,
+    ) -> Result<Option<Usage>, RBError>;
+
+    /// Lists the prefixes and keys directly under `dir_prefix` whose names start with `partial`, relative to
+    /// `dir_prefix`, for tab completion. This may stop short of the full list.
+    async fn list_completions(
+        &self,
+        bucket: String,
+        dir_prefix: String,
+        partial: &str,
+    ) -> Result<Vec<String>, RBError>;
+
+    async fn object_exists(&self, bucket: String, key: String) -> Result<bool, RBError>;
+
+    /// The object's size and modification time, or `None` if there's no such object
+    async fn head_object(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+    ) -> Result<Option<ObjectHead>, RBError>;
+
+    /// Writes the object's body to `dest_path`, creating or truncating it
+    async fn download_object(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+        dest_path: &Path,
+    ) -> Result<(), RBError>;
+
+    async fn download_object_to_stdout(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+    ) -> Result<(), RBError>;
+
+    /// Runs an S3 Select query against the object, writing the records it returns to `out` as they come in
+    async fn select_object(
+        &self,
+        bucket: String,
+        key: String,
+        query: &SelectQuery,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<(), RBError>;
+
+    /// Uploads the file at `source_path`, replacing any object that's already at that key
+    async fn put_object(
+        &self,
+        bucket: String,
+        key: String,
+        source_path: &Path,
+        metadata: HashMap<String, String>,
+        checksum: Option<ChecksumAlgorithm>,
+    ) -> Result<(), RBError>;
+
+    async fn delete_object(&self, bucket: String, key: String) -> Result<(), RBError>;
+
+    /// Deletes many objects, as few requests as possible, and returns the ones that couldn't be deleted. It's only an
+    /// error if a whole request fails.
+    async fn delete_objects(
+        &self,
+        bucket: String,
+        keys: Vec<String>,
+    ) -> Result<Vec<DeleteFailure>, RBError>;
+
+    /// Deletes one version of an object for good. Deleting the delete marker that's an object's latest version brings
+    /// the object back.
+    async fn delete_object_version(
+        &self,
+        bucket: String,
+        key: String,
+        version_id: String,
+    ) -> Result<(), RBError>;
+
+    /// Copies an object within the store, without downloading it, and returns its size
+    async fn copy_object(
+        &self,
+        source_bucket: String,
+        source_key: String,
+        dest_bucket: String,
+        dest_key: String,
+    ) -> Result<u64, RBError>;
+
+    /// Moves an object to another storage class, keeping its contents and metadata as they are
+    async fn change_storage_class(
+        &self,
+        bucket: String,
+        key: String,
+        storage_class: String,
+    ) -> Result<(), RBError>;
+
+    /// Replaces all of the object's user metadata with `metadata`, keeping its contents and content headers as they are
+    async fn set_object_metadata(
+        &self,
+        bucket: String,
+        key: String,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), RBError>;
+
+    /// Changes the object's Content-Type, keeping its contents and the rest of its metadata as they are
+    async fn set_content_type(
+        &self,
+        bucket: String,
+        key: String,
+        content_type: String,
+    ) -> Result<(), RBError>;
+
+    /// Starts bringing an archived object back for `days` days, at one of the `RESTORE_TIERS` (or S3's default,
+    /// Standard). It's done once `head_object` reports its restore as ready. Asking while a restore is already underway
+    /// isn't an error.
+    async fn restore_object(
+        &self,
+        bucket: String,
+        key: String,
+        days: i64,
+        tier: Option<String>,
+    ) -> Result<(), RBError>;
+
+    /// Lists the multipart uploads under `prefix` that were started but never completed or aborted
+    async fn list_incomplete_uploads(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> Result<Vec<IncompleteUpload>, RBError>;
+
+    /// Every version of every object under `prefix`, delete markers included, newest first for each key
+    async fn list_object_versions(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> Result<Vec<VersionEntry>, RBError>;
+
+    /// The grants in the object's access control list
+    async fn object_grants(&self, bucket: String, key: String)
+        -> Result<Vec<ObjectGrant>, RBError>;
+
+    /// Replaces the object's access control list with one of the `CANNED_ACLS`
+    async fn set_object_acl(&self, bucket: String, key: String, acl: String)
+        -> Result<(), RBError>;
+
+    /// The object's tags, by name
+    async fn object_tags(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<BTreeMap<String, String>, RBError>;
+
+    /// Replaces all of the object's tags with `tags`, which may be empty to remove them all
+    async fn set_object_tags(
+        &self,
+        bucket: String,
+        key: String,
+        tags: BTreeMap<String, String>,
+    ) -> Result<(), RBError>;
+
+    /// The bucket's CORS rules, in the order that S3 checks them
+    async fn bucket_cors_rules(&self, bucket: String) -> Result<Vec<CorsRule>, RBError>;
+
+    /// The bucket's default encryption, or None if it has none
+    async fn bucket_encryption(&self, bucket: String) -> Result<Option<BucketEncryption>, RBError>;
+
+    /// The object's Object Lock retention and legal hold. An object without any has the default status.
+    async fn object_lock_status(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<ObjectLockStatus, RBError>;
+
+    /// Aborts a multipart upload, throwing away the parts uploaded so far
+    async fn abort_incomplete_upload(
+        &self,
+        bucket: String,
+        key: String,
+        upload_id: String,
+    ) -> Result<(), RBError>;
+
+    /// The bucket's versioning state as S3 names it (`Enabled` or `Suspended`), or None if versioning has never been
+    /// turned on
+    async fn bucket_versioning(&self, bucket: String) -> Result<Option<String>, RBError>;
+
+    /// Turns versioning on, or suspends it. Once turned on, versioning can only ever be suspended, not turned off.
+    async fn set_bucket_versioning(&self, bucket: String, enabled: bool) -> Result<(), RBError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_backend::tests::temp_root;
+    use crate::local_backend::LocalBackend;
+
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn commands_can_work_against_any_backend() {
+        let root = temp_root("backend", &["docs/guide.txt", "docs/notes/a.txt"]);
+        let backend: Arc<dyn StorageBackend> = Arc::new(LocalBackend::new(root.clone()).unwrap());
+        let bucket = String::from("docs");
+
+        assert_eq!(backend.list_buckets().await.unwrap(), vec!["docs"]);
+        assert_eq!(
+            backend.list_files(bucket.clone(), None).await.unwrap(),
+            vec!["notes/", "guide.txt"]
+        );
+
+        let upload = root.join("upload.txt");
+        std::fs::write(&upload, "uploaded").unwrap();
+        backend
+            .put_object(
+                bucket.clone(),
+                "new/upload.txt".into(),
+                &upload,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        let head = backend
+            .head_object(
+                bucket.clone(),
+                "new/upload.txt".into(),
+                &GetOptions::default(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(head.size, 8);
+
+        let copied = backend
+            .copy_object(
+                bucket.clone(),
+                "new/upload.txt".into(),
+                bucket.clone(),
+                "copy.txt".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(copied, 8);
+
+        let download = root.join("download.txt");
+        backend
+            .download_object(
+                bucket.clone(),
+                "copy.txt".into(),
+                &GetOptions::default(),
+                &download,
+            )
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&download).unwrap(), "uploaded");
+
+        backend
+            .delete_object(bucket.clone(), "copy.txt".into())
+            .await
+            .unwrap();
+        assert!(!backend
+            .object_exists(bucket.clone(), "copy.txt".into())
+            .await
+            .unwrap());
+        let mut keys = backend.list_keys(bucket, String::new()).await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["guide.txt", "new/upload.txt", "notes/a.txt"]);
+    }
+}
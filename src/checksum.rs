@@ -0,0 +1,62 @@
+use crate::error::{ErrorKind, RBError};
+
+use std::path::Path;
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Additional integrity checksums that S3 can verify on upload via the `x-amz-checksum-*` headers
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32c => "x-amz-checksum-crc32c",
+            ChecksumAlgorithm::Sha256 => "x-amz-checksum-sha256",
+        }
+    }
+
+    /// Computes this checksum over the whole file, returning it base64-encoded the way S3 expects it
+    pub async fn compute(&self, path: &Path) -> Result<String, RBError> {
+        let mut file = File::open(path).await.map_err(RBError::wrap_io)?;
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut sha256 = Sha256::new();
+        let mut crc32c: u32 = 0;
+
+        loop {
+            let read_count = file.read(&mut buf).await.map_err(RBError::wrap_io)?;
+            if read_count == 0 {
+                break;
+            }
+            match self {
+                ChecksumAlgorithm::Crc32c => {
+                    crc32c = crc32c::crc32c_append(crc32c, &buf[..read_count])
+                }
+                ChecksumAlgorithm::Sha256 => sha256.update(&buf[..read_count]),
+            }
+        }
+
+        Ok(match self {
+            ChecksumAlgorithm::Crc32c => base64::encode(crc32c.to_be_bytes()),
+            ChecksumAlgorithm::Sha256 => base64::encode(sha256.finalize()),
+        })
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = RBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "crc32c" => Ok(ChecksumAlgorithm::Crc32c),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+        }
+    }
+}
@@ -1,33 +1,816 @@
-use crate::error::{ErrorKind, RBError};
-use crate::s3::{S3Path, RBS3};
+use crate::backend::StorageBackend;
+use crate::checksum::ChecksumAlgorithm;
+use crate::error::{ErrorContext, ErrorKind, RBError};
+use crate::s3::{
+    AccessPointArn, GetOptions, IncompleteUpload, ObjectEntry, ObjectHead, RestoreStatus, S3Path,
+    SelectFormat, SelectQuery, Usage, VersionEntry,
+};
 
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::read_dir;
-use std::io;
-use std::path::Path;
+use std::fmt;
+use std::fs::{read_dir, remove_file, rename, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use glob::{MatchOptions, Pattern};
 use path_clean::PathClean; // We use canonicalize() for local paths, but path_clean for remote paths
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::process;
+use tokio::time::sleep;
 
-pub async fn list_remote_path(s3: &RBS3, s3_path: S3Path) -> Result<String, RBError> {
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferOutcome {
+    Downloaded,
+    Uploaded,
+    /// The object was written to stdout rather than to a file
+    Streamed,
+    /// The destination was already up to date, per --skip-existing or --newer-only
+    Skipped,
+    /// The object was copied within S3, without passing through here
+    Copied,
+}
+
+/// What a get or put ended up doing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransferReport {
+    pub outcome: TransferOutcome,
+    pub source: String,
+    pub destination: String,
+    /// How much was actually transferred: 0 if it was skipped, or streamed to stdout
+    pub bytes: u64,
+}
+
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.outcome {
+            TransferOutcome::Downloaded => {
+                write!(f, "File downloaded successfully: {}", self.destination)
+            }
+            TransferOutcome::Uploaded => {
+                write!(f, "File uploaded successfully: {}", self.destination)
+            }
+            TransferOutcome::Streamed => Ok(()),
+            TransferOutcome::Copied => {
+                write!(f, "Object copied successfully: {}", self.destination)
+            }
+            TransferOutcome::Skipped => {
+                write!(
+                    f,
+                    "Skipping, destination is up to date: {}",
+                    self.destination
+                )
+            }
+        }
+    }
+}
+
+/// What a transfer should do when its destination already exists
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExistingTargetPolicy {
+    /// Refuse with `TargetAlreadyExists` (the interactive prompt may then ask the user about it)
+    Fail,
+    /// Overwrite the destination unconditionally (`-f`)
+    Overwrite,
+    /// Leave the destination alone if it has the same size as the source (`--skip-existing`)
+    SkipExisting,
+    /// Only overwrite the destination if the source was modified more recently (`--newer-only`)
+    NewerOnly,
+}
+
+/// Optional parameters for uploading a file
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    /// User-defined metadata to store with the object (`--meta`)
+    pub metadata: HashMap<String, String>,
+    /// Have S3 check the upload against a checksum of this kind (`--checksum`)
+    pub checksum: Option<ChecksumAlgorithm>,
+}
+
+// Decides whether a transfer can be skipped given what we know about its source and (existing) destination. Missing
+// timestamps are treated as "we can't tell," which always means the transfer goes ahead.
+fn should_skip_transfer(
+    policy: ExistingTargetPolicy,
+    source: &ObjectHead,
+    destination: &ObjectHead,
+) -> bool {
+    match policy {
+        ExistingTargetPolicy::SkipExisting => source.size == destination.size,
+        ExistingTargetPolicy::NewerOnly => {
+            match (source.last_modified, destination.last_modified) {
+                (Some(src_modified), Some(dest_modified)) => src_modified <= dest_modified,
+                _ => false,
+            }
+        }
+        ExistingTargetPolicy::Fail | ExistingTargetPolicy::Overwrite => false,
+    }
+}
+
+// Downloads are written to `<dest>.part` and only renamed into place once they complete, so the destination is never
+// left holding a truncated file. If this is dropped before `finish()` is called, the `.part` file is deleted. Because
+// that happens on drop, it also cleans up after downloads whose futures get dropped partway through, which is how
+// Ctrl-C cancels a command.
+struct PartialDownload {
+    part_path: PathBuf,
+    finished: bool,
+}
+
+impl PartialDownload {
+    fn new(dest_path: &Path) -> Self {
+        let mut part_name = dest_path
+            .file_name()
+            .unwrap_or(OsStr::new("unknown_s3_file"))
+            .to_os_string();
+        part_name.push(".part");
+        PartialDownload {
+            part_path: dest_path.with_file_name(part_name),
+            finished: false,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.part_path
+    }
+
+    fn finish(mut self, dest_path: &Path) -> Result<(), RBError> {
+        rename(&self.part_path, dest_path).map_err(RBError::wrap_io)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialDownload {
+    fn drop(&mut self) {
+        if !self.finished && self.part_path.is_file() {
+            if let Err(e) = remove_file(&self.part_path) {
+                eprintln!(
+                    "Unable to clean up partial download {}: {}",
+                    self.part_path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+fn local_file_head(path: &Path) -> Result<ObjectHead, RBError> {
+    let meta = path.metadata().map_err(RBError::wrap_io)?;
+    Ok(ObjectHead {
+        size: meta.len(),
+        last_modified: meta.modified().ok().map(DateTime::<Utc>::from),
+        restore: None,
+        metadata: HashMap::new(),
+    })
+}
+
+/// Resolves a remote path argument against the remote cwd. `s3://bucket/key` URIs are absolute, so they ignore the cwd,
+/// and so are access point ARNs, with or without `s3://` in front.
+pub fn resolve_remote_path(remote_cwd: &Path, target: &str) -> PathBuf {
+    if let Some((access_point, rest)) =
+        AccessPointArn::split_path(target.strip_prefix("s3://").unwrap_or(target))
+    {
+        return Path::new("/")
+            .join(access_point.path_component())
+            .join(rest)
+            .clean();
+    }
+    match target.strip_prefix("s3://") {
+        Some(bucket_and_key) => Path::new("/").join(bucket_and_key).clean(),
+        None => remote_cwd.join(target).clean(),
+    }
+}
+
+/// Expands a leading `~` in a local path argument, like a shell would have if the path had been given on the command
+/// line. `$VAR`s have already been expanded by the tokenizer (unless they were single-quoted), so they're left alone.
+pub fn expand_local_path(target: &str) -> PathBuf {
+    match (target.strip_prefix('~'), dirs_next::home_dir()) {
+        (Some(""), Some(home)) => home,
+        (Some(after_tilde), Some(home)) if after_tilde.starts_with('/') => {
+            home.join(after_tilde.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(target),
+    }
+}
+
+/// Resolves a local path argument against the local cwd, after expanding it with `expand_local_path`
+pub fn resolve_local_path(local_cwd: &Path, target: &str) -> PathBuf {
+    local_cwd.join(expand_local_path(target))
+}
+
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+/// Whether a path argument is a glob pattern rather than a plain path
+pub fn is_glob(target: &str) -> bool {
+    target.contains(&GLOB_CHARS[..])
+}
+
+/// Resolves a remote glob pattern to the paths of the objects that it matches. Everything under the part of the pattern
+/// before its first wildcard is listed, and then matched client-side. Like in a shell, `*` and `?` don't match `/`.
+pub async fn expand_remote_glob(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    pattern: &str,
+) -> Result<Vec<PathBuf>, RBError> {
+    let pattern_path = resolve_remote_path(remote_cwd, pattern);
+    let (bucket, key_pattern) = match S3Path::try_from_path(&pattern_path)? {
+        S3Path {
+            bucket: Some(bucket),
+            key: Some(key),
+        } if !is_glob(&bucket) => (bucket, key),
+        // Matching bucket names would mean listing every bucket's contents, so wildcards are only allowed in keys
+        _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+    };
+
+    let first_wildcard = key_pattern
+        .find(&GLOB_CHARS[..])
+        .unwrap_or(key_pattern.len());
+    let literal_prefix = match key_pattern[..first_wildcard].rfind('/') {
+        Some(slash_index) => &key_pattern[..=slash_index],
+        None => "",
+    };
+    let matcher = Pattern::new(&key_pattern).map_err(|_| RBError::new(ErrorKind::InvalidTarget))?;
+    let match_options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+
+    let keys = s3
+        .list_keys(bucket.clone(), literal_prefix.to_owned())
+        .await?;
+    Ok(keys
+        .into_iter()
+        .filter(|key| matcher.matches_with(key, match_options))
+        .map(|key| Path::new("/").join(&bucket).join(key))
+        .collect())
+}
+
+pub async fn list_remote_path(
+    s3: &dyn StorageBackend,
+    s3_path: &S3Path,
+) -> Result<Vec<ObjectEntry>, RBError> {
     if let S3Path {
         bucket: Some(bucket),
         key,
     } = s3_path
     {
-        let key_prefix = key.map(|k| k + "/");
-        let files = s3.list_files(bucket, key_prefix).await?;
-        if files.is_empty() {
-            Ok(String::from("There are no files at this path.\n"))
+        let key_prefix = key.as_ref().map(|k| format!("{}/", k));
+        s3.list_entries(bucket.clone(), key_prefix).await
+    } else {
+        list_buckets(s3).await
+    }
+}
+
+// The bucket of the remote working directory, and the key prefix for everything under it
+fn remote_dir_prefix(remote_cwd: &Path) -> Result<(String, String), RBError> {
+    match S3Path::try_from_path(remote_cwd)? {
+        S3Path {
+            bucket: Some(bucket),
+            key,
+        } => Ok((bucket, key.map(|key| key + "/").unwrap_or_default())),
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+/// The bucket of the remote working directory
+pub fn remote_bucket(remote_cwd: &Path) -> Result<String, RBError> {
+    remote_dir_prefix(remote_cwd).map(|(bucket, _)| bucket)
+}
+
+/// Resolves a remote path argument to the bucket and key of the object it names
+pub fn resolve_remote_object(remote_cwd: &Path, target: &str) -> Result<(String, String), RBError> {
+    match S3Path::try_from_path(&resolve_remote_path(remote_cwd, target))? {
+        S3Path {
+            bucket: Some(bucket),
+            key: Some(key),
+        } => Ok((bucket, key)),
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+/// Lists the multipart uploads under the remote working directory that were never completed or aborted
+pub async fn list_incomplete_uploads(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+) -> Result<Vec<IncompleteUpload>, RBError> {
+    let (bucket, prefix) = remote_dir_prefix(remote_cwd)?;
+    s3.list_incomplete_uploads(bucket, prefix).await
+}
+
+// The bucket and key prefix to list for either one object, or everything under the remote working directory if there's
+// no target. Listing by a key as a prefix also finds longer keys that start with it, so that key comes back separately
+// for filtering those out.
+fn object_or_dir_prefix(
+    remote_cwd: &Path,
+    target: Option<&str>,
+) -> Result<(String, String, Option<String>), RBError> {
+    match target {
+        Some(target) => {
+            let (bucket, key) = resolve_remote_object(remote_cwd, target)?;
+            Ok((bucket, key.clone(), Some(key)))
+        }
+        None => {
+            let (bucket, prefix) = remote_dir_prefix(remote_cwd)?;
+            Ok((bucket, prefix, None))
+        }
+    }
+}
+
+/// Aborts the incomplete multipart uploads of the object at `target`, or every one under the remote working directory
+/// if there's no target, and returns the ones that were aborted
+pub async fn abort_incomplete_uploads(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: Option<&str>,
+) -> Result<Vec<IncompleteUpload>, RBError> {
+    let (bucket, prefix, only_key) = object_or_dir_prefix(remote_cwd, target)?;
+    let uploads: Vec<IncompleteUpload> = s3
+        .list_incomplete_uploads(bucket.clone(), prefix)
+        .await?
+        .into_iter()
+        .filter(|upload| only_key.as_ref().is_none_or(|key| upload.key == *key))
+        .collect();
+    for upload in &uploads {
+        s3.abort_incomplete_upload(bucket.clone(), upload.key.clone(), upload.upload_id.clone())
+            .await?;
+    }
+    Ok(uploads)
+}
+
+/// Lists every version of the object at `target`, or of every object under the remote working directory if there's no
+/// target, delete markers included
+pub async fn list_versions(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: Option<&str>,
+) -> Result<Vec<VersionEntry>, RBError> {
+    let (bucket, prefix, only_key) = object_or_dir_prefix(remote_cwd, target)?;
+    Ok(s3
+        .list_object_versions(bucket, prefix)
+        .await?
+        .into_iter()
+        .filter(|version| only_key.as_ref().is_none_or(|key| version.key == *key))
+        .collect())
+}
+
+/// Brings back the deleted object at `target` by removing the delete marker that's hiding it, and returns that marker.
+/// It's an error (`ErrorKind::NotFound`) if the object's latest version isn't a delete marker.
+pub async fn undelete(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: &str,
+) -> Result<VersionEntry, RBError> {
+    let (bucket, key) = resolve_remote_object(remote_cwd, target)?;
+    let latest = s3
+        .list_object_versions(bucket.clone(), key.clone())
+        .await?
+        .into_iter()
+        .find(|version| version.key == key && version.is_latest);
+    match latest {
+        Some(marker) if marker.is_delete_marker => {
+            s3.delete_object_version(bucket, key, marker.version_id.clone())
+                .await?;
+            Ok(marker)
+        }
+        // Either the object was never deleted, or it was deleted for good before versioning was on
+        _ => Err(RBError::new(ErrorKind::NotFound)),
+    }
+}
+
+// Restores take minutes at the very fastest and usually hours, so there's no point asking S3 about them more often
+const RESTORE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Waits for the restore of an archived object to finish, checking on it every so often, and returns when the restored
+/// copy will expire, if S3 says
+pub async fn wait_for_restore(
+    s3: &dyn StorageBackend,
+    bucket: String,
+    key: String,
+) -> Result<Option<DateTime<Utc>>, RBError> {
+    loop {
+        let head = s3
+            .head_object(bucket.clone(), key.clone(), &GetOptions::default())
+            .await?
+            .ok_or_else(|| RBError::new(ErrorKind::NotFound))?;
+        match head.restore {
+            Some(RestoreStatus::Ready { expiry }) => return Ok(expiry),
+            Some(RestoreStatus::InProgress) => sleep(RESTORE_POLL_INTERVAL).await,
+            // Objects that aren't archived have no restore status, and can be downloaded as they are
+            None => return Ok(None),
+        }
+    }
+}
+
+// The bucket and key of every object that a key or glob pattern argument names
+async fn resolve_remote_objects(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: &str,
+) -> Result<Vec<(String, String)>, RBError> {
+    let mut paths = Vec::new();
+    if is_glob(target) {
+        paths = expand_remote_glob(s3, remote_cwd, target).await?;
+    }
+    // Brackets are legal in keys, so a "pattern" that matches nothing might just be a literal key
+    if paths.is_empty() {
+        paths.push(resolve_remote_path(remote_cwd, target));
+    }
+    paths
+        .iter()
+        .map(|path| match S3Path::try_from_path(path)? {
+            S3Path {
+                bucket: Some(bucket),
+                key: Some(key),
+            } => Ok((bucket, key)),
+            _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+        })
+        .collect()
+}
+
+/// The path and head of every object that `target` (a key or a glob pattern) names
+pub async fn stat_objects(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: &str,
+) -> Result<Vec<(String, ObjectHead)>, RBError> {
+    let mut heads = Vec::new();
+    for (bucket, key) in resolve_remote_objects(s3, remote_cwd, target).await? {
+        let head = s3
+            .head_object(bucket.clone(), key.clone(), &GetOptions::default())
+            .await?
+            .ok_or_else(|| RBError::new(ErrorKind::NotFound))?;
+        heads.push((format!("/{}/{}", bucket, key), head));
+    }
+    Ok(heads)
+}
+
+/// Counts the objects that `target` names and adds up their sizes. A bucket or "directory" counts everything under it,
+/// and a glob pattern counts each object that it matches.
+pub async fn object_usage(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: &str,
+) -> Result<Usage, RBError> {
+    if !is_glob(target) {
+        let (bucket, key) = match S3Path::try_from_path(&resolve_remote_path(remote_cwd, target))? {
+            S3Path {
+                bucket: Some(bucket),
+                key,
+            } => (bucket, key),
+            _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+        };
+        let prefix = key
+            .as_ref()
+            .map(|key| key.clone() + "/")
+            .unwrap_or_default();
+        let usage = s3.usage(bucket, prefix, None).await?.unwrap_or_default();
+        // Nothing under it means it might be a single object instead
+        if usage.objects > 0 || key.is_none() {
+            return Ok(usage);
+        }
+    }
+
+    let mut usage = Usage::default();
+    for (_, head) in stat_objects(s3, remote_cwd, target).await? {
+        usage.objects += 1;
+        usage.bytes += head.size;
+    }
+    Ok(usage)
+}
+
+/// Runs an S3 Select query against the object at `target`, streaming its results to `out`. Unless they're given, the
+/// object's format is guessed from its extension, and results come out in the same format (or JSON, for Parquet).
+pub async fn select_object(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    target: &str,
+    expression: &str,
+    input: Option<SelectFormat>,
+    output: Option<SelectFormat>,
+    out: &mut (dyn AsyncWrite + Send + Unpin),
+) -> Result<(), RBError> {
+    let (bucket, key) = resolve_remote_object(remote_cwd, target)?;
+    let input = input
+        .or_else(|| SelectFormat::from_key(&key))
+        .ok_or_else(|| {
+            RBError::wrap(
+                ErrorKind::InvalidTarget,
+                format!(
+                    "can't tell what format {} is in; give it with --input csv, json, or parquet",
+                    target
+                ),
+            )
+        })?;
+    let output = output.unwrap_or(match input {
+        SelectFormat::Parquet => SelectFormat::Json,
+        format => format,
+    });
+    let query = SelectQuery {
+        expression: expression.to_owned(),
+        input,
+        output,
+    };
+    s3.select_object(bucket, key, &query, out).await
+}
+
+// Every key at or under the "directory" `key`, for commands given `-r`
+async fn keys_at_or_under(
+    s3: &dyn StorageBackend,
+    bucket: String,
+    key: &str,
+) -> Result<Vec<String>, RBError> {
+    let dir_prefix = format!("{}/", key);
+    Ok(s3
+        .list_keys(bucket, key.to_owned())
+        .await?
+        .into_iter()
+        .filter(|listed_key| listed_key == key || listed_key.starts_with(&dir_prefix))
+        .collect())
+}
+
+/// Moves the objects that `target` names (a key or a glob pattern) into `storage_class`, one at a time, and returns
+/// their paths. With `recursive`, everything under the "directories" it names is moved too.
+pub async fn change_storage_class(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    storage_class: &str,
+    target: &str,
+    recursive: bool,
+) -> Result<Vec<String>, RBError> {
+    let mut objects = Vec::new();
+    for (bucket, key) in resolve_remote_objects(s3, remote_cwd, target).await? {
+        if recursive {
+            let keys = keys_at_or_under(s3, bucket.clone(), &key).await?;
+            objects.extend(keys.into_iter().map(|key| (bucket.clone(), key)));
         } else {
-            Ok(files.join("\n"))
+            objects.push((bucket, key));
         }
+    }
+    objects.sort_unstable();
+    objects.dedup();
+
+    let mut changed = Vec::new();
+    for (bucket, key) in objects {
+        let path = format!("/{}/{}", bucket, key);
+        s3.change_storage_class(bucket.clone(), key.clone(), storage_class.to_owned())
+            .await
+            .map_err(|e| {
+                e.with_context(ErrorContext {
+                    operation: "changing the storage class of",
+                    bucket: Some(bucket),
+                    key: Some(key),
+                    local_path: None,
+                })
+            })?;
+        changed.push(path);
+    }
+    Ok(changed)
+}
+
+/// Changes the Content-Type of the objects that `target` names (a key or a glob pattern), one at a time, and returns
+/// their paths
+pub async fn change_content_type(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    content_type: &str,
+    target: &str,
+) -> Result<Vec<String>, RBError> {
+    let mut changed = Vec::new();
+    for (bucket, key) in resolve_remote_objects(s3, remote_cwd, target).await? {
+        let path = format!("/{}/{}", bucket, key);
+        s3.set_content_type(bucket.clone(), key.clone(), content_type.to_owned())
+            .await
+            .map_err(|e| {
+                e.with_context(ErrorContext {
+                    operation: "changing the content type of",
+                    bucket: Some(bucket),
+                    key: Some(key),
+                    local_path: None,
+                })
+            })?;
+        changed.push(path);
+    }
+    Ok(changed)
+}
+
+/// Deletes the objects at `targets`, each of which is a key or a glob pattern, and with `recursive`, everything under
+/// the "directories" they name too. Returns how many objects were deleted. Deletes go out in batches, one bucket at a
+/// time. Keys that can't be deleted don't stop the rest, but they do make it an error, which lists them all.
+pub async fn remove_objects(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    targets: &[String],
+    recursive: bool,
+) -> Result<usize, RBError> {
+    let mut keys_by_bucket: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for target in targets {
+        // A bucket root doesn't name an object, which also keeps `rm -r` from emptying a whole bucket on one slip of the
+        // keyboard
+        for (bucket, key) in resolve_remote_objects(s3, remote_cwd, target).await? {
+            let keys = keys_by_bucket.entry(bucket.clone()).or_default();
+            if recursive {
+                keys.extend(keys_at_or_under(s3, bucket, &key).await?);
+            } else {
+                keys.push(key);
+            }
+        }
+    }
+
+    let mut removed = 0;
+    let mut failures = Vec::new();
+    for (bucket, mut keys) in keys_by_bucket {
+        keys.sort_unstable();
+        keys.dedup();
+        let key_count = keys.len();
+        let bucket_failures = s3.delete_objects(bucket, keys).await?;
+        removed += key_count - bucket_failures.len();
+        failures.extend(bucket_failures);
+    }
+    match failures.first() {
+        None => Ok(removed),
+        Some(first_failure) => {
+            let details: Vec<String> = failures
+                .iter()
+                .map(|failure| format!("{} ({})", failure.key, failure.message))
+                .collect();
+            Err(RBError::wrap(
+                first_failure.kind,
+                format!(
+                    "removed {} objects, but couldn't remove {}: {}",
+                    removed,
+                    failures.len(),
+                    details.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// Copies the object at `remote_source` to `remote_destination` within S3, without downloading it. With `recursive`,
+/// everything under the source "directory" is copied to the same place under the destination, up to `parallel`
+/// objects at a time. A glob pattern source copies every object it matches into the destination "directory".
+/// Otherwise a destination ending in `/` is a "directory" to copy the object into.
+pub async fn copy_remote(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    remote_source: &str,
+    remote_destination: &str,
+    recursive: bool,
+    parallel: usize,
+) -> Result<Vec<TransferReport>, RBError> {
+    let (source_bucket, source_key) =
+        match S3Path::try_from_path(&resolve_remote_path(remote_cwd, remote_source))? {
+            S3Path {
+                bucket: Some(bucket),
+                key,
+            } => (bucket, key),
+            _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+        };
+    let (dest_bucket, dest_key) =
+        match S3Path::try_from_path(&resolve_remote_path(remote_cwd, remote_destination))? {
+            S3Path {
+                bucket: Some(bucket),
+                key,
+            } => (bucket, key),
+            _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+        };
+
+    // Brackets are legal in keys, so a "pattern" that matches nothing might just be a literal key
+    let glob_matches = if !recursive && is_glob(remote_source) {
+        expand_remote_glob(s3, remote_cwd, remote_source).await?
+    } else {
+        Vec::new()
+    };
+    let copies: Vec<(String, String)> = if recursive {
+        let source_prefix = source_key.map(|key| key + "/").unwrap_or_default();
+        let dest_prefix = dest_key.map(|key| key + "/").unwrap_or_default();
+        s3.list_keys(source_bucket.clone(), source_prefix.clone())
+            .await?
+            .into_iter()
+            .map(|key| {
+                let dest_key = format!("{}{}", dest_prefix, &key[source_prefix.len()..]);
+                (key, dest_key)
+            })
+            .collect()
+    } else if !glob_matches.is_empty() {
+        // Every object that the pattern matches is copied into the destination "directory", keeping its name
+        let dest_dir = dest_key.map(|dir| dir + "/").unwrap_or_default();
+        glob_matches
+            .iter()
+            .filter_map(|path| S3Path::try_from_path(path).ok()?.key)
+            .map(|key| {
+                let dest_key =
+                    format!("{}{}", dest_dir, key.rsplit('/').next().unwrap_or_default());
+                (key, dest_key)
+            })
+            .collect()
     } else {
-        let buckets = s3.list_buckets().await?;
-        Ok(buckets.join("\n"))
+        let source_key = source_key.ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?;
+        let dest_key = match dest_key {
+            Some(dest_key) if !remote_destination.ends_with('/') => dest_key,
+            // Copying into a "directory" keeps the object's name
+            dest_dir => format!(
+                "{}{}",
+                dest_dir.map(|dir| dir + "/").unwrap_or_default(),
+                source_key.rsplit('/').next().unwrap_or_default()
+            ),
+        };
+        vec![(source_key, dest_key)]
+    };
+
+    let source_bucket = &source_bucket;
+    let dest_bucket = &dest_bucket;
+    let mut reports: Vec<TransferReport> = stream::iter(copies)
+        .map(|(source_key, dest_key)| async move {
+            let bytes = s3
+                .copy_object(
+                    source_bucket.clone(),
+                    source_key.clone(),
+                    dest_bucket.clone(),
+                    dest_key.clone(),
+                )
+                .await
+                .map_err(|e| {
+                    e.with_context(ErrorContext {
+                        operation: "copying",
+                        bucket: Some(source_bucket.clone()),
+                        key: Some(source_key.clone()),
+                        local_path: None,
+                    })
+                })?;
+            Ok::<TransferReport, RBError>(TransferReport {
+                outcome: TransferOutcome::Copied,
+                source: format!("/{}/{}", source_bucket, source_key),
+                destination: format!("/{}/{}", dest_bucket, dest_key),
+                bytes,
+            })
+        })
+        .buffer_unordered(parallel.max(1))
+        .try_collect()
+        .await?;
+    reports.sort_by(|a, b| a.source.cmp(&b.source));
+    Ok(reports)
+}
+
+// Adding up a bucket means listing every object in it, a thousand per request, so past this many objects `df` asks first
+const DISK_USAGE_CONFIRMATION_THRESHOLD: u64 = 100_000;
+
+/// Counts the objects in the current bucket, or in every bucket when at the root, and adds up their sizes. Unless
+/// `confirmed`, this stops with an Unconfirmed error as soon as a bucket turns out to be big enough to take a while.
+pub async fn disk_usage(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    confirmed: bool,
+) -> Result<Vec<(String, Usage)>, RBError> {
+    let buckets = match S3Path::try_from_path(remote_cwd)?.bucket {
+        Some(bucket) => vec![bucket],
+        None => s3.list_buckets().await?,
+    };
+    let max_objects = if confirmed {
+        None
+    } else {
+        Some(DISK_USAGE_CONFIRMATION_THRESHOLD)
+    };
+
+    let mut usages = Vec::new();
+    for bucket in buckets {
+        match s3.usage(bucket.clone(), String::new(), max_objects).await? {
+            Some(usage) => usages.push((bucket, usage)),
+            None => {
+                let change = format!(
+                    "Bucket {} has more than {} objects, and listing all of them could take a while.",
+                    bucket, DISK_USAGE_CONFIRMATION_THRESHOLD
+                );
+                return Err(RBError::wrap(ErrorKind::Unconfirmed, change));
+            }
+        }
     }
+    Ok(usages)
 }
 
-pub fn list_local_path(local_path: &Path) -> Result<String, RBError> {
+/// Lists the buckets as entries, so that they can be shown like the "directories" inside them
+pub async fn list_buckets(s3: &dyn StorageBackend) -> Result<Vec<ObjectEntry>, RBError> {
+    let buckets = s3.list_buckets().await?;
+    Ok(buckets
+        .into_iter()
+        .map(|name| ObjectEntry {
+            name,
+            is_prefix: true,
+            size: None,
+            last_modified: None,
+            etag: None,
+            storage_class: None,
+        })
+        .collect())
+}
+
+pub fn list_local_path(local_path: &Path) -> Result<Vec<String>, RBError> {
     read_dir(local_path)
         .and_then(|mut entries| {
             let mut dirs: Vec<String> = Vec::new();
@@ -36,31 +819,140 @@ pub fn list_local_path(local_path: &Path) -> Result<String, RBError> {
                 Ok(())
             })?;
             dirs.sort_unstable();
-            Ok(dirs.join("\n"))
+            Ok(dirs)
         })
         .map_err(RBError::wrap_io)
 }
 
+/// Writes a command's output to a local file, replacing what was there unless `append` is set. Like a shell redirect,
+/// the file is created (or emptied) even if there's no output.
+pub fn write_local_file(path: &Path, output: &str, append: bool) -> Result<(), RBError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(RBError::wrap_io)?;
+    file.write_all(output.as_bytes()).map_err(RBError::wrap_io)
+}
+
+/// Runs a command line with the local shell (`sh`, or `cmd` on Windows) and feeds `output` to its standard input. What
+/// the pipeline itself prints goes straight to our stdout and stderr. Like in a shell, its exit status is ignored, since
+/// e.g. `grep` exits unsuccessfully whenever nothing matches.
+pub async fn pipe_to_shell(local_cwd: &Path, pipeline: &str, output: &str) -> Result<(), RBError> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(pipeline)
+        .current_dir(local_cwd)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(RBError::wrap_io)?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pipeline is allowed to stop reading early, e.g. `| head`
+        if let Err(e) = stdin.write_all(output.as_bytes()).await {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                return Err(RBError::wrap_io(e));
+            }
+        }
+        // Dropping stdin closes it, so that the pipeline sees the end of its input
+    }
+    child.wait().await.map_err(RBError::wrap_io)?;
+    Ok(())
+}
+
+// The context for an error during a get or put: the remote path as far as it could be made sense of, and the local one
+fn transfer_context(
+    operation: &'static str,
+    remote_path: &Path,
+    local_path: PathBuf,
+) -> ErrorContext {
+    let s3_path = S3Path::try_from_path(remote_path).ok();
+    ErrorContext {
+        operation,
+        bucket: s3_path.as_ref().and_then(|s3_path| s3_path.bucket.clone()),
+        key: s3_path.and_then(|s3_path| s3_path.key),
+        local_path: Some(local_path),
+    }
+}
+
 pub async fn get_file(
-    s3: &RBS3,
+    s3: &dyn StorageBackend,
     remote_cwd: &Path,
     local_cwd: &Path,
-    remote_source: &String,
+    remote_source: &str,
     local_destination: &Option<String>,
-) -> Result<String, RBError> {
-    let source_path = remote_cwd.join(remote_source).clean();
+    if_exists: ExistingTargetPolicy,
+    get_options: &GetOptions,
+) -> Result<TransferReport, RBError> {
+    download_file(
+        s3,
+        remote_cwd,
+        local_cwd,
+        remote_source,
+        local_destination,
+        if_exists,
+        get_options,
+    )
+    .await
+    .map_err(|e| {
+        let local_path = match local_destination {
+            Some(local_dest) => resolve_local_path(local_cwd, local_dest),
+            None => local_cwd.to_owned(),
+        };
+        e.with_context(transfer_context(
+            "downloading",
+            &resolve_remote_path(remote_cwd, remote_source),
+            local_path,
+        ))
+    })
+}
+
+async fn download_file(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    local_cwd: &Path,
+    remote_source: &str,
+    local_destination: &Option<String>,
+    if_exists: ExistingTargetPolicy,
+    get_options: &GetOptions,
+) -> Result<TransferReport, RBError> {
+    let source_path = resolve_remote_path(remote_cwd, remote_source);
     let s3_path = S3Path::try_from_path(&source_path)?;
     if !s3_path.has_key_and_bucket() {
         return Err(RBError::new(ErrorKind::InvalidTarget));
     }
     let bucket = s3_path.bucket.unwrap();
     let key = s3_path.key.unwrap();
+    let source = source_path.display().to_string();
+
+    if local_destination.as_deref() == Some("-") {
+        // Stdout belongs to the object body here, so all of our own chatter has to go to stderr
+        status!("Streaming file '{}' to stdout...", key);
+        s3.download_object_to_stdout(bucket, key, get_options)
+            .await?;
+        return Ok(TransferReport {
+            outcome: TransferOutcome::Streamed,
+            source,
+            destination: String::from("-"),
+            bytes: 0,
+        });
+    }
 
     let dest_path = if let Some(local_dest) = local_destination {
         // We want to canonicalize this path so that we ensure that whatever directory local_destination
         // puts us in actually exists. It's valid for local_destination to either include or omit a
         // terminating filename, so we have to deal with that too.
-        let non_canonical_path = local_cwd.join(local_dest);
+        let non_canonical_path = resolve_local_path(local_cwd, local_dest);
         if non_canonical_path.is_dir() {
             // Awesome, this is the happy path!
             let dest_dir = non_canonical_path
@@ -73,10 +965,12 @@ pub async fn get_file(
                     .unwrap_or(OsStr::new("unknown_s3_file")),
             ))
         } else if non_canonical_path.is_file() {
-            return Err(RBError::new(ErrorKind::TargetAlreadyExists));
+            non_canonical_path
+                .canonicalize()
+                .map_err(RBError::wrap_io)?
         } else if non_canonical_path
             .to_str()
-            .map_or(false, |s| s.ends_with('/') || s.ends_with('\\'))
+            .is_some_and(|s| s.ends_with('/') || s.ends_with('\\'))
         {
             // This means the path does not exist, but it ends in a slash, which means that the user
             // expected it to be a directory
@@ -111,37 +1005,136 @@ pub async fn get_file(
             .file_name()
             .ok_or(RBError::new(ErrorKind::Other))?; // This should never happen thanks to set_current_dir() earlier
 
-        let dest_filepath = local_cwd.join(dest_filename);
-        if dest_filepath.is_file() {
-            return Err(RBError::new(ErrorKind::TargetAlreadyExists));
-        }
-        dest_filepath
+        local_cwd.join(dest_filename)
     };
 
+    if dest_path.is_file() {
+        match if_exists {
+            ExistingTargetPolicy::Fail => return Err(RBError::new(ErrorKind::TargetAlreadyExists)),
+            ExistingTargetPolicy::Overwrite => {}
+            ExistingTargetPolicy::SkipExisting | ExistingTargetPolicy::NewerOnly => {
+                let remote_head = s3
+                    .head_object(bucket.clone(), key.clone(), get_options)
+                    .await?
+                    .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                let local_head = local_file_head(&dest_path)?;
+                if should_skip_transfer(if_exists, &remote_head, &local_head) {
+                    return Ok(TransferReport {
+                        outcome: TransferOutcome::Skipped,
+                        source,
+                        destination: dest_path.display().to_string(),
+                        bytes: 0,
+                    });
+                }
+            }
+        }
+    }
+
     // Okay, after all that, now we have finalized bucket, key, dest_path. Time to download!
-    println!(
+    status!(
         "Downloading file '{}'...",
         dest_path
             .file_name()
             .unwrap_or(OsStr::new("unknown"))
             .to_string_lossy()
     );
-    s3.download_object(bucket, key, &dest_path).await?;
-    Ok(format!(
-        "File downloaded successfully: {}",
-        dest_path.display()
-    ))
+    let partial_download = PartialDownload::new(&dest_path);
+    s3.download_object(bucket, key, get_options, partial_download.path())
+        .await?;
+    partial_download.finish(&dest_path)?;
+    Ok(TransferReport {
+        outcome: TransferOutcome::Downloaded,
+        source,
+        destination: dest_path.display().to_string(),
+        bytes: local_file_head(&dest_path)?.size,
+    })
+}
+
+/// Downloads every object matching a remote glob pattern into the `local_destination` directory (or the local cwd)
+pub async fn get_matching_files(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    local_cwd: &Path,
+    matches: Vec<PathBuf>,
+    local_destination: &Option<String>,
+    if_exists: ExistingTargetPolicy,
+    get_options: &GetOptions,
+) -> Result<Vec<TransferReport>, RBError> {
+    // A single version ID or byte range can't sensibly apply to many objects, and neither can a single output stream
+    if get_options.version_id.is_some()
+        || get_options.range.is_some()
+        || local_destination.as_deref() == Some("-")
+    {
+        return Err(RBError::new(ErrorKind::InvalidTarget));
+    }
+    let dest_dir = match local_destination {
+        Some(local_dest) => resolve_local_path(local_cwd, local_dest),
+        None => local_cwd.to_owned(),
+    };
+    if !dest_dir.is_dir() {
+        return Err(RBError::new(ErrorKind::InvalidTarget));
+    }
+
+    let mut reports = Vec::with_capacity(matches.len());
+    for remote_source in matches {
+        // Each match is already an absolute path, and with no explicit destination get_file puts it in dest_dir
+        let report = get_file(
+            s3,
+            remote_cwd,
+            &dest_dir,
+            &remote_source.to_string_lossy(),
+            &None,
+            if_exists,
+            get_options,
+        )
+        .await?;
+        reports.push(report);
+    }
+    Ok(reports)
 }
 
 pub async fn put_file(
-    s3: &RBS3,
+    s3: &dyn StorageBackend,
     remote_cwd: &Path,
     local_cwd: &Path,
-    local_source: &String,
+    local_source: &str,
     remote_destination: &Option<String>,
-) -> Result<String, RBError> {
-    let src_path = local_cwd
-        .join(local_source)
+    if_exists: ExistingTargetPolicy,
+    put_options: &PutOptions,
+) -> Result<TransferReport, RBError> {
+    upload_file(
+        s3,
+        remote_cwd,
+        local_cwd,
+        local_source,
+        remote_destination,
+        if_exists,
+        put_options,
+    )
+    .await
+    .map_err(|e| {
+        let remote_path = match remote_destination {
+            Some(remote_dest) => resolve_remote_path(remote_cwd, remote_dest),
+            None => remote_cwd.to_owned(),
+        };
+        e.with_context(transfer_context(
+            "uploading",
+            &remote_path,
+            resolve_local_path(local_cwd, local_source),
+        ))
+    })
+}
+
+async fn upload_file(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    local_cwd: &Path,
+    local_source: &str,
+    remote_destination: &Option<String>,
+    if_exists: ExistingTargetPolicy,
+    put_options: &PutOptions,
+) -> Result<TransferReport, RBError> {
+    let src_path = resolve_local_path(local_cwd, local_source)
         .canonicalize()
         .map_err(RBError::wrap_io)?;
 
@@ -150,7 +1143,7 @@ pub async fn put_file(
     }
 
     let dest_path = if let Some(remote_dir) = remote_destination {
-        remote_cwd.join(remote_dir).clean()
+        resolve_remote_path(remote_cwd, remote_dir)
     } else {
         remote_cwd
             .join(Path::new(
@@ -168,18 +1161,134 @@ pub async fn put_file(
     let bucket = s3_path.bucket.unwrap();
     let key = s3_path.key.unwrap();
 
-    if s3.object_exists(bucket.clone(), key.clone()).await? {
-        return Err(RBError::new(ErrorKind::TargetAlreadyExists));
+    match if_exists {
+        ExistingTargetPolicy::Fail => {
+            if s3.object_exists(bucket.clone(), key.clone()).await? {
+                return Err(RBError::new(ErrorKind::TargetAlreadyExists));
+            }
+        }
+        ExistingTargetPolicy::Overwrite => {}
+        ExistingTargetPolicy::SkipExisting | ExistingTargetPolicy::NewerOnly => {
+            if let Some(remote_head) = s3
+                .head_object(bucket.clone(), key.clone(), &GetOptions::default())
+                .await?
+            {
+                let local_head = local_file_head(&src_path)?;
+                if should_skip_transfer(if_exists, &local_head, &remote_head) {
+                    return Ok(TransferReport {
+                        outcome: TransferOutcome::Skipped,
+                        source: src_path.display().to_string(),
+                        destination: dest_path.display().to_string(),
+                        bytes: 0,
+                    });
+                }
+            }
+        }
     }
 
     // Okay, after all that, now we have finalized bucket, key, src_path. Time to upload!
-    println!(
+    status!(
         "Uploading file '{}'...",
         src_path.file_name().unwrap().to_string_lossy()
     );
-    s3.put_object(bucket, key, &src_path).await?;
-    Ok(format!(
-        "File uploaded successfully: {}",
-        dest_path.display()
-    ))
+    s3.put_object(
+        bucket,
+        key,
+        &src_path,
+        put_options.metadata.clone(),
+        put_options.checksum,
+    )
+    .await?;
+    Ok(TransferReport {
+        outcome: TransferOutcome::Uploaded,
+        source: src_path.display().to_string(),
+        destination: dest_path.display().to_string(),
+        bytes: local_file_head(&src_path)?.size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_backend::tests::temp_root;
+    use crate::local_backend::LocalBackend;
+
+    const FILES: [&str; 4] = [
+        "logs/2024/jan.log",
+        "logs/2024/feb.log",
+        "logs/2024/notes.txt",
+        "logs/readme.txt",
+    ];
+
+    #[test]
+    fn expand_local_path_only_expands_a_leading_tilde() {
+        let home = dirs_next::home_dir().unwrap();
+        assert_eq!(expand_local_path("~"), home);
+        assert_eq!(expand_local_path("~/reports"), home.join("reports"));
+        assert_eq!(
+            expand_local_path("$HOME/reports"),
+            PathBuf::from("$HOME/reports")
+        );
+        assert_eq!(expand_local_path("a/~b"), PathBuf::from("a/~b"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pipe_to_shell_feeds_the_output_to_the_pipeline() {
+        let dir = temp_root("pipe", &[]);
+        pipe_to_shell(&dir, "tr a-z A-Z > piped.txt", "hello\n")
+            .await
+            .unwrap();
+        let piped = std::fs::read_to_string(dir.join("piped.txt")).unwrap();
+        assert_eq!(piped, "HELLO\n");
+
+        // A pipeline that stops reading early isn't an error
+        pipe_to_shell(&dir, "true", &"x".repeat(1 << 20))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn stat_and_du_accept_globs() {
+        let backend = LocalBackend::new(temp_root("stat-du", &FILES)).unwrap();
+        let cwd = Path::new("/logs");
+
+        let stats = stat_objects(&backend, cwd, "2024/*.log").await.unwrap();
+        let paths: Vec<&str> = stats.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["/logs/2024/feb.log", "/logs/2024/jan.log"]);
+        assert_eq!(stats[0].1.size, "logs/2024/feb.log".len() as u64);
+
+        let glob_usage = object_usage(&backend, cwd, "2024/*.log").await.unwrap();
+        assert_eq!(glob_usage.objects, 2);
+        assert_eq!(
+            object_usage(&backend, cwd, "2024").await.unwrap().objects,
+            3
+        );
+        assert_eq!(
+            object_usage(&backend, cwd, "readme.txt")
+                .await
+                .unwrap()
+                .objects,
+            1
+        );
+        assert_eq!(
+            object_usage(&backend, cwd, "/logs").await.unwrap().objects,
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn cp_copies_each_glob_match_into_the_destination() {
+        let backend = LocalBackend::new(temp_root("cp-glob", &FILES)).unwrap();
+        let cwd = Path::new("/logs");
+
+        let reports = copy_remote(&backend, cwd, "2024/*.log", "archive", false, 2)
+            .await
+            .unwrap();
+        let destinations: Vec<&str> = reports.iter().map(|r| r.destination.as_str()).collect();
+        assert_eq!(
+            destinations,
+            vec!["/logs/archive/feb.log", "/logs/archive/jan.log"]
+        );
+    }
 }
@@ -0,0 +1,281 @@
+use crate::backend::StorageBackend;
+use crate::commands::resolve_remote_path;
+use crate::s3::S3Path;
+use crate::tokenize;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task::block_in_place;
+use tokio::time::timeout;
+
+// Give up on remote completions after this long, so that a slow or unreachable S3 doesn't freeze the prompt
+const REMOTE_COMPLETION_TIMEOUT: Duration = Duration::from_millis(1500);
+// People tend to mash tab a few times in a row, so a listing is reused for a little while rather than fetched again
+const REMOTE_COMPLETION_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// Keyed by bucket (empty for the bucket list itself) and key prefix
+type RemoteNameCache = HashMap<(String, String), (Instant, Vec<String>)>;
+
+// Flags whose value is the next word, which therefore isn't one of the command's positional arguments
+const FLAGS_WITH_VALUES: [&str; 10] = [
+    "--version-id",
+    "--range",
+    "--checksum",
+    "--meta",
+    "-p",
+    "--parallel",
+    "--days",
+    "--tier",
+    "--input",
+    "--output",
+];
+
+/// Tab completion and multi-line input for the interactive prompt
+pub struct RBHelper {
+    backend: Arc<dyn StorageBackend>,
+    remote_cwd: PathBuf,
+    local_cwd: PathBuf,
+    // A RefCell because rustyline only gives completers a shared reference
+    remote_cache: RefCell<RemoteNameCache>,
+    // The session's listing cache generation that `remote_cache` goes with
+    listing_generation: u64,
+}
+
+impl RBHelper {
+    pub fn new(backend: Arc<dyn StorageBackend>, remote_cwd: PathBuf, local_cwd: PathBuf) -> Self {
+        RBHelper {
+            backend,
+            remote_cwd,
+            local_cwd,
+            remote_cache: RefCell::new(HashMap::new()),
+            listing_generation: 0,
+        }
+    }
+
+    /// Brings completions up to date with the session after a command has run, since it may have changed the
+    /// directory or region. Cached names are kept unless the session's listings have been invalidated since (because
+    /// objects were created or removed, or by `refresh`), as shown by a new `listing_generation`.
+    pub fn update(
+        &mut self,
+        backend: Arc<dyn StorageBackend>,
+        remote_cwd: &Path,
+        local_cwd: &Path,
+        listing_generation: u64,
+    ) {
+        self.backend = backend;
+        self.remote_cwd = remote_cwd.to_owned();
+        self.local_cwd = local_cwd.to_owned();
+        if listing_generation != self.listing_generation {
+            self.listing_generation = listing_generation;
+            self.remote_cache.borrow_mut().clear();
+        }
+    }
+
+    fn remote_candidates(&self, word: &str) -> Vec<Pair> {
+        let (dir_part, partial) = match word.rfind('/') {
+            Some(slash_index) => word.split_at(slash_index + 1),
+            None => ("", word),
+        };
+        let names = match S3Path::try_from_path(&resolve_remote_path(&self.remote_cwd, dir_part)) {
+            Ok(S3Path {
+                bucket: Some(bucket),
+                key,
+            }) => self.list_remote(
+                Some(bucket),
+                key.map_or(String::new(), |k| k + "/"),
+                partial,
+            ),
+            Ok(_) => self.list_remote(None, String::new(), partial),
+            Err(_) => Vec::new(),
+        };
+
+        names
+            .into_iter()
+            .filter(|name| name.starts_with(partial))
+            .map(|name| Pair {
+                replacement: format!("{}{}", dir_part, name),
+                display: name,
+            })
+            .collect()
+    }
+
+    // Relative paths are completed against our own local cwd, so unlike rustyline's FilenameCompleter this doesn't
+    // depend on the process's working directory
+    fn local_candidates(&self, word: &str) -> Vec<Pair> {
+        let (dir_part, partial) = match word.rfind('/') {
+            Some(slash_index) => word.split_at(slash_index + 1),
+            None => ("", word),
+        };
+        let entries = match read_dir(self.local_cwd.join(dir_part)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut candidates: Vec<Pair> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let mut name = entry.file_name().to_string_lossy().into_owned();
+                if !name.starts_with(partial) {
+                    return None;
+                }
+                if entry.path().is_dir() {
+                    name.push('/');
+                }
+                Some(Pair {
+                    replacement: format!("{}{}", dir_part, name),
+                    display: name,
+                })
+            })
+            .collect();
+        candidates.sort_unstable_by(|a, b| a.display.cmp(&b.display));
+        candidates
+    }
+
+    // Lists the names under a bucket and prefix, or the buckets themselves if there's no bucket. Failures and timeouts
+    // just mean there's nothing to suggest.
+    fn list_remote(
+        &self,
+        bucket: Option<String>,
+        dir_prefix: String,
+        partial: &str,
+    ) -> Vec<String> {
+        let cache_key = match &bucket {
+            Some(bucket) => (bucket.clone(), format!("{}{}", dir_prefix, partial)),
+            None => (String::new(), String::new()),
+        };
+        if let Some((fetched_at, names)) = self.remote_cache.borrow().get(&cache_key) {
+            if fetched_at.elapsed() < REMOTE_COMPLETION_CACHE_TTL {
+                return names.clone();
+            }
+        }
+
+        let listing = async {
+            match bucket {
+                Some(bucket) => {
+                    self.backend
+                        .list_completions(bucket, dir_prefix, partial)
+                        .await
+                }
+                None => self
+                    .backend
+                    .list_buckets()
+                    .await
+                    .map(|buckets| buckets.into_iter().map(|name| name + "/").collect()),
+            }
+        };
+        // Completion happens synchronously inside rustyline, so this is the bridge back into async land. block_in_place
+        // panics on a current-thread runtime (which a library caller might be using), so there's no remote completion
+        // there.
+        let handle = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => handle,
+            _ => return Vec::new(),
+        };
+        let result =
+            block_in_place(|| handle.block_on(timeout(REMOTE_COMPLETION_TIMEOUT, listing)));
+        match result {
+            Ok(Ok(names)) => {
+                self.remote_cache
+                    .borrow_mut()
+                    .insert(cache_key, (Instant::now(), names.clone()));
+                names
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Completer for RBHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before_cursor = &line[..pos];
+        let word_start = before_cursor
+            .rfind(char::is_whitespace)
+            .map_or(0, |space_index| space_index + 1);
+        let word = &before_cursor[word_start..];
+
+        let mut earlier_words = before_cursor[..word_start].split_whitespace();
+        let command = match earlier_words.next() {
+            Some(command) => command.to_lowercase(),
+            None => return Ok((word_start, Vec::new())),
+        };
+
+        // Work out which positional argument the cursor is in by skipping over flags and their values. A lone "-" is
+        // not a flag, it's a destination meaning stdout.
+        let mut positional_index = 0;
+        let mut expecting_flag_value = false;
+        for earlier_word in earlier_words {
+            if expecting_flag_value {
+                expecting_flag_value = false;
+            } else if earlier_word.starts_with('-') && earlier_word != "-" {
+                expecting_flag_value = FLAGS_WITH_VALUES.contains(&earlier_word);
+            } else {
+                positional_index += 1;
+            }
+        }
+        if expecting_flag_value || (word.starts_with('-') && word != "-") {
+            return Ok((word_start, Vec::new()));
+        }
+
+        let candidates = match (command.as_str(), positional_index) {
+            ("cd", 0)
+            | ("pushd", 0)
+            | ("get", 0)
+            | ("put", 1)
+            | ("rm", _)
+            | ("cp", _)
+            | ("url", 0)
+            | ("restore", 0)
+            | ("restore-status", 0)
+            | ("tags", 1)
+            | ("meta", 0)
+            | ("meta", 1)
+            | ("acl", 0)
+            | ("acl", 1)
+            | ("select", 0)
+            | ("stat", 0)
+            | ("du", 0)
+            | ("chtype", 0)
+            | ("chclass", 0)
+            | ("chclass", 1) => self.remote_candidates(word),
+            ("lcd", 0) | ("get", 1) | ("put", 0) => self.local_candidates(word),
+            _ => Vec::new(),
+        };
+        Ok((word_start, candidates))
+    }
+}
+
+impl Hinter for RBHelper {
+    type Hint = String;
+}
+
+impl Highlighter for RBHelper {}
+
+// Keeps reading lines while a command ends in a `\` continuation or has an unclosed quote, rather than running it
+impl Validator for RBHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if tokenize::is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for RBHelper {}
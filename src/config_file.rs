@@ -0,0 +1,56 @@
+use crate::error::RBError;
+use crate::output::TimeStyle;
+
+use std::fs::read_to_string;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Settings from the config file. Anything left out falls back to the built-in default, and command-line flags win over
+/// anything set here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Template for the interactive prompt; see `render_prompt` for the placeholders it can use
+    pub prompt: Option<String>,
+    pub history_size: Option<usize>,
+    pub edit_mode: Option<EditModeSetting>,
+    /// Always start where the last interactive session left off, as if `--resume` were given
+    pub resume: Option<bool>,
+    /// Ring the terminal bell when a command takes at least this many seconds, like `--notify-after`
+    pub notify_after: Option<f64>,
+    /// How long listings show modification times: "relative" (the default) or "full"
+    pub time_style: Option<TimeStyle>,
+}
+
+/// Which set of keybindings the interactive prompt uses
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditModeSetting {
+    Emacs,
+    Vi,
+}
+
+/// The config file's default location, e.g. ~/.config/rustbucket/config.toml on Linux
+pub fn default_path() -> Option<PathBuf> {
+    dirs_next::config_dir().map(|config_dir| config_dir.join("rustbucket").join("config.toml"))
+}
+
+/// Reads the config file at `path`, or at the default location if there isn't one. It's fine for the default file not
+/// to exist, but one that was asked for by name has to.
+pub fn load(path: Option<&Path>) -> Result<FileConfig, RBError> {
+    let (path, required) = match path {
+        Some(path) => (path.to_owned(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(FileConfig::default()),
+        },
+    };
+
+    match read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).map_err(RBError::wrap_config),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(FileConfig::default()),
+        Err(e) => Err(RBError::wrap_io(e)),
+    }
+}
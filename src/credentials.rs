@@ -0,0 +1,210 @@
+use crate::error::{ErrorKind, RBError};
+use crate::sso::{SsoProvider, SsoSettings};
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::read_to_string;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
+    ProfileProvider, ProvideAwsCredentials,
+};
+use rusoto_core::{HttpClient, Region};
+use rusoto_sts::{
+    StsAssumeRoleSessionCredentialsProvider, StsClient, StsSessionCredentialsProvider,
+};
+
+// Used as the RoleSessionName when we assume a role, so it shows up recognizably in CloudTrail
+const ROLE_SESSION_NAME: &str = "rustbucket";
+
+/// A cheaply-cloneable credentials provider that's shared by every client we create during a session, so that
+/// anything expensive or interactive (like asking for an MFA code) only happens once
+#[derive(Clone)]
+pub struct SessionCredentials {
+    provider: Arc<dyn ProvideAwsCredentials + Send + Sync>,
+}
+
+impl SessionCredentials {
+    /// Wraps any rusoto credentials provider, e.g. a `StaticProvider` for credentials that come from somewhere else
+    pub fn new<P>(provider: P) -> Self
+    where
+        P: ProvideAwsCredentials + Send + Sync + 'static,
+    {
+        SessionCredentials {
+            provider: Arc::new(provider),
+        }
+    }
+}
+
+impl fmt::Debug for SessionCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionCredentials")
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for SessionCredentials {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.provider.credentials().await
+    }
+}
+
+pub fn profile_name() -> String {
+    env::var("AWS_PROFILE")
+        .or_else(|_| env::var("AWS_DEFAULT_PROFILE"))
+        .unwrap_or_else(|_| String::from("default"))
+}
+
+fn aws_config_path() -> Option<PathBuf> {
+    env::var_os("AWS_CONFIG_FILE")
+        .map(PathBuf::from)
+        .or_else(|| dirs_next::home_dir().map(|home| home.join(".aws").join("config")))
+}
+
+// In the AWS config file, the default profile's section is `[default]`, but every other profile's section is
+// `[profile name]`
+fn load_profile_settings(profile: &str) -> Result<HashMap<String, String>, RBError> {
+    if profile == "default" {
+        load_config_section("default")
+    } else {
+        load_config_section(&format!("profile {}", profile))
+    }
+}
+
+// Reads the settings in one `[section]` of the AWS config file
+fn load_config_section(wanted_section: &str) -> Result<HashMap<String, String>, RBError> {
+    let contents = match aws_config_path().map(read_to_string) {
+        Some(Ok(contents)) => contents,
+        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(RBError::wrap_io(e)),
+        _ => return Ok(HashMap::new()),
+    };
+
+    let mut settings = HashMap::new();
+    let mut in_wanted_section = false;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_wanted_section = section.trim() == wanted_section;
+        } else if in_wanted_section {
+            if let Some((setting, value)) = line.split_once('=') {
+                settings.insert(setting.trim().to_owned(), value.trim().to_owned());
+            }
+        }
+    }
+
+    Ok(settings)
+}
+
+// Looks up one of the AWS SDKs' standard settings, which come from an environment variable or else the current profile
+fn standard_setting(env_var: &str, profile_setting: &str) -> Result<Option<String>, RBError> {
+    match env::var(env_var) {
+        Ok(value) => Ok(Some(value.trim().to_owned())),
+        Err(_) => Ok(load_profile_settings(&profile_name())?.remove(profile_setting)),
+    }
+}
+
+/// The AWS SDKs' standard `max_attempts` setting, from AWS_MAX_ATTEMPTS or else the current profile
+pub fn configured_max_attempts() -> Result<Option<u32>, RBError> {
+    match standard_setting("AWS_MAX_ATTEMPTS", "max_attempts")? {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| RBError::new(ErrorKind::InvalidCommand)),
+        None => Ok(None),
+    }
+}
+
+/// The AWS SDKs' standard `use_fips_endpoint` setting, from AWS_USE_FIPS_ENDPOINT or else the current profile
+pub fn configured_use_fips_endpoint() -> Result<bool, RBError> {
+    Ok(
+        standard_setting("AWS_USE_FIPS_ENDPOINT", "use_fips_endpoint")?
+            .is_some_and(|value| value.eq_ignore_ascii_case("true")),
+    )
+}
+
+fn prompt_for_mfa_code(mfa_serial: &str) -> Result<String, RBError> {
+    let code = rpassword::read_password_from_tty(Some(&format!("MFA code for {}: ", mfa_serial)))
+        .map_err(RBError::wrap_io)?;
+    Ok(code.trim().to_owned())
+}
+
+fn profile_provider(profile: &str) -> Result<ProfileProvider, RBError> {
+    let mut provider = ProfileProvider::new().map_err(RBError::wrap_credentials)?;
+    provider.set_profile(profile);
+    Ok(provider)
+}
+
+/// Sets up credentials for profiles that rusoto's default credential chain can't handle on its own: AWS SSO profiles
+/// (`sso_start_url` or `sso_session`), ones that assume a role (`role_arn`), and ones protected by MFA
+/// (`mfa_serial`). When MFA is required, this prompts for a token code on the terminal. Returns None when the profile
+/// needs none of that, in which case the default chain is fine.
+pub fn session_credentials(region: &Region) -> Result<Option<SessionCredentials>, RBError> {
+    let profile = profile_name();
+    let settings = load_profile_settings(&profile)?;
+    let mfa_serial = settings.get("mfa_serial").cloned();
+    let http_client = || HttpClient::new().map_err(RBError::wrap_io);
+
+    let sso_session = match settings.get("sso_session") {
+        Some(session_name) => Some(load_config_section(&format!(
+            "sso-session {}",
+            session_name
+        ))?),
+        None => None,
+    };
+    if let Some(sso_settings) = SsoSettings::from_profile(&settings, sso_session.as_ref()) {
+        // The SSO token is checked (and the browser sign-in started, if need be) the first time we need credentials
+        let provider = AutoRefreshingProvider::new(SsoProvider::new(sso_settings)?)
+            .map_err(RBError::wrap_credentials)?;
+        Ok(Some(SessionCredentials::new(provider)))
+    } else if let Some(role_arn) = settings.get("role_arn") {
+        let sts_client = match settings.get("source_profile") {
+            Some(source_profile) => StsClient::new_with(
+                http_client()?,
+                profile_provider(source_profile)?,
+                region.clone(),
+            ),
+            None => StsClient::new_with(
+                http_client()?,
+                DefaultCredentialsProvider::new().map_err(RBError::wrap_credentials)?,
+                region.clone(),
+            ),
+        };
+
+        let mut provider = StsAssumeRoleSessionCredentialsProvider::new(
+            sts_client,
+            role_arn.clone(),
+            String::from(ROLE_SESSION_NAME),
+            None,
+            None,
+            None,
+            mfa_serial.clone(),
+        );
+        if let Some(serial) = mfa_serial {
+            provider.set_mfa_code(prompt_for_mfa_code(&serial)?);
+        }
+
+        let cached_provider =
+            AutoRefreshingProvider::new(provider).map_err(RBError::wrap_credentials)?;
+        Ok(Some(SessionCredentials::new(cached_provider)))
+    } else if let Some(serial) = mfa_serial {
+        // The profile's own long-term keys need an MFA code, so trade them for MFA-authenticated session credentials
+        let sts_client =
+            StsClient::new_with(http_client()?, profile_provider(&profile)?, region.clone());
+        let mut provider =
+            StsSessionCredentialsProvider::new(sts_client, None, Some(serial.clone()));
+        provider.set_mfa_code(prompt_for_mfa_code(&serial)?);
+
+        let cached_provider =
+            AutoRefreshingProvider::new(provider).map_err(RBError::wrap_credentials)?;
+        Ok(Some(SessionCredentials::new(cached_provider)))
+    } else {
+        Ok(None)
+    }
+}
@@ -1,22 +1,94 @@
+use crate::s3::S3ServiceError;
+
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::path::PathBuf;
 
+/// What sort of thing went wrong
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum ErrorKind {
+    /// S3 refused the request (AccessDenied, a bad access key or signature...), or a local file couldn't be accessed
+    AccessDenied,
+    Cancelled,
+    /// The config file couldn't be parsed or has invalid settings in it
+    Config,
+    /// The request clashed with something else going on, like a conflicting operation on the same bucket or a failed
+    /// precondition
+    Conflict,
+    Credentials,
+    ExpiredCredentials,
+    /// A local file operation failed in some way without a more specific kind
     IO,
     InvalidCommand,
     InvalidTarget,
+    /// S3 couldn't be reached, or the connection dropped or timed out
+    Network,
+    /// The bucket, object, version, or local file doesn't exist
+    NotFound,
     Other,
     Readline,
+    /// S3 rejected the request for a reason without a more specific kind
     S3,
     TargetAlreadyExists,
+    /// S3 asked us to slow down
+    Throttled,
+    /// S3 had an internal error, which might well not happen again if the request is retried
+    Transient,
+    /// The command would make a change that has to be confirmed first, and it wasn't. The error's source describes the
+    /// change.
+    Unconfirmed,
     UserExit,
 }
 
+/// Process exit codes, so that scripts can tell classes of failure apart
+pub mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    /// Anything that doesn't fit one of the more specific codes
+    pub const FAILURE: i32 = 1;
+    /// Unknown command or invalid arguments
+    pub const INVALID_USAGE: i32 = 2;
+    pub const NOT_FOUND: i32 = 3;
+    /// Missing, expired, or insufficient credentials
+    pub const ACCESS_DENIED: i32 = 4;
+    /// An S3 request or local file operation failed partway through
+    pub const TRANSFER_FAILED: i32 = 5;
+    pub const TARGET_EXISTS: i32 = 6;
+    /// The shell convention for "killed by SIGINT"
+    pub const CANCELLED: i32 = 130;
+}
+
+/// What was going on when an error happened, so that a failure partway through many transfers can say which one it was
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorContext {
+    /// What was being attempted, as an -ing verb like "downloading"
+    pub operation: &'static str,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub local_path: Option<PathBuf>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.operation)?;
+        match (&self.bucket, &self.key) {
+            (Some(bucket), Some(key)) => write!(f, " s3://{}/{}", bucket, key)?,
+            (Some(bucket), None) => write!(f, " s3://{}", bucket)?,
+            _ => {}
+        }
+        if let Some(local_path) = &self.local_path {
+            write!(f, " (local path {})", local_path.display())?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct RBError {
     kind: ErrorKind,
-    source_error: Option<Box<dyn Error + 'static>>,
+    source_error: Option<Box<dyn Error + Send + Sync + 'static>>,
+    context: Option<ErrorContext>,
 }
 
 impl RBError {
@@ -24,6 +96,7 @@ impl RBError {
         RBError {
             kind,
             source_error: None,
+            context: None,
         }
     }
 
@@ -31,37 +104,246 @@ impl RBError {
         self.kind
     }
 
+    /// Records what was going on when this error happened. The innermost context is the most specific, so if the
+    /// error already has one, that's kept.
+    pub fn with_context(mut self, context: ErrorContext) -> Self {
+        self.context.get_or_insert(context);
+        self
+    }
+
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.context.as_ref()
+    }
+
+    /// Whether trying the same thing again might well succeed: S3 was throttling, had an internal error, or couldn't
+    /// be reached. Expired credentials don't count, since they need refreshing before a retry can help.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            ErrorKind::Network | ErrorKind::Throttled | ErrorKind::Transient
+        )
+    }
+
+    /// Whether S3 asked us to slow down
+    pub fn is_throttle(&self) -> bool {
+        self.kind == ErrorKind::Throttled
+    }
+
+    /// Whether this is a problem with credentials or permissions, which retrying won't fix (except for expired
+    /// credentials, once they've been refreshed)
+    pub fn is_auth(&self) -> bool {
+        matches!(
+            self.kind,
+            ErrorKind::AccessDenied | ErrorKind::Credentials | ErrorKind::ExpiredCredentials
+        )
+    }
+
+    /// The process exit code that best describes this error
+    pub fn exit_code(&self) -> i32 {
+        match self.kind {
+            ErrorKind::UserExit => exit_code::SUCCESS,
+            ErrorKind::Cancelled => exit_code::CANCELLED,
+            ErrorKind::Credentials | ErrorKind::ExpiredCredentials | ErrorKind::AccessDenied => {
+                exit_code::ACCESS_DENIED
+            }
+            ErrorKind::NotFound => exit_code::NOT_FOUND,
+            ErrorKind::Config
+            | ErrorKind::InvalidCommand
+            | ErrorKind::InvalidTarget
+            | ErrorKind::Unconfirmed => exit_code::INVALID_USAGE,
+            ErrorKind::TargetAlreadyExists => exit_code::TARGET_EXISTS,
+            ErrorKind::IO => match self
+                .source_error
+                .as_ref()
+                .and_then(|e| e.downcast_ref::<io::Error>())
+                .map(|e| e.kind())
+            {
+                Some(io::ErrorKind::NotFound) => exit_code::NOT_FOUND,
+                Some(io::ErrorKind::PermissionDenied) => exit_code::ACCESS_DENIED,
+                _ => exit_code::TRANSFER_FAILED,
+            },
+            ErrorKind::S3
+            | ErrorKind::Conflict
+            | ErrorKind::Network
+            | ErrorKind::Throttled
+            | ErrorKind::Transient => exit_code::TRANSFER_FAILED,
+            ErrorKind::Other | ErrorKind::Readline => exit_code::FAILURE,
+        }
+    }
+
+    /// Wraps an underlying error as the given kind of RBError
+    pub fn wrap<E>(kind: ErrorKind, err: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        RBError {
+            kind,
+            source_error: Some(err.into()),
+            context: None,
+        }
+    }
+
     // These "wrap" functions reduce duplicate code in the common `.map_err(|err| please_turn_this_into_rb_error(err))`
     // type situations
     pub fn wrap_s3<E>(err: E) -> Self
     where
-        E: Into<Box<dyn Error + 'static>>,
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
     {
         RBError {
             kind: ErrorKind::S3,
             source_error: Some(err.into()),
+            context: None,
         }
     }
 
-    pub fn wrap_io<E>(err: E) -> Self
+    pub fn wrap_config<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        RBError {
+            kind: ErrorKind::Config,
+            source_error: Some(err.into()),
+            context: None,
+        }
+    }
+
+    pub fn wrap_credentials<E>(err: E) -> Self
     where
-        E: Into<Box<dyn Error + 'static>>,
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
     {
         RBError {
-            kind: ErrorKind::IO,
+            kind: ErrorKind::Credentials,
             source_error: Some(err.into()),
+            context: None,
         }
     }
+
+    pub fn wrap_expired_credentials<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        RBError {
+            kind: ErrorKind::ExpiredCredentials,
+            source_error: Some(err.into()),
+            context: None,
+        }
+    }
+
+    pub fn wrap_invalid_command<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        RBError {
+            kind: ErrorKind::InvalidCommand,
+            source_error: Some(err.into()),
+            context: None,
+        }
+    }
+
+    pub fn wrap_transient<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        RBError {
+            kind: ErrorKind::Transient,
+            source_error: Some(err.into()),
+            context: None,
+        }
+    }
+
+    // Missing files and permission problems get the same kinds as their S3 equivalents
+    pub fn wrap_io<E>(err: E) -> Self
+    where
+        E: Into<Box<dyn Error + Send + Sync + 'static>>,
+    {
+        let err = err.into();
+        let kind = match err.downcast_ref::<io::Error>().map(|e| e.kind()) {
+            Some(io::ErrorKind::NotFound) => ErrorKind::NotFound,
+            Some(io::ErrorKind::PermissionDenied) => ErrorKind::AccessDenied,
+            _ => ErrorKind::IO,
+        };
+        RBError::wrap(kind, err)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ErrorKind::AccessDenied => "access denied (check your credentials and permissions)",
+            ErrorKind::Cancelled => "cancelled",
+            ErrorKind::Config => "invalid configuration",
+            ErrorKind::Conflict => "conflicts with another operation",
+            ErrorKind::Credentials => "couldn't load AWS credentials",
+            ErrorKind::ExpiredCredentials => "AWS credentials have expired",
+            ErrorKind::IO => "local file operation failed",
+            ErrorKind::InvalidCommand => "invalid command",
+            ErrorKind::InvalidTarget => "invalid target",
+            ErrorKind::Network => "couldn't reach S3",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::Other => "unexpected error",
+            ErrorKind::Readline => "couldn't read input",
+            ErrorKind::S3 => "S3 request failed",
+            ErrorKind::TargetAlreadyExists => "target already exists",
+            ErrorKind::Throttled => "S3 is throttling requests",
+            ErrorKind::Transient => "S3 had a temporary failure",
+            ErrorKind::Unconfirmed => "not confirmed",
+            ErrorKind::UserExit => "exited",
+        };
+        write!(f, "{}", description)
+    }
 }
 
+// Reads like "downloading s3://b/k failed: No such file or directory (os error 2)": the context, if any, then each
+// error in the source chain. The kind only gets a mention when there's no underlying error to say more.
 impl fmt::Display for RBError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        if let Some(context) = &self.context {
+            write!(f, "{} failed: ", context)?;
+        }
+        let mut source = self.source();
+        if source.is_none() {
+            return write!(f, "{}", self.kind);
+        }
+        let mut first = true;
+        while let Some(err) = source {
+            if !first {
+                write!(f, ": ")?;
+            }
+            write!(f, "{}", err)?;
+            first = false;
+            // An S3ServiceError already explains itself; below it is just the raw response
+            source = if err.is::<S3ServiceError>() {
+                None
+            } else {
+                err.source()
+            };
+        }
+        Ok(())
     }
 }
 
 impl Error for RBError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.source_error.as_ref().map(|b| b.as_ref())
+        self.source_error
+            .as_ref()
+            .map(|b| b.as_ref() as &(dyn Error + 'static))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn errors_can_cross_threads() {
+        assert_send_sync::<RBError>();
+
+        let e = std::thread::spawn(|| RBError::wrap_io(io::Error::from(io::ErrorKind::NotFound)))
+            .join()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert!(e.source().is_some());
     }
 }
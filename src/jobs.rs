@@ -0,0 +1,186 @@
+use crate::describe_error;
+use crate::error::{ErrorKind, RBError};
+use crate::output::{self, OutputFormat};
+use crate::progress::{current_observer, observe_transfers};
+use crate::table::{terminal_width, Align, Table};
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+/// What a background job ended up with: its output if it succeeded, or what went wrong
+pub type JobResult = Result<String, RBError>;
+
+enum JobState {
+    Running(JoinHandle<JobResult>),
+    Finished(JobResult),
+    Killed,
+}
+
+struct Job {
+    id: usize,
+    description: String,
+    state: JobState,
+    // Whether the user has already been told that this job is no longer running
+    reported: bool,
+}
+
+impl Job {
+    // Takes the job's result out of its task once the task is done. Unless `block` is set, a job that's still running
+    // is left alone.
+    async fn collect(&mut self, block: bool) {
+        if let JobState::Running(handle) = &mut self.state {
+            if block || handle.is_finished() {
+                let result = handle
+                    .await
+                    .unwrap_or_else(|e| Err(RBError::wrap(ErrorKind::Other, e)));
+                self.state = JobState::Finished(result);
+            }
+        }
+    }
+
+    // The job's ID, state, and description, as a row of a status table
+    fn status_row(&self) -> Vec<String> {
+        let (state, description) = match &self.state {
+            JobState::Running(_) => ("Running", self.description.clone()),
+            JobState::Finished(Ok(_)) => ("Done", self.description.clone()),
+            JobState::Finished(Err(e)) => (
+                "Failed",
+                format!("{}: {}", self.description, describe_error(e)),
+            ),
+            JobState::Killed => ("Killed", self.description.clone()),
+        };
+        vec![format!("[{}]", self.id), String::from(state), description]
+    }
+}
+
+fn status_table() -> Table {
+    Table::new()
+        .align(0, Align::Right)
+        .max_width(terminal_width())
+}
+
+// Renders the rows of some jobs' statuses, lined up with each other
+fn status_lines<'a>(jobs: impl Iterator<Item = &'a Job>, mut table: Table) -> Vec<String> {
+    jobs.for_each(|job| table.push(job.status_row()));
+    table.render()
+}
+
+/// The background jobs started with `&` during this session. Jobs are numbered from 1, like in a shell, and stay in
+/// the table after they finish so that `jobs` can still say how they went.
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    last_id: usize,
+}
+
+impl JobTable {
+    /// Starts running `task` in the background and returns its job ID. The task runs as if `-q` were given, so that
+    /// its progress doesn't get drawn over the prompt, but any transfer observer still hears about it.
+    pub fn spawn<F>(&mut self, description: String, task: F) -> usize
+    where
+        F: Future<Output = JobResult> + Send + 'static,
+    {
+        self.last_id += 1;
+        // Task-locals don't carry over into a newly spawned task by themselves
+        let task = output::in_background(task);
+        let handle = match current_observer() {
+            Some(observer) => tokio::spawn(observe_transfers(observer, task)),
+            None => tokio::spawn(task),
+        };
+        self.jobs.push(Job {
+            id: self.last_id,
+            description,
+            state: JobState::Running(handle),
+            reported: false,
+        });
+        self.last_id
+    }
+
+    fn find(&mut self, id: usize) -> Result<&mut Job, RBError> {
+        self.jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    /// Whether a job is still going. Jobs that aren't in the table aren't.
+    pub fn is_running(&self, id: usize) -> bool {
+        self.jobs.iter().any(|job| {
+            job.id == id && matches!(&job.state, JobState::Running(handle) if !handle.is_finished())
+        })
+    }
+
+    /// A status line for every job. For people, there's a header line above them (unless there are no jobs at all).
+    pub async fn status_lines(&mut self) -> Vec<String> {
+        for job in &mut self.jobs {
+            job.collect(false).await;
+            if !matches!(job.state, JobState::Running(_)) {
+                job.reported = true;
+            }
+        }
+        // Scripts reading JSON or porcelain output want just the jobs
+        let table = match output::format() {
+            OutputFormat::Human if !self.jobs.is_empty() => {
+                status_table().headers(&["JOB", "STATE", "DESCRIPTION"])
+            }
+            _ => status_table(),
+        };
+        status_lines(self.jobs.iter(), table)
+    }
+
+    /// Status lines for the jobs that have stopped since the user was last told about them
+    pub async fn newly_finished(&mut self) -> Vec<String> {
+        let mut finished = Vec::new();
+        for (index, job) in self.jobs.iter_mut().enumerate() {
+            job.collect(false).await;
+            if !job.reported && !matches!(job.state, JobState::Running(_)) {
+                job.reported = true;
+                finished.push(index);
+            }
+        }
+        status_lines(
+            finished.iter().map(|index| &self.jobs[*index]),
+            status_table(),
+        )
+    }
+
+    /// Waits for a job to stop, or for all of them to if no ID is given, and returns their status lines
+    pub async fn wait(&mut self, id: Option<usize>) -> Result<Vec<String>, RBError> {
+        let waited_jobs: Vec<&mut Job> = match id {
+            Some(id) => vec![self.find(id)?],
+            None => self.jobs.iter_mut().collect(),
+        };
+        let mut table = status_table();
+        for job in waited_jobs {
+            job.collect(true).await;
+            job.reported = true;
+            table.push(job.status_row());
+        }
+        Ok(table.render())
+    }
+
+    /// Stops a running job. Its transfer is dropped, which cleans up after it the same way that Ctrl-C does.
+    pub fn kill(&mut self, id: usize) -> Result<String, RBError> {
+        let job = self.find(id)?;
+        if let JobState::Running(handle) = &job.state {
+            handle.abort();
+            job.state = JobState::Killed;
+        }
+        job.reported = true;
+        let mut table = status_table();
+        table.push(job.status_row());
+        Ok(table.render().join("\n"))
+    }
+}
+
+// A job shouldn't outlive the session that started it, so a library user dropping their Session stops its transfers
+impl Drop for JobTable {
+    fn drop(&mut self) {
+        for job in &self.jobs {
+            if let JobState::Running(handle) = &job.state {
+                handle.abort();
+            }
+        }
+    }
+}
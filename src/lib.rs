@@ -1,72 +1,791 @@
+#[macro_use]
+mod output;
+
+mod backend;
+mod checksum;
 mod commands;
+mod completion;
+mod config_file;
+mod credentials;
 pub mod error;
+mod jobs;
+mod listing_cache;
+mod local_backend;
+mod progress;
+mod queue;
+mod repl;
 mod s3;
+mod session;
+mod sso;
+mod table;
+mod tokenize;
 
+pub use crate::backend::StorageBackend;
+pub use crate::checksum::ChecksumAlgorithm;
+pub use crate::commands::ExistingTargetPolicy;
+use crate::commands::PutOptions;
+pub use crate::commands::{TransferOutcome, TransferReport};
+use crate::completion::RBHelper;
+use crate::config_file::EditModeSetting;
+pub use crate::credentials::SessionCredentials;
 use crate::error::{ErrorKind, RBError};
-use crate::s3::{S3Path, RBS3};
+use crate::jobs::JobTable;
+use crate::listing_cache::ListingCache;
+use crate::local_backend::LocalBackend;
+use crate::output::{format_timestamp, print_error, OutputFormat, Row};
+pub use crate::output::{CommandOutput, ListingFormat, TimeStyle};
+pub use crate::progress::TransferObserver;
+use crate::progress::{current_observer, format_duration, format_timing, observe_transfers};
+use crate::queue::TransferQueue;
+pub use crate::repl::LineReader;
+use crate::repl::RustylineReader;
+use crate::s3::{
+    AccessPointArn, ObjectLockStatus, RestoreStatus, S3Path, S3ServiceError, SelectFormat,
+    CANNED_ACLS, RESTORE_TIERS, STORAGE_CLASSES,
+};
+pub use crate::s3::{ClientConfig, GetOptions, ObjectEntry, RBS3Builder, RBS3};
+use crate::session::SessionState;
+use crate::table::{Align, Table};
 
+use std::collections::{BTreeMap, HashMap};
 use std::env::{current_dir, set_current_dir};
-use std::io;
+use std::error::Error;
+use std::future::{self, Future};
+use std::io::{self, IsTerminal, Write};
 use std::iter::Peekable;
-use std::path::PathBuf;
-use std::str::SplitWhitespace;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use path_clean::PathClean; // We use canonicalize() for local paths, but path_clean for remote paths
+use futures::StreamExt;
+pub use rusoto_core::Region;
 use rustyline::error::ReadlineError;
+use rustyline::{At, Cmd, EditMode, KeyCode, KeyEvent, Modifiers, Movement, Word};
+use serde_json::json;
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::signal::ctrl_c;
+use tokio::time::sleep;
 
 #[derive(Debug)]
 pub struct Config {
-    pub debug: bool,
-    pub single_command: Option<String>,
+    pub verbosity: u64,
+    pub commands: Vec<String>,
+    pub region: Option<String>,
+    pub force_path_style: bool,
+    pub no_sign_request: bool,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub no_verify_ssl: bool,
+    pub use_fips_endpoint: bool,
+    pub max_attempts: Option<u32>,
+    pub retry_base_delay: Option<Duration>,
+    pub script_file: Option<PathBuf>,
+    pub keep_going: bool,
+    pub quiet: bool,
+    pub history_size: Option<usize>,
+    pub config_file: Option<PathBuf>,
+    pub resume: bool,
+    pub remote_dir: Option<String>,
+    pub local_dir: Option<PathBuf>,
+    pub json: bool,
+    pub porcelain: bool,
+    pub notify_after: Option<Duration>,
+    pub backend: Option<String>,
 }
 
+/// A parsed rustbucket command, as produced by `parse_command`
 #[derive(Debug, Clone)]
-enum Command {
-    ListRemoteDirectory,
+#[non_exhaustive]
+pub enum Command {
+    /// `ls`: list the remote working directory, or all buckets at `/`
+    ListRemoteDirectory {
+        format: ListingFormat,
+        /// `--full-time`, to show exact modification times in a long listing whatever the config file says
+        full_time: bool,
+    },
+    /// `lls`: list the local working directory
     ListLocalDirectory,
+    /// `refresh`: forget cached remote listings, so that the next `ls` or tab completion asks S3 again
+    RefreshListings,
+    /// `pwd`
     PrintRemoteDirectory,
+    /// `lpwd`
     PrintLocalDirectory,
+    /// `cd DIR`, where `-` means the previous directory
     ChangeRemoteDirectory(String),
+    /// `lcd DIR`, where `-` means the previous directory
     ChangeLocalDirectory(String),
+    /// `pushd` with no directory swaps the current directory with the top of the stack, like it does in bash
+    PushRemoteDirectory(Option<String>),
+    /// `popd`
+    PopRemoteDirectory,
+    /// `dirs`
+    PrintRemoteDirectoryStack,
+    /// `region` with no argument
+    PrintRegion,
+    /// `region NAME`
+    ChangeRegion(String),
+    /// Runs the wrapped command and then reports how long it took
+    Time(Box<Command>),
+    /// A transfer followed by `&`, to be run as a background job
+    Background {
+        command: Box<Command>,
+        description: String,
+    },
+    /// `jobs`
+    ListJobs,
+    /// Waits for one job, or all of them
+    WaitForJobs(Option<usize>),
+    /// `kill ID`
+    KillJob(usize),
+    /// A transfer to add to the queue, without running it yet
+    QueueTransfer {
+        command: Box<Command>,
+        description: String,
+    },
+    /// `queue start`
+    StartQueue { parallel: usize },
+    /// `queue status`
+    PrintQueue,
+    /// `queue clear`
+    ClearQueue,
+    /// `mpu list`: the multipart uploads under the remote working directory that were never completed or aborted
+    ListIncompleteUploads,
+    /// `mpu abort KEY`, or `mpu abort --all [--yes]` (with no key) for every incomplete upload under the remote working
+    /// directory, which has to be confirmed (or given `--yes`) first
+    AbortIncompleteUploads {
+        key: Option<String>,
+        confirmed: bool,
+    },
+    /// `lock KEY`: the object's Object Lock retention and legal hold
+    PrintObjectLock(String),
+    /// `cp [-r] [-p N] SOURCE DESTINATION`, copying within S3
+    CopyRemote {
+        remote_source: String,
+        remote_destination: String,
+        recursive: bool,
+        parallel: usize,
+    },
+    /// `chclass [-r] TARGET CLASS` (or `chclass CLASS TARGET`), where the target may be a glob, and `-r` also changes
+    /// everything under a "directory"
+    ChangeStorageClass {
+        storage_class: String,
+        target: String,
+        recursive: bool,
+    },
+    /// `chtype TARGET MIME_TYPE`, where the target may be a glob
+    ChangeContentType {
+        target: String,
+        content_type: String,
+    },
+    /// `restore KEY [--days N] [--tier Bulk|Standard|Expedited] [--wait]`: starts bringing an archived object back for
+    /// a number of days, and with `--wait`, waits until it can be downloaded
+    RestoreObject {
+        target: String,
+        days: i64,
+        tier: Option<String>,
+        wait: bool,
+    },
+    /// `restore-status KEY`: whether an archived object's restored copy is ready yet
+    PrintRestoreStatus(String),
+    /// `url KEY`: the object's HTTPS URLs
+    PrintObjectUrl(String),
+    /// `rm [-r] TARGET...`, where each target may be a glob, and `-r` also removes everything under a "directory"
+    RemoveObjects {
+        targets: Vec<String>,
+        recursive: bool,
+    },
+    /// `versions [KEY]`: every version of one object, or of everything under the remote working directory, delete
+    /// markers included
+    ListVersions(Option<String>),
+    /// `undelete KEY`: removes the delete marker that's the object's latest version, bringing the object back
+    Undelete(String),
+    /// `df [--yes]`: how many objects the current bucket (or every bucket, at the root) holds and how big they are,
+    /// which has to be confirmed (or given `--yes`) when a bucket is big enough to take a while to add up
+    DiskUsage { confirmed: bool },
+    /// `du [PATH]`: how many objects are under a bucket or "directory" (the remote working directory by default), or
+    /// match a glob pattern, and how big they are all together
+    ObjectUsage(Option<String>),
+    /// `stat [--lock] KEY`: the size and modification time of an object, or of each one matching a glob pattern. With
+    /// `--lock`, its Object Lock retention and legal hold too, which takes two more requests per object.
+    StatObjects { target: String, lock: bool },
+    /// `select KEY SQL [--input csv|json|parquet] [--output csv|json]`: runs an S3 Select query against the object and
+    /// streams the results to stdout
+    SelectObject {
+        target: String,
+        expression: String,
+        input: Option<SelectFormat>,
+        output: Option<SelectFormat>,
+    },
+    /// `acl KEY`: the grants in the object's access control list
+    PrintObjectAcl(String),
+    /// `acl set KEY CANNED_ACL`: replaces the object's access control list with a canned one, like `private`
+    SetObjectAcl { target: String, acl: String },
+    /// `meta KEY`: the object's user metadata
+    PrintObjectMetadata(String),
+    /// `meta set KEY NAME=value...`: adds to or changes the object's user metadata, which means copying the object onto
+    /// itself
+    SetObjectMetadata {
+        target: String,
+        metadata: HashMap<String, String>,
+    },
+    /// `tags get KEY`: the object's tags
+    PrintObjectTags(String),
+    /// `tags set KEY NAME=value...`: adds tags to the object, or changes the values of ones it already has
+    SetObjectTags {
+        target: String,
+        tags: BTreeMap<String, String>,
+    },
+    /// `tags rm KEY [NAME...]`: removes the named tags from the object, or all of them if none are named
+    RemoveObjectTags { target: String, names: Vec<String> },
+    /// `cors`: the current bucket's CORS rules
+    PrintCorsRules,
+    /// `encryption`: the current bucket's default encryption
+    PrintEncryption,
+    /// `versioning` with no argument: whether the current bucket keeps old versions of its objects
+    PrintVersioning,
+    /// `versioning enable` or `versioning suspend`, which has to be confirmed (or given `--yes`) first
+    SetVersioning { enabled: bool, confirmed: bool },
+    /// `set` with no argument
+    PrintVariables,
+    /// `set NAME=value`
+    SetVariable { name: String, value: String },
+    /// `get SOURCE [DESTINATION]`, where the source may be a glob
     GetFile {
         remote_source: String,
         local_destination: Option<String>,
+        if_exists: ExistingTargetPolicy,
+        get_options: GetOptions,
     },
+    /// `put SOURCE [DESTINATION]`
     PutFile {
         local_source: String,
         remote_destination: Option<String>,
+        metadata: HashMap<String, String>,
+        if_exists: ExistingTargetPolicy,
+        checksum: Option<ChecksumAlgorithm>,
     },
 }
 
-fn warn_if_more_words(mut words: Peekable<SplitWhitespace>) {
+impl Command {
+    // The remote paths, as given (so relative to the remote working directory), where a command may change which
+    // objects there are or what they're like, so that cached listings of them can't be trusted afterwards
+    fn changed_remote_paths(&self) -> Vec<&str> {
+        match self {
+            Command::PutFile {
+                remote_destination, ..
+            } => vec![remote_destination.as_deref().unwrap_or(".")],
+            Command::RemoveObjects { targets, .. } => targets.iter().map(String::as_str).collect(),
+            Command::CopyRemote {
+                remote_destination, ..
+            } => vec![remote_destination],
+            Command::ChangeStorageClass { target, .. }
+            | Command::ChangeContentType { target, .. }
+            | Command::SetObjectMetadata { target, .. }
+            | Command::SetObjectAcl { target, .. }
+            | Command::SetObjectTags { target, .. }
+            | Command::RemoveObjectTags { target, .. }
+            | Command::Undelete(target) => vec![target],
+            Command::Time(wrapped_cmd)
+            | Command::Background {
+                command: wrapped_cmd,
+                ..
+            }
+            | Command::QueueTransfer {
+                command: wrapped_cmd,
+                ..
+            } => wrapped_cmd.changed_remote_paths(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Returns a copy of this command that will overwrite its target if it already exists, unless the command was given
+    // an explicit policy like --skip-existing. Commands that never overwrite anything are returned unchanged.
+    fn forced(&self) -> Command {
+        let mut forced_cmd = self.clone();
+        match &mut forced_cmd {
+            Command::GetFile { if_exists, .. } | Command::PutFile { if_exists, .. }
+                if *if_exists == ExistingTargetPolicy::Fail =>
+            {
+                *if_exists = ExistingTargetPolicy::Overwrite
+            }
+            Command::Time(wrapped_cmd)
+            | Command::Background {
+                command: wrapped_cmd,
+                ..
+            }
+            | Command::QueueTransfer {
+                command: wrapped_cmd,
+                ..
+            } => **wrapped_cmd = wrapped_cmd.forced(),
+            _ => {}
+        }
+        forced_cmd
+    }
+
+    // Returns a copy of this command that goes ahead with a change it would otherwise have asked about first. This is
+    // kept apart from forced(), so that answering "all" to an overwrite prompt doesn't also answer every question after
+    // it.
+    fn confirmed(&self) -> Command {
+        let mut confirmed_cmd = self.clone();
+        match &mut confirmed_cmd {
+            Command::SetVersioning { confirmed, .. }
+            | Command::DiskUsage { confirmed }
+            | Command::AbortIncompleteUploads { confirmed, .. } => *confirmed = true,
+            Command::Time(wrapped_cmd)
+            | Command::Background {
+                command: wrapped_cmd,
+                ..
+            }
+            | Command::QueueTransfer {
+                command: wrapped_cmd,
+                ..
+            } => **wrapped_cmd = wrapped_cmd.confirmed(),
+            _ => {}
+        }
+        confirmed_cmd
+    }
+}
+
+// The directory argument of cd, lcd, and pushd, which is all of their words joined back up. Unquoted directory names
+// with spaces in them still work, as they always have.
+fn join_dir_words<'a, I>(first_word: &'a str, words: I) -> String
+where
+    I: Iterator<Item = &'a str>,
+{
+    std::iter::once(first_word)
+        .chain(words)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn warn_if_more_words<'a, I>(mut words: Peekable<I>)
+where
+    I: Iterator<Item = &'a str>,
+{
     if words.peek().is_some() {
         let extra_words = words.count();
-        println!(
+        eprintln!(
             "Command doesn't take any more arguments, but {} more were given; ignoring them.",
             extra_words
         );
     }
 }
 
-// todo: non-cd commands don't support paths with spaces; none of the commands support quoted or escaped arguments to
-// deal with the spaces problem
-fn parse_command(cmd_str: String) -> Result<Command, RBError> {
-    let trimmed = cmd_str.trim();
-    let mut words = trimmed.split_whitespace().peekable();
+// Parses a `set` argument of the form `NAME=value`. Names follow the same rules as environment variable names in a shell,
+// since that's how they're referred to (`$NAME`) afterwards.
+fn parse_variable_assignment(assignment: &str) -> Result<(String, String), RBError> {
+    match assignment.split_once('=') {
+        Some((name, value))
+            if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok((name.to_owned(), value.to_owned()))
+        }
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+fn parse_region(region_name: &str) -> Result<Region, RBError> {
+    Region::from_str(region_name).map_err(|_| RBError::new(ErrorKind::InvalidTarget))
+}
+
+// Parses a `--meta` argument of the form `key=value`. S3 stores user metadata keys lowercased (and prefixes them with
+// `x-amz-meta-` on the wire), so we lowercase them here too in order to make repeated keys collide predictably.
+fn parse_metadata_pair(pair: &str) -> Result<(String, String), RBError> {
+    match pair.split_once('=') {
+        Some((meta_key, meta_value)) if !meta_key.is_empty() => {
+            Ok((meta_key.to_lowercase(), meta_value.to_owned()))
+        }
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+// Checks that a `chtype` argument at least looks like a MIME type (`type/subtype`, maybe with parameters after a `;`).
+// S3 itself takes anything.
+fn parse_content_type(content_type: &str) -> Result<String, RBError> {
+    let essence = content_type.split(';').next().unwrap_or("");
+    match essence.split_once('/') {
+        Some((type_, subtype))
+            if !type_.trim().is_empty() && !subtype.trim().is_empty() && !subtype.contains('/') =>
+        {
+            Ok(content_type.to_owned())
+        }
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+// Parses a `select --input` or `--output` argument. Parquet can only be read.
+fn parse_select_format(format: &str, is_output: bool) -> Result<SelectFormat, RBError> {
+    match format.to_lowercase().as_str() {
+        "csv" => Ok(SelectFormat::Csv),
+        "json" => Ok(SelectFormat::Json),
+        "parquet" if !is_output => Ok(SelectFormat::Parquet),
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+// Parses a `tags set` argument of the form `NAME=value`. Unlike metadata keys, tag names keep their case.
+fn parse_tag(pair: &str) -> Result<(String, String), RBError> {
+    match pair.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+    }
+}
+
+// Turns a `--range` argument like `0-1048575`, `1024-`, or `-512` (the last 512 bytes) into the value S3 expects in
+// its Range header
+fn parse_byte_range(range: &str) -> Result<String, RBError> {
+    let (start, end) = range
+        .split_once('-')
+        .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+    let parse_bound = |bound: &str| -> Result<Option<u64>, RBError> {
+        if bound.is_empty() {
+            Ok(None)
+        } else {
+            bound
+                .parse()
+                .map(Some)
+                .map_err(|_| RBError::new(ErrorKind::InvalidTarget))
+        }
+    };
+
+    match (parse_bound(start)?, parse_bound(end)?) {
+        (None, None) => Err(RBError::new(ErrorKind::InvalidTarget)),
+        (Some(start), Some(end)) if start > end => Err(RBError::new(ErrorKind::InvalidTarget)),
+        _ => Ok(format!("bytes={}", range)),
+    }
+}
+
+// How many objects `cp -r` copies at once, unless it's told otherwise. Copies happen within S3, so the only limit on
+// them here is how many requests we want in flight.
+const DEFAULT_PARALLEL_COPIES: usize = 8;
+// How long a restored copy of an archived object sticks around before S3 deletes it again
+const DEFAULT_RESTORE_DAYS: i64 = 1;
+// The most entries a streamed `ls` prints at once. S3 returns pages of up to 1000 keys.
+const LISTING_PAGE_SIZE: usize = 1000;
+
+// Checks a storage class argument against the ones S3 knows. They're accepted in any case, but S3 only takes them in
+// upper case.
+fn parse_storage_class(storage_class: &str) -> Result<String, RBError> {
+    let storage_class = storage_class.to_uppercase();
+    if STORAGE_CLASSES.contains(&storage_class.as_str()) {
+        Ok(storage_class)
+    } else {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+}
+
+// Checks a restore tier argument against the ones S3 knows, accepting any case
+fn parse_restore_tier(tier: &str) -> Result<String, RBError> {
+    RESTORE_TIERS
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(tier))
+        .map(|name| String::from(*name))
+        .ok_or(RBError::new(ErrorKind::InvalidTarget))
+}
+
+// Every command word that parse_command understands, including aliases. Unambiguous prefixes of these are accepted
+// too.
+const COMMAND_NAMES: [&str; 44] = [
+    "acl",
+    "cd",
+    "chclass",
+    "chtype",
+    "cors",
+    "cp",
+    "df",
+    "dir",
+    "dirs",
+    "du",
+    "encryption",
+    "exit",
+    "get",
+    "jobs",
+    "kill",
+    "lcd",
+    "ldir",
+    "lls",
+    "lock",
+    "lpwd",
+    "ls",
+    "meta",
+    "mpu",
+    "popd",
+    "pushd",
+    "put",
+    "pwd",
+    "queue",
+    "quit",
+    "refresh",
+    "region",
+    "restore",
+    "restore-status",
+    "rm",
+    "select",
+    "set",
+    "stat",
+    "tags",
+    "time",
+    "undelete",
+    "url",
+    "versioning",
+    "versions",
+    "wait",
+];
+
+// Abbreviations that would be ambiguous but have always meant one command, and still do
+const PREFERRED_ABBREVIATIONS: [(&str, &str); 2] = [("q", "quit"), ("qu", "quit")];
+
+// The Levenshtein distance between two words: how many single-character insertions, deletions, and substitutions it
+// takes to turn one into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current_row = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+            current_row.push(
+                (previous_row[j] + substitution_cost)
+                    .min(previous_row[j + 1] + 1)
+                    .min(current_row[j] + 1),
+            );
+        }
+        previous_row = current_row;
+    }
+    previous_row[b_chars.len()]
+}
+
+// Builds the error for an unknown command word, suggesting the closest known commands if any are close enough to be
+// plausible typos
+fn unknown_command_error(word: &str) -> RBError {
+    // Past this many edits, a "suggestion" is more likely to be confusing than helpful
+    const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+    let distances: Vec<(usize, &str)> = COMMAND_NAMES
+        .iter()
+        .map(|name| (edit_distance(word, name), *name))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .collect();
+    match distances.iter().map(|(distance, _)| *distance).min() {
+        Some(closest) => {
+            let suggestions: Vec<String> = distances
+                .iter()
+                .filter(|(distance, _)| *distance == closest)
+                .map(|(_, name)| format!("'{}'", name))
+                .collect();
+            RBError::wrap_invalid_command(format!(
+                "Unknown command '{}'. Did you mean {}?",
+                word,
+                suggestions.join(" or ")
+            ))
+        }
+        None => RBError::new(ErrorKind::InvalidCommand),
+    }
+}
+
+// Expands an unambiguous abbreviation like `pw` into the full command word (`pwd`)
+fn resolve_command_word(word: &str) -> Result<&'static str, RBError> {
+    if let Some(name) = COMMAND_NAMES.iter().find(|name| **name == word) {
+        return Ok(name);
+    }
+    if let Some((_, name)) = PREFERRED_ABBREVIATIONS
+        .iter()
+        .find(|(abbreviation, _)| *abbreviation == word)
+    {
+        return Ok(name);
+    }
+
+    let candidates: Vec<&'static str> = COMMAND_NAMES
+        .iter()
+        .copied()
+        .filter(|name| name.starts_with(word))
+        .collect();
+    match candidates.as_slice() {
+        [] => Err(unknown_command_error(word)),
+        [name] => Ok(name),
+        _ => Err(RBError::wrap_invalid_command(format!(
+            "Ambiguous command '{}'. It could be any of: {}",
+            word,
+            candidates.join(", ")
+        ))),
+    }
+}
+
+// Replaces `%N` (and `%N-%M` ranges) with the paths of entries in the last listing, counting from 1. Quoted words are
+// left as they are.
+fn expand_listing_references(
+    words: Vec<tokenize::Word>,
+    last_listing: &[String],
+) -> Result<Vec<String>, RBError> {
+    let entry = |reference: &str| -> Option<usize> {
+        let index: usize = reference.strip_prefix('%')?.parse().ok()?;
+        index.checked_sub(1).filter(|i| *i < last_listing.len())
+    };
+
+    let mut expanded = Vec::with_capacity(words.len());
+    for tokenize::Word {
+        text: token,
+        leading_quoted,
+    } in words
+    {
+        if leading_quoted || !token.starts_with('%') {
+            expanded.push(token);
+            continue;
+        }
+        let (first, last) = match token.split_once('-') {
+            Some((start, end)) => (entry(start), entry(end)),
+            None => (entry(&token), entry(&token)),
+        };
+        match (first, last) {
+            (Some(first), Some(last)) if first <= last => {
+                expanded.extend_from_slice(&last_listing[first..=last])
+            }
+            _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Where to send a command's output instead of the terminal
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Redirect {
+    /// A trailing `> file` or `>> file`. The path is relative to the local working directory at the time the command
+    /// finishes.
+    File { path: String, append: bool },
+    /// A `| command` pipeline, run by the local shell
+    Pipe(String),
+}
+
+// Takes a trailing `> file` or `>> file` (or `>file`/`>>file`) off the end of a command's words. Nothing may follow the
+// file name. A quoted or escaped `>` at the start of a word is just part of it.
+fn split_redirect(words: &mut Vec<tokenize::Word>) -> Result<Option<Redirect>, RBError> {
+    let redirect_index = match words
+        .iter()
+        .position(|word| !word.leading_quoted && word.text.starts_with('>'))
+    {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    let mut redirect_words = words
+        .split_off(redirect_index)
+        .into_iter()
+        .map(|word| word.text);
+    let operator = redirect_words.next().unwrap_or_default();
+    let (append, attached_path) = match operator.strip_prefix(">>") {
+        Some(rest) => (true, rest.to_owned()),
+        None => (false, operator[1..].to_owned()),
+    };
+    let path = if attached_path.is_empty() {
+        redirect_words
+            .next()
+            .ok_or(RBError::new(ErrorKind::InvalidTarget))?
+    } else {
+        attached_path
+    };
+    if redirect_words.next().is_some() {
+        return Err(RBError::new(ErrorKind::InvalidCommand));
+    }
+    Ok(Some(Redirect::File { path, append }))
+}
+
+/// Parses one line of rustbucket's command language, the same way the interactive prompt does, into the command and
+/// where its output should go. `$VAR` is expanded from the environment. Since there's no session here, `%N` references
+/// to a previous listing are rejected with `ErrorKind::InvalidTarget`.
+///
+/// Errors are `ErrorKind::InvalidCommand` for unknown commands and malformed syntax (the error's `source()` may hold a
+/// more specific message, like a did-you-mean suggestion), and `ErrorKind::InvalidTarget` for bad arguments.
+pub fn parse_command(input: &str) -> Result<(Command, Option<Redirect>), RBError> {
+    parse_session_command(input.to_owned(), &[], &HashMap::new())
+}
+
+// Parses a command line in the context of a session: `%N` refers to its last listing, and its variables are expanded
+fn parse_session_command(
+    cmd_str: String,
+    last_listing: &[String],
+    variables: &HashMap<String, String>,
+) -> Result<(Command, Option<Redirect>), RBError> {
+    let joined = tokenize::join_continuations(&cmd_str);
+    // Everything after the pipe is handed to the shell as-is, so it's split off before we tokenize anything
+    let (cmd_part, pipeline) = tokenize::split_pipe(&joined);
+    let mut words = tokenize::tokenize(cmd_part, variables)?;
+    // Redirects are found before references are expanded, so that a listed key starting with `>` stays a key
+    let file_redirect = split_redirect(&mut words)?;
+    let mut tokens = expand_listing_references(words, last_listing)?;
+    let redirect = match (file_redirect, pipeline.map(str::trim)) {
+        (None, None) => None,
+        (Some(file_redirect), None) => Some(file_redirect),
+        (None, Some(pipeline)) if !pipeline.is_empty() => Some(Redirect::Pipe(pipeline.to_owned())),
+        _ => return Err(RBError::new(ErrorKind::InvalidCommand)),
+    };
+    let background = tokens.last().map(String::as_str) == Some("&");
+    if background {
+        tokens.pop();
+    }
+    let cmd = parse_words(&tokens)?;
+    if !background {
+        return Ok((cmd, redirect));
+    }
+    match cmd {
+        Command::GetFile { .. } | Command::PutFile { .. } => Ok((
+            Command::Background {
+                command: Box::new(cmd),
+                description: tokens.join(" "),
+            },
+            redirect,
+        )),
+        // Only transfers take long enough to be worth running in the background
+        _ => Err(RBError::new(ErrorKind::InvalidCommand)),
+    }
+}
+
+// Parses the job ID argument of `wait` or `kill`. Unlike in a shell there's no `%` in front, since `%1` already means
+// the first entry of the last listing.
+fn parse_job_id(word: &str) -> Result<usize, RBError> {
+    word.parse()
+        .map_err(|_| RBError::new(ErrorKind::InvalidTarget))
+}
+
+fn parse_words(tokens: &[String]) -> Result<Command, RBError> {
+    let mut words = tokens.iter().map(String::as_str).peekable();
 
-    match words.next().unwrap_or("invalid").to_lowercase().as_str() {
+    let command_word = words.next().unwrap_or("invalid").to_lowercase();
+    match resolve_command_word(&command_word)? {
         "exit" | "quit" => {
             warn_if_more_words(words);
             Err(RBError::new(ErrorKind::UserExit))
         }
         "ls" | "dir" => {
-            warn_if_more_words(words);
-            Ok(Command::ListRemoteDirectory)
+            let mut format = ListingFormat::Short;
+            let mut full_time = false;
+            let mut other_words: Vec<&str> = Vec::new();
+            for word in words {
+                match word {
+                    // `--csv` and `-0` pick their own fields, so they win whether or not `-l` comes too
+                    "-l" if format == ListingFormat::Short => format = ListingFormat::Long,
+                    "-l" => {}
+                    "--csv" => format = ListingFormat::Csv,
+                    "-0" | "--print0" => format = ListingFormat::Print0,
+                    "--full-time" => full_time = true,
+                    _ => other_words.push(word),
+                }
+            }
+            warn_if_more_words(other_words.into_iter().peekable());
+            Ok(Command::ListRemoteDirectory { format, full_time })
         }
         "lls" | "ldir" => {
             warn_if_more_words(words);
             Ok(Command::ListLocalDirectory)
         }
+        "refresh" => {
+            warn_if_more_words(words);
+            Ok(Command::RefreshListings)
+        }
         "pwd" => {
             warn_if_more_words(words);
             Ok(Command::PrintRemoteDirectory)
@@ -76,41 +795,484 @@ fn parse_command(cmd_str: String) -> Result<Command, RBError> {
             Ok(Command::PrintLocalDirectory)
         }
         "cd" => match words.next() {
-            Some(_) => {
-                let cmd_arg = trimmed
-                    .strip_prefix("cd ") // Below error only possible if non-space whitespace was used
-                    .ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?
-                    .trim();
-                Ok(Command::ChangeRemoteDirectory(cmd_arg.to_owned()))
+            Some(first_word) => {
+                let cmd_arg = join_dir_words(first_word, words);
+                Ok(Command::ChangeRemoteDirectory(cmd_arg))
             }
             None => Err(RBError::new(ErrorKind::InvalidTarget)),
         },
         "lcd" => match words.next() {
-            Some(_) => {
-                let cmd_arg = trimmed
-                    .strip_prefix("lcd ") // Below error only possible if non-space whitespace was used
-                    .ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?
-                    .trim();
-                Ok(Command::ChangeLocalDirectory(cmd_arg.to_owned()))
+            Some(first_word) => {
+                let cmd_arg = join_dir_words(first_word, words);
+                Ok(Command::ChangeLocalDirectory(cmd_arg))
             }
             None => Err(RBError::new(ErrorKind::InvalidTarget)),
         },
-        "get" => {
-            let source = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
-            let destination = words.next();
+        "pushd" => match words.next() {
+            Some(first_word) => {
+                let cmd_arg = join_dir_words(first_word, words);
+                Ok(Command::PushRemoteDirectory(Some(cmd_arg)))
+            }
+            None => Ok(Command::PushRemoteDirectory(None)),
+        },
+        "popd" => {
+            warn_if_more_words(words);
+            Ok(Command::PopRemoteDirectory)
+        }
+        "dirs" => {
+            warn_if_more_words(words);
+            Ok(Command::PrintRemoteDirectoryStack)
+        }
+        "region" => match words.next() {
+            Some(region_name) => {
+                warn_if_more_words(words);
+                Ok(Command::ChangeRegion(region_name.to_owned()))
+            }
+            None => Ok(Command::PrintRegion),
+        },
+        "time" => match parse_words(&tokens[1..])? {
+            // `time time ls` is just `time ls`
+            Command::Time(timed_cmd) => Ok(Command::Time(timed_cmd)),
+            timed_cmd => Ok(Command::Time(Box::new(timed_cmd))),
+        },
+        "jobs" => {
+            warn_if_more_words(words);
+            Ok(Command::ListJobs)
+        }
+        "wait" => match words.next() {
+            Some(job_id) => {
+                warn_if_more_words(words);
+                Ok(Command::WaitForJobs(Some(parse_job_id(job_id)?)))
+            }
+            None => Ok(Command::WaitForJobs(None)),
+        },
+        "kill" => {
+            let job_id = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+            warn_if_more_words(words);
+            Ok(Command::KillJob(parse_job_id(job_id)?))
+        }
+        "queue" => match words.next() {
+            Some("add") => match parse_words(&tokens[2..])? {
+                queued_cmd @ Command::GetFile { .. } | queued_cmd @ Command::PutFile { .. } => {
+                    Ok(Command::QueueTransfer {
+                        command: Box::new(queued_cmd),
+                        description: tokens[2..].join(" "),
+                    })
+                }
+                _ => Err(RBError::new(ErrorKind::InvalidCommand)),
+            },
+            Some("start") => {
+                let mut parallel = 1;
+                while let Some(word) = words.next() {
+                    match word {
+                        "-p" | "--parallel" => {
+                            parallel = words
+                                .next()
+                                .and_then(|count| count.parse().ok())
+                                .filter(|count| *count > 0)
+                                .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        }
+                        _ => return Err(RBError::new(ErrorKind::InvalidCommand)),
+                    }
+                }
+                Ok(Command::StartQueue { parallel })
+            }
+            Some("status") | None => Ok(Command::PrintQueue),
+            Some("clear") => {
+                warn_if_more_words(words);
+                Ok(Command::ClearQueue)
+            }
+            Some(_) => Err(RBError::new(ErrorKind::InvalidCommand)),
+        },
+        "lock" => match words.next() {
+            Some(target) => {
+                warn_if_more_words(words);
+                Ok(Command::PrintObjectLock(target.to_owned()))
+            }
+            None => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "mpu" => match words.next() {
+            Some("list") | None => {
+                warn_if_more_words(words);
+                Ok(Command::ListIncompleteUploads)
+            }
+            Some("abort") => match words.next() {
+                Some("--all") => {
+                    let confirmed = match words.next() {
+                        Some("-y") | Some("--yes") => true,
+                        Some(_) => return Err(RBError::new(ErrorKind::InvalidTarget)),
+                        None => false,
+                    };
+                    warn_if_more_words(words);
+                    Ok(Command::AbortIncompleteUploads {
+                        key: None,
+                        confirmed,
+                    })
+                }
+                Some(key) => {
+                    warn_if_more_words(words);
+                    Ok(Command::AbortIncompleteUploads {
+                        key: Some(key.to_owned()),
+                        confirmed: true,
+                    })
+                }
+                None => Err(RBError::new(ErrorKind::InvalidTarget)),
+            },
+            Some(_) => Err(RBError::new(ErrorKind::InvalidCommand)),
+        },
+        "cp" => {
+            let mut positional_args: Vec<&str> = Vec::new();
+            let mut recursive = false;
+            let mut parallel = DEFAULT_PARALLEL_COPIES;
+            while let Some(word) = words.next() {
+                match word {
+                    "-r" | "-R" | "--recursive" => recursive = true,
+                    "-p" | "--parallel" => {
+                        parallel = words
+                            .next()
+                            .and_then(|count| count.parse().ok())
+                            .filter(|count| *count > 0)
+                            .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                    }
+                    _ => positional_args.push(word),
+                }
+            }
+            let mut args = positional_args.into_iter().peekable();
+            let source = args.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+            let destination = args.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+            warn_if_more_words(args);
+            Ok(Command::CopyRemote {
+                remote_source: source.to_owned(),
+                remote_destination: destination.to_owned(),
+                recursive,
+                parallel,
+            })
+        }
+        "rm" => {
+            let mut targets = Vec::new();
+            let mut recursive = false;
+            for word in words {
+                match word {
+                    "-r" | "-R" | "--recursive" => recursive = true,
+                    _ => targets.push(word.to_owned()),
+                }
+            }
+            if targets.is_empty() {
+                return Err(RBError::new(ErrorKind::InvalidTarget));
+            }
+            Ok(Command::RemoveObjects { targets, recursive })
+        }
+        "chclass" => {
+            let mut positional_args = Vec::new();
+            let mut recursive = false;
+            for word in words {
+                match word {
+                    "-r" | "-R" | "--recursive" => recursive = true,
+                    _ => positional_args.push(word),
+                }
+            }
+            // The class can come before or after the target. When both look like classes, the target comes first.
+            let (target, storage_class) = match positional_args.as_slice() {
+                [target, storage_class] if parse_storage_class(storage_class).is_ok() => {
+                    (target, storage_class)
+                }
+                [storage_class, target] => (target, storage_class),
+                _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+            };
+            Ok(Command::ChangeStorageClass {
+                storage_class: parse_storage_class(storage_class)?,
+                target: (*target).to_owned(),
+                recursive,
+            })
+        }
+        "chtype" => match (words.next(), words.next()) {
+            (Some(target), Some(content_type)) => {
+                warn_if_more_words(words);
+                Ok(Command::ChangeContentType {
+                    target: target.to_owned(),
+                    content_type: parse_content_type(content_type)?,
+                })
+            }
+            _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "restore" => {
+            let mut target = None;
+            let mut days = DEFAULT_RESTORE_DAYS;
+            let mut tier = None;
+            let mut wait = false;
+            while let Some(word) = words.next() {
+                match word {
+                    "--wait" => wait = true,
+                    "--days" => {
+                        days = words
+                            .next()
+                            .and_then(|days| days.parse().ok())
+                            .filter(|days| *days > 0)
+                            .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                    }
+                    "--tier" => {
+                        let name = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        tier = Some(parse_restore_tier(name)?);
+                    }
+                    _ if target.is_none() => target = Some(word.to_owned()),
+                    _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+                }
+            }
+            match target {
+                Some(target) => Ok(Command::RestoreObject {
+                    target,
+                    days,
+                    tier,
+                    wait,
+                }),
+                None => Err(RBError::new(ErrorKind::InvalidTarget)),
+            }
+        }
+        "restore-status" => match words.next() {
+            Some(target) => {
+                warn_if_more_words(words);
+                Ok(Command::PrintRestoreStatus(target.to_owned()))
+            }
+            None => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "url" => match words.next() {
+            Some(target) => {
+                warn_if_more_words(words);
+                Ok(Command::PrintObjectUrl(target.to_owned()))
+            }
+            None => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "versions" => {
+            let target = words.next().map(str::to_owned);
+            warn_if_more_words(words);
+            Ok(Command::ListVersions(target))
+        }
+        "undelete" => match words.next() {
+            Some(target) => {
+                warn_if_more_words(words);
+                Ok(Command::Undelete(target.to_owned()))
+            }
+            None => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "df" => {
+            let confirmed = match words.next() {
+                Some("-y") | Some("--yes") => true,
+                Some(_) => return Err(RBError::new(ErrorKind::InvalidTarget)),
+                None => false,
+            };
+            warn_if_more_words(words);
+            Ok(Command::DiskUsage { confirmed })
+        }
+        "du" => {
+            let target = words.next().map(str::to_owned);
+            warn_if_more_words(words);
+            Ok(Command::ObjectUsage(target))
+        }
+        "stat" => {
+            let lock = words.next_if_eq(&"--lock").is_some();
+            match words.next() {
+                Some(target) => {
+                    warn_if_more_words(words);
+                    Ok(Command::StatObjects {
+                        target: target.to_owned(),
+                        lock,
+                    })
+                }
+                None => Err(RBError::new(ErrorKind::InvalidTarget)),
+            }
+        }
+        "select" => {
+            let mut positional_args = Vec::new();
+            let mut input = None;
+            let mut output = None;
+            while let Some(word) = words.next() {
+                match word {
+                    "--input" => {
+                        let format = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        input = Some(parse_select_format(format, false)?);
+                    }
+                    "--output" => {
+                        let format = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        output = Some(parse_select_format(format, true)?);
+                    }
+                    _ => positional_args.push(word),
+                }
+            }
+            match positional_args.as_slice() {
+                [target, expression] => Ok(Command::SelectObject {
+                    target: (*target).to_owned(),
+                    expression: (*expression).to_owned(),
+                    input,
+                    output,
+                }),
+                _ => Err(RBError::new(ErrorKind::InvalidTarget)),
+            }
+        }
+        "acl" => match words.next() {
+            // `acl set` on its own shows the ACL of an object that's actually called "set"
+            Some("set") if words.peek().is_some() => {
+                let target = words.next().map(str::to_owned).unwrap_or_default();
+                let acl = words
+                    .next()
+                    .and_then(|acl| {
+                        CANNED_ACLS
+                            .iter()
+                            .find(|name| name.eq_ignore_ascii_case(acl))
+                    })
+                    .map(|acl| String::from(*acl))
+                    .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                warn_if_more_words(words);
+                Ok(Command::SetObjectAcl { target, acl })
+            }
+            Some(target) => {
+                warn_if_more_words(words);
+                Ok(Command::PrintObjectAcl(target.to_owned()))
+            }
+            None => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "meta" => match words.next() {
+            // `meta set` on its own shows the metadata of an object that's actually called "set"
+            Some("set") if words.peek().is_some() => {
+                let target = words.next().map(str::to_owned).unwrap_or_default();
+                let metadata = words
+                    .map(parse_metadata_pair)
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+                if metadata.is_empty() {
+                    return Err(RBError::new(ErrorKind::InvalidTarget));
+                }
+                Ok(Command::SetObjectMetadata { target, metadata })
+            }
+            Some(target) => {
+                warn_if_more_words(words);
+                Ok(Command::PrintObjectMetadata(target.to_owned()))
+            }
+            None => Err(RBError::new(ErrorKind::InvalidTarget)),
+        },
+        "tags" => {
+            let subcommand = words.next();
+            let target = words
+                .next()
+                .map(str::to_owned)
+                .ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+            match subcommand {
+                Some("get") => {
+                    warn_if_more_words(words);
+                    Ok(Command::PrintObjectTags(target))
+                }
+                Some("set") => {
+                    let tags = words
+                        .map(parse_tag)
+                        .collect::<Result<BTreeMap<_, _>, _>>()?;
+                    if tags.is_empty() {
+                        return Err(RBError::new(ErrorKind::InvalidTarget));
+                    }
+                    Ok(Command::SetObjectTags { target, tags })
+                }
+                Some("rm") => Ok(Command::RemoveObjectTags {
+                    target,
+                    names: words.map(str::to_owned).collect(),
+                }),
+                _ => Err(RBError::new(ErrorKind::InvalidCommand)),
+            }
+        }
+        "cors" => {
+            warn_if_more_words(words);
+            Ok(Command::PrintCorsRules)
+        }
+        "encryption" => {
+            warn_if_more_words(words);
+            Ok(Command::PrintEncryption)
+        }
+        "versioning" => {
+            let enabled = match words.next() {
+                Some("enable") => true,
+                Some("suspend") => false,
+                Some(_) => return Err(RBError::new(ErrorKind::InvalidCommand)),
+                None => return Ok(Command::PrintVersioning),
+            };
+            let confirmed = match words.next() {
+                Some("-y") | Some("--yes") => true,
+                Some(_) => return Err(RBError::new(ErrorKind::InvalidTarget)),
+                None => false,
+            };
             warn_if_more_words(words);
+            Ok(Command::SetVersioning { enabled, confirmed })
+        }
+        "set" => match words.next() {
+            Some(assignment) => {
+                warn_if_more_words(words);
+                let (name, value) = parse_variable_assignment(assignment)?;
+                Ok(Command::SetVariable { name, value })
+            }
+            None => Ok(Command::PrintVariables),
+        },
+        "get" => {
+            let mut positional_args: Vec<&str> = Vec::new();
+            let mut if_exists = ExistingTargetPolicy::Fail;
+            let mut get_options = GetOptions::default();
+            while let Some(word) = words.next() {
+                match word {
+                    "-f" | "--force" => if_exists = ExistingTargetPolicy::Overwrite,
+                    "--skip-existing" => if_exists = ExistingTargetPolicy::SkipExisting,
+                    "--newer-only" => if_exists = ExistingTargetPolicy::NewerOnly,
+                    "--version-id" => {
+                        let version_id =
+                            words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        get_options.version_id = Some(version_id.to_owned());
+                    }
+                    "--range" => {
+                        let range = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        get_options.range = Some(parse_byte_range(range)?);
+                    }
+                    _ => positional_args.push(word),
+                }
+            }
+
+            let mut args = positional_args.into_iter().peekable();
+            let source = args.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+            let destination = args.next();
+            warn_if_more_words(args);
             Ok(Command::GetFile {
                 remote_source: source.to_owned(),
                 local_destination: destination.map(|dest_str| dest_str.to_owned()),
+                if_exists,
+                get_options,
             })
         }
         "put" => {
-            let source = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
-            let destination = words.next();
-            warn_if_more_words(words);
+            let mut positional_args: Vec<&str> = Vec::new();
+            let mut metadata: HashMap<String, String> = HashMap::new();
+            let mut if_exists = ExistingTargetPolicy::Fail;
+            let mut checksum = None;
+            while let Some(word) = words.next() {
+                match word {
+                    "-f" | "--force" => if_exists = ExistingTargetPolicy::Overwrite,
+                    "--skip-existing" => if_exists = ExistingTargetPolicy::SkipExisting,
+                    "--newer-only" => if_exists = ExistingTargetPolicy::NewerOnly,
+                    "--checksum" => {
+                        let algorithm =
+                            words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        checksum = Some(algorithm.parse::<ChecksumAlgorithm>()?);
+                    }
+                    "--meta" => {
+                        let pair = words.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+                        let (meta_key, meta_value) = parse_metadata_pair(pair)?;
+                        metadata.insert(meta_key, meta_value);
+                    }
+                    _ => positional_args.push(word),
+                }
+            }
+
+            let mut args = positional_args.into_iter().peekable();
+            let source = args.next().ok_or(RBError::new(ErrorKind::InvalidTarget))?;
+            let destination = args.next();
+            warn_if_more_words(args);
             Ok(Command::PutFile {
                 local_source: source.to_owned(),
                 remote_destination: destination.map(|dest_str| dest_str.to_owned()),
+                metadata,
+                if_exists,
+                checksum,
             })
         }
         // todo: mget? mput?
@@ -118,196 +1280,2382 @@ fn parse_command(cmd_str: String) -> Result<Command, RBError> {
     }
 }
 
+/// How hard to try when S3 requests fail in ways that might succeed on another attempt
+#[derive(Debug, Copy, Clone)]
+struct RetryPolicy {
+    /// Total attempts per command, including the first one
+    max_attempts: u32,
+    /// The wait before the first retry. Each retry after that waits twice as long as the last.
+    base_delay: Duration,
+}
+
+// Same as the AWS SDKs' "standard" retry mode
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+// No matter how many attempts are allowed, don't leave the user waiting on a single backoff for longer than this
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(20);
+
+impl RetryPolicy {
+    fn delay_before_attempt(&self, attempt: u32) -> Duration {
+        // `attempt` starts at 2 for the first retry
+        let multiplier = 2u32.saturating_pow(attempt.saturating_sub(2));
+        self.base_delay
+            .checked_mul(multiplier)
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
+}
+
+// Runs a get or put, which may transfer several files if it was given a glob. This doesn't need the rest of the Runner,
+// so that it can also be run as a background job.
+async fn run_transfer(
+    s3: &dyn StorageBackend,
+    remote_cwd: &Path,
+    local_cwd: &Path,
+    cmd: &Command,
+) -> Result<Vec<TransferReport>, RBError> {
+    match cmd {
+        Command::GetFile {
+            remote_source,
+            local_destination,
+            if_exists,
+            get_options,
+        } => {
+            if commands::is_glob(remote_source) {
+                let matches = commands::expand_remote_glob(s3, remote_cwd, remote_source).await?;
+                // Brackets are legal in keys, so a "pattern" that matches nothing might just be a literal key
+                if !matches.is_empty() {
+                    return commands::get_matching_files(
+                        s3,
+                        remote_cwd,
+                        local_cwd,
+                        matches,
+                        local_destination,
+                        *if_exists,
+                        get_options,
+                    )
+                    .await;
+                }
+            }
+
+            let report = commands::get_file(
+                s3,
+                remote_cwd,
+                local_cwd,
+                remote_source,
+                local_destination,
+                *if_exists,
+                get_options,
+            )
+            .await?;
+            Ok(vec![report])
+        }
+        Command::PutFile {
+            local_source,
+            remote_destination,
+            metadata,
+            if_exists,
+            checksum,
+        } => {
+            let report = commands::put_file(
+                s3,
+                remote_cwd,
+                local_cwd,
+                local_source,
+                remote_destination,
+                *if_exists,
+                &PutOptions {
+                    metadata: metadata.clone(),
+                    checksum: *checksum,
+                },
+            )
+            .await?;
+            Ok(vec![report])
+        }
+        _ => Err(RBError::new(ErrorKind::InvalidCommand)),
+    }
+}
+
 struct Runner {
     local_cwd: PathBuf,
     remote_cwd: PathBuf,
+    // Where `cd -` and `lcd -` go back to
+    previous_local_cwd: Option<PathBuf>,
+    previous_remote_cwd: Option<PathBuf>,
+    // The pushd/popd stack, with the most recently pushed directory last
+    remote_dir_stack: Vec<PathBuf>,
+    // The last directory visited in each bucket, which is saved along with the rest of the session
+    bucket_dirs: HashMap<String, PathBuf>,
+    // Full paths of the entries in the last ls or lls, for `%N` references
+    last_listing: Vec<String>,
+    // Set with `set NAME=value` and expanded with `$NAME`, taking precedence over environment variables
+    variables: HashMap<String, String>,
+    // Running totals of everything get and put have transferred this session, for `time` to measure against
+    transferred_objects: u64,
+    transferred_bytes: u64,
+    jobs: JobTable,
+    transfer_queue: TransferQueue,
+    // Commands that take at least this long ring the terminal bell when they finish
+    notify_after: Option<Duration>,
+    // How `ls -l` shows modification times when `--full-time` isn't given
+    time_style: TimeStyle,
+    // Recent `ls` results, which commands that change objects invalidate as they go
+    listing_cache: ListingCache,
+    // The job ID of each background job that may change remote objects, and the (absolute) remote paths it changes,
+    // so that listings of them can be forgotten once it's done
+    background_changes: Vec<(usize, Vec<PathBuf>)>,
+    // Whether a plain `ls` may print its names to stdout as they come in, rather than returning them all at the end
+    print_listing_pages: bool,
+    // Whether the command being run has its output redirected, so that output which would otherwise go straight to
+    // stdout (like select's records) has to be returned instead
+    output_redirected: bool,
+    // Buckets that S3 has told us are in a different region, so that going back to them later goes straight there
+    bucket_regions: HashMap<String, Region>,
     s3: RBS3,
+    // Set with `--backend local:PATH`, in which case it's used for everything instead of S3
+    local_backend: Option<LocalBackend>,
+    retry_policy: RetryPolicy,
 }
 
 impl Runner {
-    fn new(local_cwd: PathBuf, remote_cwd: PathBuf) -> Self {
+    fn new(local_cwd: PathBuf, remote_cwd: PathBuf, s3: RBS3, retry_policy: RetryPolicy) -> Self {
         Runner {
             local_cwd,
             remote_cwd,
-            s3: RBS3::new(),
+            previous_local_cwd: None,
+            previous_remote_cwd: None,
+            remote_dir_stack: Vec::new(),
+            bucket_dirs: HashMap::new(),
+            last_listing: Vec::new(),
+            variables: HashMap::new(),
+            transferred_objects: 0,
+            transferred_bytes: 0,
+            jobs: JobTable::default(),
+            transfer_queue: TransferQueue::default(),
+            notify_after: None,
+            time_style: TimeStyle::default(),
+            listing_cache: ListingCache::default(),
+            background_changes: Vec::new(),
+            print_listing_pages: false,
+            output_redirected: false,
+            bucket_regions: HashMap::new(),
+            s3,
+            local_backend: None,
+            retry_policy,
         }
     }
 
-    async fn run_command(&mut self, cmd: &Command) -> Result<String, RBError> {
-        match cmd {
-            Command::PrintRemoteDirectory => Ok(format!(
-                "Remote directory is now: {}",
-                self.remote_cwd.display()
-            )),
-            Command::PrintLocalDirectory => Ok(format!(
-                "Local directory is now: {}",
-                self.local_cwd.display()
-            )),
-            Command::ListRemoteDirectory => match S3Path::try_from_path(&self.remote_cwd) {
-                Ok(s3_path) => commands::list_remote_path(&self.s3, s3_path).await,
-                Err(e) if e.kind() == ErrorKind::InvalidTarget => {
-                    println!("No valid S3 bucket path provided! Resetting remote path to '/' and listing all available buckets");
-                    self.remote_cwd = PathBuf::from("/");
-                    let buckets = self.s3.list_buckets().await?;
-                    Ok(buckets.join("\n"))
-                }
-                Err(e) => Err(e),
-            },
-            Command::ListLocalDirectory => commands::list_local_path(&self.local_cwd),
-            Command::ChangeRemoteDirectory(dir) => {
-                // TODO: use S3 to validate that the requested bucket and prefix path exist
-                self.remote_cwd.push(dir);
-                self.remote_cwd = self.remote_cwd.clean();
-                Ok(format!(
-                    "Remote directory is now: {}",
-                    self.remote_cwd.display()
-                ))
+    // Whichever store the session's objects live in. It's an Arc so that background jobs can take it with them.
+    fn backend(&self) -> Arc<dyn StorageBackend> {
+        match &self.local_backend {
+            Some(local_backend) => Arc::new(local_backend.clone()),
+            None => Arc::new(self.s3.clone()),
+        }
+    }
+
+    // Runs a command, but gives up on it if the user hits Ctrl-C first. Dropping the command's future is what cancels
+    // it, so any cleanup (like removing a partial download) happens in destructors.
+    async fn run_cancellable_command(&mut self, cmd: &Command) -> Result<CommandOutput, RBError> {
+        let started_at = Instant::now();
+        let result = tokio::select! {
+            result = self.run_command(cmd) => result,
+            _ = ctrl_c() => Err(RBError::new(ErrorKind::Cancelled)),
+        };
+
+        // Ring the terminal bell when a long command is done, whether it worked or not, in case the user has switched
+        // to another window in the meantime. Someone who just hit Ctrl-C is clearly still here.
+        let cancelled = matches!(&result, Err(e) if e.kind() == ErrorKind::Cancelled);
+        if let Some(notify_after) = self.notify_after {
+            if started_at.elapsed() >= notify_after && !cancelled && io::stderr().is_terminal() {
+                eprint!("\x07");
             }
-            Command::ChangeLocalDirectory(dir) => {
-                let new_path = self.local_cwd.join(dir);
-                let canonical_path = new_path.canonicalize().and_then(|canonical_path| {
-                    set_current_dir(&canonical_path)?;
+        }
+        result
+    }
+
+    // Runs a command whose output is headed for `redirect` if there is one, or else for stdout. Only output that's
+    // headed for stdout can be printed bit by bit while the command runs.
+    async fn run_redirectable_command(
+        &mut self,
+        cmd: &Command,
+        redirect: Option<&Redirect>,
+    ) -> Result<CommandOutput, RBError> {
+        let print_listing_pages = self.print_listing_pages;
+        self.print_listing_pages &= redirect.is_none();
+        self.output_redirected = redirect.is_some();
+        let result = self.run_cancellable_command(cmd).await;
+        self.print_listing_pages = print_listing_pages;
+        self.output_redirected = false;
+        result
+    }
+
+    // Runs a command, timing it (retries and all) if it was prefixed with `time`. The timing goes to stderr, like the
+    // shell's `time`, so that it doesn't get mixed into output that's been redirected.
+    async fn run_command(&mut self, cmd: &Command) -> Result<CommandOutput, RBError> {
+        self.invalidate_background_changes();
+        let result = match cmd {
+            Command::Time(timed_cmd) => {
+                let started_at = Instant::now();
+                let (objects_before, bytes_before) =
+                    (self.transferred_objects, self.transferred_bytes);
+                let result = self.run_command_with_retries(timed_cmd).await;
+                eprintln!(
+                    "{}",
+                    format_timing(
+                        started_at.elapsed(),
+                        self.transferred_objects - objects_before,
+                        self.transferred_bytes - bytes_before
+                    )
+                );
+                result
+            }
+            _ => self.run_command_with_retries(cmd).await,
+        };
+        // Even a command that failed may have changed some objects before it did
+        self.invalidate_listings(cmd);
+        result
+    }
+
+    fn invalidate_listings(&mut self, cmd: &Command) {
+        // A background job's changes are made when it finishes, and a queued transfer's by `queue start`
+        if matches!(
+            cmd,
+            Command::Background { .. } | Command::QueueTransfer { .. }
+        ) {
+            return;
+        }
+        for path in cmd.changed_remote_paths() {
+            let path = commands::resolve_remote_path(&self.remote_cwd, path);
+            self.invalidate_listings_at(&path);
+        }
+    }
+
+    // `path` is an absolute remote path
+    fn invalidate_listings_at(&mut self, path: &Path) {
+        match S3Path::try_from_path(path) {
+            Ok(s3_path) => self.listing_cache.invalidate(&s3_path),
+            Err(_) => self.listing_cache.clear(),
+        }
+    }
+
+    // Forgets the listings that background jobs have changed, once they've finished (or been killed)
+    fn invalidate_background_changes(&mut self) {
+        let (finished, running) = mem::take(&mut self.background_changes)
+            .into_iter()
+            .partition(|(job_id, _)| !self.jobs.is_running(*job_id));
+        self.background_changes = running;
+        for (_, paths) in finished {
+            for path in paths {
+                self.invalidate_listings_at(&path);
+            }
+        }
+    }
+
+    // Lists a prefix like list_remote_path does, but prints the names to stdout a page at a time as they arrive, so that
+    // a big listing starts showing up straight away. Names are only sorted within each page. Returns the entries in the
+    // order they were printed.
+    async fn print_remote_listing(&mut self, s3_path: S3Path) -> Result<Vec<ObjectEntry>, RBError> {
+        let (bucket, key) = match &s3_path {
+            S3Path {
+                bucket: Some(bucket),
+                key,
+            } => (bucket.clone(), key.clone()),
+            _ => return Err(RBError::new(ErrorKind::InvalidTarget)),
+        };
+        let backend = self.backend();
+        let mut pages = backend
+            .list_files_stream(bucket, key.map(|key| format!("{}/", key)))
+            .ready_chunks(LISTING_PAGE_SIZE);
+        let mut entries = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page
+                .into_iter()
+                .collect::<Result<Vec<ObjectEntry>, RBError>>()?;
+            let rendered = CommandOutput::Objects {
+                entries: page.clone(),
+                format: ListingFormat::Short,
+                time_style: self.time_style,
+            }
+            .render_text();
+            let mut stdout = io::stdout();
+            write!(stdout, "{}", rendered)
+                .and_then(|_| stdout.flush())
+                .map_err(RBError::wrap_io)?;
+            entries.extend(page);
+        }
+
+        let mut sorted_entries = entries.clone();
+        sorted_entries.sort_by(|a, b| b.is_prefix.cmp(&a.is_prefix).then(a.name.cmp(&b.name)));
+        self.listing_cache.insert(&s3_path, sorted_entries);
+        Ok(entries)
+    }
+
+    // Lists a remote directory (or the buckets), reusing the listing from a recent `ls` of the same place if there is one
+    async fn list_remote_path(&mut self, s3_path: S3Path) -> Result<Vec<ObjectEntry>, RBError> {
+        if let Some(entries) = self.listing_cache.get(&s3_path) {
+            return Ok(entries);
+        }
+        let entries = commands::list_remote_path(self.backend().as_ref(), &s3_path).await?;
+        self.listing_cache.insert(&s3_path, entries.clone());
+        Ok(entries)
+    }
+
+    // Temporary credentials can expire partway through a long session. When a command fails because of that, we get
+    // fresh credentials and give the command one more try. The same goes for a bucket that turns out to be in another
+    // region than the one we asked.
+    async fn run_command_with_retries(&mut self, cmd: &Command) -> Result<CommandOutput, RBError> {
+        let mut attempt = 1;
+        let mut refreshed_credentials = false;
+        let mut followed_redirect = false;
+        loop {
+            match self.run_command_once(cmd).await {
+                // Refreshed credentials either work or they don't, so there's no point in doing this more than once
+                Err(e) if e.kind() == ErrorKind::ExpiredCredentials && !refreshed_credentials => {
+                    eprintln!("AWS credentials have expired; refreshing them and retrying...");
+                    self.refresh_credentials()?;
+                    refreshed_credentials = true;
+                }
+                // Like refreshing credentials, switching regions either fixes things or it doesn't
+                Err(e) if !followed_redirect && self.wrong_region_bucket(&e).is_some() => {
+                    self.follow_region_redirect(&e).await?;
+                    followed_redirect = true;
+                }
+                Err(e) if e.is_retryable() && attempt < self.retry_policy.max_attempts => {
+                    attempt += 1;
+                    if let Some(observer) = current_observer() {
+                        observer.retry_occurred(attempt, &e);
+                    }
+                    let delay = self.retry_policy.delay_before_attempt(attempt);
+                    status!(
+                        "Request failed ({}); retrying in {} (attempt {} of {})...",
+                        e,
+                        format_duration(delay),
+                        attempt,
+                        self.retry_policy.max_attempts
+                    );
+                    sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn refresh_credentials(&mut self) -> Result<(), RBError> {
+        let mut client_config = self.s3.config().clone();
+        if !client_config.no_sign_request {
+            client_config.credentials = credentials::session_credentials(&client_config.region)?;
+        }
+        self.s3 = RBS3::new(client_config)?;
+        Ok(())
+    }
+
+    // Fills in the placeholders in a prompt template: {path} (the bucket and prefix), {bucket}, {prefix}, {region},
+    // {profile}, and {local} (the local directory)
+    fn render_prompt(&self, template: &str) -> String {
+        let remote_cwd = self.remote_cwd.to_string_lossy();
+        let path = remote_cwd.trim_start_matches('/');
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        template
+            .replace("{path}", if path.is_empty() { "/" } else { path })
+            .replace("{bucket}", bucket)
+            .replace("{prefix}", prefix)
+            .replace("{region}", self.s3.region().name())
+            .replace("{profile}", &credentials::profile_name())
+            .replace("{local}", &self.local_cwd.to_string_lossy())
+    }
+
+    fn remote_cwd_output(&self) -> CommandOutput {
+        let remote_cwd = self.remote_cwd.display().to_string();
+        let message = format!("Remote directory is now: {}", remote_cwd);
+        CommandOutput::value("remote_directory", &remote_cwd, message)
+    }
+
+    // Like `dirs` in bash: the current directory first, then the rest of the stack from the top down
+    fn remote_dir_stack_output(&self) -> CommandOutput {
+        let entries = std::iter::once(&self.remote_cwd)
+            .chain(self.remote_dir_stack.iter().rev())
+            .map(|dir| dir.display().to_string())
+            .collect();
+        CommandOutput::listing(entries, "")
+    }
+
+    // Where `cd` goes when it's entering the top of a bucket from outside of it: back to wherever we last were in that
+    // bucket, including in a resumed session. Going to the top of the bucket again from in there stays at the top.
+    fn last_dir_in_bucket(&self, new_path: &Path) -> Option<PathBuf> {
+        let bucket = match S3Path::try_from_path(new_path) {
+            Ok(S3Path {
+                bucket: Some(bucket),
+                key: None,
+            }) => bucket,
+            _ => return None,
+        };
+        let current_bucket = S3Path::try_from_path(&self.remote_cwd)
+            .ok()
+            .and_then(|current| current.bucket);
+        if current_bucket.as_ref() == Some(&bucket) {
+            return None;
+        }
+        self.bucket_dirs.get(&bucket).cloned()
+    }
+
+    fn set_remote_cwd(&mut self, new_path: PathBuf) {
+        if let Ok(S3Path {
+            bucket: Some(bucket),
+            ..
+        }) = S3Path::try_from_path(&new_path)
+        {
+            self.bucket_dirs.insert(bucket.clone(), new_path.clone());
+            // If the client can't be rebuilt here, the next request will just be redirected again and say why
+            if let Some(region) = self.bucket_regions.get(&bucket) {
+                if region != self.s3.region() {
+                    if let Ok(s3) = self.s3.to_builder().region(region.clone()).build() {
+                        self.s3 = s3;
+                    }
+                }
+            }
+        }
+        self.previous_remote_cwd = Some(mem::replace(&mut self.remote_cwd, new_path));
+    }
+
+    // The bucket that an error is about, if it's because that bucket is in another region than the client's. Custom
+    // endpoints don't have regions to move between, and neither does a local backend. Access points are always sent
+    // to their own region already.
+    fn wrong_region_bucket(&self, e: &RBError) -> Option<String> {
+        if self.local_backend.is_some() || matches!(self.s3.region(), Region::Custom { .. }) {
+            return None;
+        }
+        e.source()
+            .and_then(|source| source.downcast_ref::<S3ServiceError>())
+            .filter(|service_error| service_error.is_wrong_region())?;
+        e.context()
+            .and_then(|context| context.bucket.clone())
+            .or_else(|| S3Path::try_from_path(&self.remote_cwd).ok()?.bucket)
+            .filter(|bucket| AccessPointArn::split_path(bucket).is_none())
+    }
+
+    // Moves the client to whichever region the bucket that `e` is about is in, taking S3's word for it if the error
+    // said, or asking otherwise. The region is remembered for the rest of the session.
+    async fn follow_region_redirect(&mut self, e: &RBError) -> Result<(), RBError> {
+        let bucket = match self.wrong_region_bucket(e) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        let named_region = e
+            .source()
+            .and_then(|source| source.downcast_ref::<S3ServiceError>())
+            .and_then(|service_error| service_error.bucket_region.as_deref())
+            .and_then(|region_name| region_name.parse::<Region>().ok());
+        let region = match named_region {
+            Some(region) => region,
+            None => self.s3.bucket_region(bucket.clone()).await?,
+        };
+        status!(
+            "Bucket {} is in {}; switching to that region and retrying...",
+            bucket,
+            region.name()
+        );
+        self.s3 = self.s3.to_builder().region(region.clone()).build()?;
+        self.bucket_regions.insert(bucket, region);
+        Ok(())
+    }
+
+    // Sends a command's output where it was redirected: into a file (created if needed), or to the standard input of a
+    // local shell pipeline, which runs in the local working directory
+    async fn write_redirected(&self, output: &str, redirect: &Redirect) -> Result<(), RBError> {
+        match redirect {
+            Redirect::File { path, append } => {
+                let path = commands::resolve_local_path(&self.local_cwd, path);
+                commands::write_local_file(&path, output, *append)
+            }
+            Redirect::Pipe(pipeline) => {
+                commands::pipe_to_shell(&self.local_cwd, pipeline, output).await
+            }
+        }
+    }
+
+    fn record_transfer(&mut self, report: &TransferReport) {
+        if matches!(
+            report.outcome,
+            TransferOutcome::Downloaded | TransferOutcome::Uploaded
+        ) {
+            self.transferred_objects += 1;
+            self.transferred_bytes += report.bytes;
+        }
+    }
+
+    fn session_state(&self) -> SessionState {
+        SessionState {
+            remote_cwd: self.remote_cwd.clone(),
+            local_cwd: self.local_cwd.clone(),
+            bucket_dirs: self.bucket_dirs.clone(),
+        }
+    }
+
+    fn local_cwd_output(&self) -> CommandOutput {
+        let local_cwd = self.local_cwd.display().to_string();
+        let message = format!("Local directory is now: {}", local_cwd);
+        CommandOutput::value("local_directory", &local_cwd, message)
+    }
+
+    async fn run_command_once(&mut self, cmd: &Command) -> Result<CommandOutput, RBError> {
+        match cmd {
+            Command::PrintRemoteDirectory => Ok(self.remote_cwd_output()),
+            Command::RefreshListings => {
+                self.listing_cache.clear();
+                Ok(CommandOutput::Message(String::from(
+                    "Cached listings cleared; the next ones will come straight from S3.",
+                )))
+            }
+            Command::PrintLocalDirectory => Ok(self.local_cwd_output()),
+            Command::ListRemoteDirectory { format, full_time } => {
+                if self.print_listing_pages
+                    && *format == ListingFormat::Short
+                    && output::format() == OutputFormat::Human
+                {
+                    if let Ok(
+                        s3_path @ S3Path {
+                            bucket: Some(_), ..
+                        },
+                    ) = S3Path::try_from_path(&self.remote_cwd)
+                    {
+                        if self.listing_cache.get(&s3_path).is_none() {
+                            let entries = self.print_remote_listing(s3_path).await?;
+                            self.last_listing = entries
+                                .iter()
+                                .map(|entry| {
+                                    self.remote_cwd.join(&entry.name).display().to_string()
+                                })
+                                .collect();
+                            // Everything has been printed already. An empty listing (now cached) still gets its
+                            // message below.
+                            if !entries.is_empty() {
+                                return Ok(CommandOutput::Empty);
+                            }
+                        }
+                    }
+                }
+                let mut entries = match S3Path::try_from_path(&self.remote_cwd) {
+                    Ok(s3_path) => self.list_remote_path(s3_path).await?,
+                    Err(e) if e.kind() == ErrorKind::InvalidTarget => {
+                        status!("No valid S3 bucket path provided! Resetting remote path to '/' and listing all available buckets");
+                        self.remote_cwd = PathBuf::from("/");
+                        commands::list_buckets(self.backend().as_ref()).await?
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.last_listing = entries
+                    .iter()
+                    .map(|entry| self.remote_cwd.join(&entry.name).display().to_string())
+                    .collect();
+                // A manifest is more use with whole keys than with names relative to the current directory
+                if *format == ListingFormat::Csv {
+                    if let Ok(S3Path { key: Some(key), .. }) =
+                        S3Path::try_from_path(&self.remote_cwd)
+                    {
+                        entries
+                            .iter_mut()
+                            .for_each(|entry| entry.name = format!("{}/{}", key, entry.name));
+                    }
+                }
+                Ok(CommandOutput::Objects {
+                    entries,
+                    format: *format,
+                    time_style: if *full_time {
+                        TimeStyle::Full
+                    } else {
+                        self.time_style
+                    },
+                })
+            }
+            Command::ListLocalDirectory => {
+                let entries = commands::list_local_path(&self.local_cwd)?;
+                self.last_listing = entries
+                    .iter()
+                    .map(|entry| self.local_cwd.join(entry).display().to_string())
+                    .collect();
+                Ok(CommandOutput::listing(entries, ""))
+            }
+            Command::PrintRegion => Ok(CommandOutput::value(
+                "region",
+                self.s3.region().name(),
+                format!("Region is: {}", self.s3.region().name()),
+            )),
+            Command::ChangeRegion(region_name) => {
+                // Clients are tied to a region, so switching regions means starting over with a new one
+                self.s3 = self
+                    .s3
+                    .to_builder()
+                    .region(parse_region(region_name)?)
+                    .build()?;
+                Ok(CommandOutput::value(
+                    "region",
+                    self.s3.region().name(),
+                    format!("Region is now: {}", self.s3.region().name()),
+                ))
+            }
+            Command::Time(_) => unreachable!("run_command unwraps timed commands"),
+            Command::PrintVariables => {
+                let mut assignments: Vec<String> = self
+                    .variables
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                assignments.sort_unstable();
+                Ok(CommandOutput::listing(assignments, ""))
+            }
+            Command::SetVariable { name, value } => {
+                self.variables.insert(name.clone(), value.clone());
+                Ok(CommandOutput::value(
+                    name,
+                    value,
+                    format!("{}={}", name, value),
+                ))
+            }
+            Command::ChangeRemoteDirectory(dir) => {
+                // TODO: use S3 to validate that the requested bucket and prefix path exist
+                let new_path = if dir == "-" {
+                    self.previous_remote_cwd
+                        .clone()
+                        .ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?
+                } else {
+                    let new_path = commands::resolve_remote_path(&self.remote_cwd, dir);
+                    self.last_dir_in_bucket(&new_path).unwrap_or(new_path)
+                };
+                self.set_remote_cwd(new_path);
+                Ok(self.remote_cwd_output())
+            }
+            Command::PushRemoteDirectory(Some(dir)) => {
+                let new_path = commands::resolve_remote_path(&self.remote_cwd, dir);
+                self.remote_dir_stack.push(self.remote_cwd.clone());
+                self.set_remote_cwd(new_path);
+                Ok(self.remote_dir_stack_output())
+            }
+            Command::PushRemoteDirectory(None) => {
+                let top = self
+                    .remote_dir_stack
+                    .pop()
+                    .ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?;
+                self.remote_dir_stack.push(self.remote_cwd.clone());
+                self.set_remote_cwd(top);
+                Ok(self.remote_dir_stack_output())
+            }
+            Command::PopRemoteDirectory => {
+                let top = self
+                    .remote_dir_stack
+                    .pop()
+                    .ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?;
+                self.set_remote_cwd(top);
+                Ok(self.remote_dir_stack_output())
+            }
+            Command::PrintRemoteDirectoryStack => Ok(self.remote_dir_stack_output()),
+            Command::ChangeLocalDirectory(dir) => {
+                let new_path = if dir == "-" {
+                    self.previous_local_cwd
+                        .clone()
+                        .ok_or_else(|| RBError::new(ErrorKind::InvalidTarget))?
+                } else {
+                    commands::resolve_local_path(&self.local_cwd, dir)
+                };
+                let canonical_path = new_path.canonicalize().and_then(|canonical_path| {
+                    set_current_dir(&canonical_path)?;
                     Ok(canonical_path)
                 });
                 match canonical_path {
                     Ok(good_new_path) => {
-                        self.local_cwd = good_new_path;
-                        Ok(format!(
-                            "Local directory is now: {}",
-                            self.local_cwd.display()
-                        ))
+                        self.previous_local_cwd =
+                            Some(mem::replace(&mut self.local_cwd, good_new_path));
+                        Ok(self.local_cwd_output())
                     }
                     Err(io_err) => match io_err.kind() {
-                        io::ErrorKind::NotFound => {
-                            Ok(format!("Directory not found: {}", new_path.display()))
-                        }
-                        io::ErrorKind::InvalidInput => {
-                            Ok(format!("Invalid path: {}", new_path.display()))
-                        }
+                        io::ErrorKind::NotFound => Ok(CommandOutput::Message(format!(
+                            "Directory not found: {}",
+                            new_path.display()
+                        ))),
+                        io::ErrorKind::InvalidInput => Ok(CommandOutput::Message(format!(
+                            "Invalid path: {}",
+                            new_path.display()
+                        ))),
                         _ => Err(RBError::wrap_io(io_err)),
                     },
                 }
             }
-            Command::GetFile {
-                remote_source,
-                local_destination,
-            } => {
-                commands::get_file(
-                    &self.s3,
+            Command::GetFile { .. } | Command::PutFile { .. } => {
+                let reports = run_transfer(
+                    self.backend().as_ref(),
                     &self.remote_cwd,
                     &self.local_cwd,
-                    remote_source,
-                    local_destination,
+                    cmd,
                 )
-                .await
+                .await?;
+                reports
+                    .iter()
+                    .for_each(|report| self.record_transfer(report));
+                Ok(CommandOutput::Transfers(reports))
             }
-            Command::PutFile {
-                local_source,
+            Command::Background {
+                command,
+                description,
+            } => {
+                let (backend, remote_cwd, local_cwd) = (
+                    self.backend(),
+                    self.remote_cwd.clone(),
+                    self.local_cwd.clone(),
+                );
+                let command = command.as_ref().clone();
+                let changed_paths = command
+                    .changed_remote_paths()
+                    .into_iter()
+                    .map(|path| commands::resolve_remote_path(&remote_cwd, path))
+                    .collect();
+                let job_id = self.jobs.spawn(description.clone(), async move {
+                    run_transfer(backend.as_ref(), &remote_cwd, &local_cwd, &command)
+                        .await
+                        .map(|reports| CommandOutput::Transfers(reports).render())
+                });
+                self.background_changes.push((job_id, changed_paths));
+                Ok(CommandOutput::value(
+                    "job",
+                    &job_id.to_string(),
+                    format!("[{}] {}", job_id, description),
+                ))
+            }
+            Command::ListJobs => Ok(CommandOutput::listing(
+                self.jobs.status_lines().await,
+                "No jobs.",
+            )),
+            Command::WaitForJobs(job_id) => Ok(CommandOutput::listing(
+                self.jobs.wait(*job_id).await?,
+                "No jobs.",
+            )),
+            Command::KillJob(job_id) => Ok(CommandOutput::Message(self.jobs.kill(*job_id)?)),
+            Command::QueueTransfer {
+                command,
+                description,
+            } => {
+                self.transfer_queue.add(
+                    command.as_ref().clone(),
+                    description.clone(),
+                    self.remote_cwd.clone(),
+                    self.local_cwd.clone(),
+                );
+                Ok(CommandOutput::Message(format!("Queued: {}", description)))
+            }
+            Command::StartQueue { parallel } => {
+                let (succeeded, failed) = self
+                    .transfer_queue
+                    .run(self.backend().as_ref(), *parallel)
+                    .await;
+                for path in self.transfer_queue.changed_remote_paths() {
+                    self.invalidate_listings_at(&path);
+                }
+                Ok(CommandOutput::Message(format!(
+                    "Queue finished: {} succeeded, {} failed.",
+                    succeeded, failed
+                )))
+            }
+            Command::PrintQueue => Ok(CommandOutput::listing(
+                self.transfer_queue.status_lines(),
+                "The queue is empty.",
+            )),
+            Command::ClearQueue => {
+                self.transfer_queue.clear();
+                Ok(CommandOutput::Message(String::from("Queue cleared.")))
+            }
+            Command::ListIncompleteUploads => {
+                let uploads =
+                    commands::list_incomplete_uploads(self.backend().as_ref(), &self.remote_cwd)
+                        .await?;
+                let mut table = Table::new();
+                for upload in uploads {
+                    table.push(vec![
+                        upload.initiated.map(format_timestamp).unwrap_or_default(),
+                        upload.key,
+                        upload.upload_id,
+                    ]);
+                }
+                Ok(CommandOutput::listing(
+                    table.render(),
+                    "There are no incomplete uploads here.",
+                ))
+            }
+            Command::PrintObjectLock(target) => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let status = self.backend().object_lock_status(bucket, key).await?;
+                let mut table = Table::new();
+                table.push(vec![
+                    String::from("Retention:"),
+                    describe_retention(&status),
+                ]);
+                table.push(vec![
+                    String::from("Legal hold:"),
+                    describe_legal_hold(&status),
+                ]);
+                Ok(CommandOutput::rows(
+                    "object_lock",
+                    vec![object_lock_fields(&status)],
+                    table,
+                    "",
+                ))
+            }
+            Command::CopyRemote {
+                remote_source,
                 remote_destination,
+                recursive,
+                parallel,
             } => {
-                commands::put_file(
-                    &self.s3,
+                let reports = commands::copy_remote(
+                    self.backend().as_ref(),
                     &self.remote_cwd,
-                    &self.local_cwd,
-                    local_source,
+                    remote_source,
                     remote_destination,
+                    *recursive,
+                    *parallel,
+                )
+                .await?;
+                if reports.is_empty() {
+                    return Ok(CommandOutput::Message(String::from(
+                        "There was nothing to copy.",
+                    )));
+                }
+                Ok(CommandOutput::Transfers(reports))
+            }
+            Command::ChangeStorageClass {
+                storage_class,
+                target,
+                recursive,
+            } => {
+                let changed = commands::change_storage_class(
+                    self.backend().as_ref(),
+                    &self.remote_cwd,
+                    storage_class,
+                    target,
+                    *recursive,
+                )
+                .await?;
+                let lines = changed
+                    .into_iter()
+                    .map(|path| format!("Moved to {}: {}", storage_class, path))
+                    .collect();
+                Ok(CommandOutput::listing(lines, ""))
+            }
+            Command::ChangeContentType {
+                target,
+                content_type,
+            } => {
+                let changed = commands::change_content_type(
+                    self.backend().as_ref(),
+                    &self.remote_cwd,
+                    content_type,
+                    target,
+                )
+                .await?;
+                let lines = changed
+                    .into_iter()
+                    .map(|path| format!("Content-Type set to {}: {}", content_type, path))
+                    .collect();
+                Ok(CommandOutput::listing(lines, ""))
+            }
+            Command::RestoreObject {
+                target,
+                days,
+                tier,
+                wait,
+            } => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                self.backend()
+                    .restore_object(bucket.clone(), key.clone(), *days, tier.clone())
+                    .await?;
+                if !wait {
+                    return Ok(CommandOutput::Message(format!(
+                        "Restore started for {}. Depending on its storage class, it can take minutes to hours.",
+                        target
+                    )));
+                }
+                let expiry =
+                    commands::wait_for_restore(self.backend().as_ref(), bucket, key).await?;
+                Ok(CommandOutput::Message(match expiry {
+                    Some(expiry) => format!(
+                        "{} is restored and can be downloaded until {}.",
+                        target,
+                        format_timestamp(expiry)
+                    ),
+                    None => format!("{} can be downloaded.", target),
+                }))
+            }
+            Command::PrintRestoreStatus(target) => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let head = self
+                    .backend()
+                    .head_object(bucket, key, &GetOptions::default())
+                    .await?
+                    .ok_or_else(|| RBError::new(ErrorKind::NotFound))?;
+                let (status, message) = match head.restore {
+                    Some(RestoreStatus::InProgress) => (
+                        "in-progress",
+                        format!("{} is still being restored.", target),
+                    ),
+                    Some(RestoreStatus::Ready {
+                        expiry: Some(expiry),
+                    }) => (
+                        "ready",
+                        format!(
+                            "{} is restored and can be downloaded until {}.",
+                            target,
+                            format_timestamp(expiry)
+                        ),
+                    ),
+                    Some(RestoreStatus::Ready { expiry: None }) => (
+                        "ready",
+                        format!("{} is restored and can be downloaded.", target),
+                    ),
+                    None => (
+                        "none",
+                        format!(
+                            "{} isn't being restored. Either it isn't archived, or no restore has been asked for (or the last one expired).",
+                            target
+                        ),
+                    ),
+                };
+                Ok(CommandOutput::value("restore", status, message))
+            }
+            Command::PrintObjectUrl(target) => {
+                // Local files don't have URLs that anyone else could use
+                if self.local_backend.is_some() {
+                    return Err(RBError::new(ErrorKind::InvalidTarget));
+                }
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                Ok(CommandOutput::listing(
+                    self.s3.object_urls(&bucket, &key),
+                    "",
+                ))
+            }
+            Command::RemoveObjects { targets, recursive } => {
+                let removed = commands::remove_objects(
+                    self.backend().as_ref(),
+                    &self.remote_cwd,
+                    targets,
+                    *recursive,
+                )
+                .await?;
+                Ok(CommandOutput::Message(match removed {
+                    0 => String::from("There was nothing to remove."),
+                    1 => String::from("Removed 1 object."),
+                    count => format!("Removed {} objects.", count),
+                }))
+            }
+            Command::ListVersions(target) => {
+                let versions = commands::list_versions(
+                    self.backend().as_ref(),
+                    &self.remote_cwd,
+                    target.as_deref(),
                 )
-                .await
+                .await?;
+                let mut table = Table::new().align(1, Align::Right);
+                for version in versions {
+                    let size = if version.is_delete_marker {
+                        String::from("(deleted)")
+                    } else {
+                        version
+                            .size
+                            .map(|size| size.to_string())
+                            .unwrap_or_default()
+                    };
+                    let latest = if version.is_latest { "latest" } else { "" };
+                    table.push(vec![
+                        version
+                            .last_modified
+                            .map(format_timestamp)
+                            .unwrap_or_default(),
+                        size,
+                        String::from(latest),
+                        version.version_id,
+                        version.key,
+                    ]);
+                }
+                Ok(CommandOutput::listing(
+                    table.render(),
+                    "There are no object versions here.",
+                ))
+            }
+            Command::Undelete(target) => {
+                let marker =
+                    commands::undelete(self.backend().as_ref(), &self.remote_cwd, target).await?;
+                Ok(CommandOutput::Message(format!(
+                    "Restored {} by removing its delete marker (version {}).",
+                    marker.key, marker.version_id
+                )))
+            }
+            Command::DiskUsage { confirmed } => {
+                let usages =
+                    commands::disk_usage(self.backend().as_ref(), &self.remote_cwd, *confirmed)
+                        .await?;
+                let mut table = Table::new()
+                    .headers(&["BUCKET", "OBJECTS", "SIZE"])
+                    .align(1, Align::Right)
+                    .align(2, Align::Right);
+                let mut rows = Vec::new();
+                for (bucket, usage) in usages {
+                    table.push(vec![
+                        bucket.clone(),
+                        usage.objects.to_string(),
+                        usage.bytes.to_string(),
+                    ]);
+                    rows.push(vec![
+                        ("bucket", json!(bucket)),
+                        ("objects", json!(usage.objects)),
+                        ("bytes", json!(usage.bytes)),
+                    ]);
+                }
+                Ok(CommandOutput::rows(
+                    "bucket_usage",
+                    rows,
+                    table,
+                    "There are no buckets.",
+                ))
+            }
+            Command::ObjectUsage(target) => {
+                let target = target.as_deref().unwrap_or(".");
+                let usage =
+                    commands::object_usage(self.backend().as_ref(), &self.remote_cwd, target)
+                        .await?;
+                let mut table = Table::new()
+                    .headers(&["OBJECTS", "SIZE"])
+                    .align(0, Align::Right)
+                    .align(1, Align::Right);
+                table.push(vec![usage.objects.to_string(), usage.bytes.to_string()]);
+                let row = vec![
+                    ("objects", json!(usage.objects)),
+                    ("bytes", json!(usage.bytes)),
+                ];
+                Ok(CommandOutput::rows("usage", vec![row], table, ""))
+            }
+            Command::StatObjects { target, lock } => {
+                let heads =
+                    commands::stat_objects(self.backend().as_ref(), &self.remote_cwd, target)
+                        .await?;
+                let headers: &[&str] = if *lock {
+                    &["PATH", "SIZE", "MODIFIED", "RETENTION", "LEGAL HOLD"]
+                } else {
+                    &["PATH", "SIZE", "MODIFIED"]
+                };
+                let mut table = Table::new().headers(headers).align(1, Align::Right);
+                let mut rows = Vec::new();
+                for (path, head) in heads {
+                    let modified = head.last_modified.map(format_timestamp);
+                    let mut cells = vec![
+                        path.clone(),
+                        head.size.to_string(),
+                        modified.clone().unwrap_or_default(),
+                    ];
+                    let mut row = vec![
+                        ("path", json!(path)),
+                        ("size", json!(head.size)),
+                        ("last_modified", json!(modified)),
+                    ];
+                    if *lock {
+                        let (bucket, key) =
+                            commands::resolve_remote_object(&self.remote_cwd, &path)?;
+                        let status = self.backend().object_lock_status(bucket, key).await?;
+                        cells.push(describe_retention(&status));
+                        cells.push(describe_legal_hold(&status));
+                        row.extend(object_lock_fields(&status));
+                    }
+                    table.push(cells);
+                    rows.push(row);
+                }
+                Ok(CommandOutput::rows(
+                    "stat",
+                    rows,
+                    table,
+                    "No objects match.",
+                ))
+            }
+            Command::SelectObject {
+                target,
+                expression,
+                input,
+                output,
+            } => {
+                // The records can go straight to stdout as they come in, unless they're headed somewhere else
+                if self.output_redirected {
+                    let mut records = Vec::new();
+                    commands::select_object(
+                        self.backend().as_ref(),
+                        &self.remote_cwd,
+                        target,
+                        expression,
+                        *input,
+                        *output,
+                        &mut records,
+                    )
+                    .await?;
+                    Ok(CommandOutput::Records(
+                        String::from_utf8_lossy(&records).into_owned(),
+                    ))
+                } else {
+                    commands::select_object(
+                        self.backend().as_ref(),
+                        &self.remote_cwd,
+                        target,
+                        expression,
+                        *input,
+                        *output,
+                        &mut tokio::io::stdout(),
+                    )
+                    .await?;
+                    Ok(CommandOutput::Empty)
+                }
+            }
+            Command::PrintObjectAcl(target) => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let grants = self.backend().object_grants(bucket, key).await?;
+                if grants.iter().any(|grant| grant.is_public()) {
+                    status!(
+                        "Warning: {} can be read or changed by people outside the account.",
+                        target
+                    );
+                }
+                // Scripts reading JSON or porcelain output want just the grants
+                let mut table = match output::format() {
+                    OutputFormat::Human if !grants.is_empty() => {
+                        Table::new().headers(&["GRANTEE", "TYPE", "PERMISSION"])
+                    }
+                    _ => Table::new(),
+                };
+                for grant in grants {
+                    table.push(vec![grant.grantee, grant.grantee_type, grant.permission]);
+                }
+                Ok(CommandOutput::listing(
+                    table.render(),
+                    "This object's ACL grants nothing to anyone.",
+                ))
+            }
+            Command::SetObjectAcl { target, acl } => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                self.backend()
+                    .set_object_acl(bucket, key, acl.clone())
+                    .await?;
+                Ok(CommandOutput::Message(format!(
+                    "Set ACL of {} to {}.",
+                    target, acl
+                )))
+            }
+            Command::PrintObjectMetadata(target) => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let head = self
+                    .backend()
+                    .head_object(bucket, key, &GetOptions::default())
+                    .await?
+                    .ok_or_else(|| RBError::new(ErrorKind::NotFound))?;
+                let mut metadata: Vec<_> = head.metadata.into_iter().collect();
+                metadata.sort();
+                // Scripts reading JSON or porcelain output want just the metadata
+                let mut table = match output::format() {
+                    OutputFormat::Human if !metadata.is_empty() => {
+                        Table::new().headers(&["NAME", "VALUE"])
+                    }
+                    _ => Table::new(),
+                };
+                for (name, value) in metadata {
+                    table.push(vec![name, value]);
+                }
+                Ok(CommandOutput::listing(
+                    table.render(),
+                    "This object has no user metadata.",
+                ))
+            }
+            Command::SetObjectMetadata { target, metadata } => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let backend = self.backend();
+                let head = backend
+                    .head_object(bucket.clone(), key.clone(), &GetOptions::default())
+                    .await?
+                    .ok_or_else(|| RBError::new(ErrorKind::NotFound))?;
+                let mut all_metadata = head.metadata;
+                all_metadata.extend(metadata.clone());
+                backend
+                    .set_object_metadata(bucket, key, all_metadata)
+                    .await?;
+                let mut lines: Vec<String> = metadata
+                    .iter()
+                    .map(|(name, value)| format!("Set metadata on {}: {}={}", target, name, value))
+                    .collect();
+                lines.sort();
+                Ok(CommandOutput::listing(lines, ""))
+            }
+            Command::PrintObjectTags(target) => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let tags = self.backend().object_tags(bucket, key).await?;
+                // Scripts reading JSON or porcelain output want just the tags
+                let mut table = match output::format() {
+                    OutputFormat::Human if !tags.is_empty() => {
+                        Table::new().headers(&["NAME", "VALUE"])
+                    }
+                    _ => Table::new(),
+                };
+                for (name, value) in tags {
+                    table.push(vec![name, value]);
+                }
+                Ok(CommandOutput::listing(
+                    table.render(),
+                    "This object has no tags.",
+                ))
+            }
+            Command::SetObjectTags { target, tags } => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let backend = self.backend();
+                let mut all_tags = backend.object_tags(bucket.clone(), key.clone()).await?;
+                all_tags.extend(tags.clone());
+                backend.set_object_tags(bucket, key, all_tags).await?;
+                let lines = tags
+                    .iter()
+                    .map(|(name, value)| format!("Tagged {}: {}={}", target, name, value))
+                    .collect();
+                Ok(CommandOutput::listing(lines, ""))
+            }
+            Command::RemoveObjectTags { target, names } => {
+                let (bucket, key) = commands::resolve_remote_object(&self.remote_cwd, target)?;
+                let backend = self.backend();
+                let mut all_tags = backend.object_tags(bucket.clone(), key.clone()).await?;
+                let removed: Vec<String> = if names.is_empty() {
+                    all_tags.keys().cloned().collect()
+                } else {
+                    names
+                        .iter()
+                        .filter(|name| all_tags.contains_key(*name))
+                        .cloned()
+                        .collect()
+                };
+                if removed.is_empty() {
+                    return Ok(CommandOutput::Message(format!(
+                        "{} has no such tags; nothing to remove.",
+                        target
+                    )));
+                }
+                all_tags.retain(|name, _| !removed.contains(name));
+                backend.set_object_tags(bucket, key, all_tags).await?;
+                Ok(CommandOutput::Message(format!(
+                    "Removed tags from {}: {}",
+                    target,
+                    removed.join(", ")
+                )))
+            }
+            Command::PrintCorsRules => {
+                let bucket = commands::remote_bucket(&self.remote_cwd)?;
+                let rules = self.backend().bucket_cors_rules(bucket).await?;
+                // Scripts reading JSON or porcelain output want just the rules
+                let mut table = match output::format() {
+                    OutputFormat::Human if !rules.is_empty() => Table::new()
+                        .headers(&["ORIGINS", "METHODS", "HEADERS", "EXPOSE", "MAX AGE"]),
+                    _ => Table::new(),
+                };
+                for rule in rules {
+                    table.push(vec![
+                        rule.allowed_origins.join(","),
+                        rule.allowed_methods.join(","),
+                        rule.allowed_headers.join(","),
+                        rule.expose_headers.join(","),
+                        rule.max_age_seconds
+                            .map(|seconds| format!("{}s", seconds))
+                            .unwrap_or_default(),
+                    ]);
+                }
+                Ok(CommandOutput::listing(
+                    table.render(),
+                    "This bucket has no CORS rules.",
+                ))
+            }
+            Command::PrintEncryption => {
+                let bucket = commands::remote_bucket(&self.remote_cwd)?;
+                let encryption = match self.backend().bucket_encryption(bucket.clone()).await? {
+                    Some(encryption) => encryption,
+                    None => {
+                        return Ok(CommandOutput::Message(format!(
+                            "Bucket {} has no default encryption.",
+                            bucket
+                        )))
+                    }
+                };
+                let mut table = Table::new();
+                let uses_kms = encryption.algorithm.starts_with("aws:kms");
+                let description = match encryption.algorithm.as_str() {
+                    "AES256" => String::from("SSE-S3 (AES256)"),
+                    "aws:kms" => String::from("SSE-KMS (aws:kms)"),
+                    "aws:kms:dsse" => String::from("DSSE-KMS (aws:kms:dsse)"),
+                    other => String::from(other),
+                };
+                table.push(vec![String::from("Encryption:"), description]);
+                if uses_kms {
+                    table.push(vec![
+                        String::from("KMS key:"),
+                        encryption
+                            .kms_key_id
+                            .unwrap_or_else(|| String::from("AWS managed key (aws/s3)")),
+                    ]);
+                    let bucket_key = if encryption.bucket_key_enabled {
+                        "on"
+                    } else {
+                        "off"
+                    };
+                    table.push(vec![String::from("Bucket key:"), String::from(bucket_key)]);
+                }
+                Ok(CommandOutput::listing(table.render(), ""))
+            }
+            Command::PrintVersioning => {
+                let bucket = commands::remote_bucket(&self.remote_cwd)?;
+                let status = self.backend().bucket_versioning(bucket.clone()).await?;
+                let status =
+                    status.map_or(String::from("disabled"), |status| status.to_lowercase());
+                Ok(CommandOutput::value(
+                    "versioning",
+                    &status,
+                    format!("Versioning for bucket {} is {}.", bucket, status),
+                ))
+            }
+            Command::SetVersioning { enabled, confirmed } => {
+                let bucket = commands::remote_bucket(&self.remote_cwd)?;
+                if !confirmed {
+                    let change = if *enabled {
+                        format!(
+                            "This will turn on versioning for bucket {}. After that, it can be suspended but never turned off.",
+                            bucket
+                        )
+                    } else {
+                        format!(
+                            "This will suspend versioning for bucket {}. Old versions are kept, but overwriting or deleting an object won't keep another.",
+                            bucket
+                        )
+                    };
+                    return Err(RBError::wrap(ErrorKind::Unconfirmed, change));
+                }
+                self.backend()
+                    .set_bucket_versioning(bucket.clone(), *enabled)
+                    .await?;
+                let state = if *enabled { "enabled" } else { "suspended" };
+                Ok(CommandOutput::Message(format!(
+                    "Versioning {} for bucket {}.",
+                    state, bucket
+                )))
+            }
+            Command::AbortIncompleteUploads { key, confirmed } => {
+                if !confirmed {
+                    return Err(RBError::wrap(
+                        ErrorKind::Unconfirmed,
+                        format!(
+                            "This will abort every incomplete upload under {}, and delete the parts uploaded so far.",
+                            self.remote_cwd.display()
+                        ),
+                    ));
+                }
+                let aborted = commands::abort_incomplete_uploads(
+                    self.backend().as_ref(),
+                    &self.remote_cwd,
+                    key.as_deref(),
+                )
+                .await?;
+                Ok(CommandOutput::Message(match aborted.len() {
+                    0 => String::from("There were no incomplete uploads to abort."),
+                    1 => String::from("Aborted 1 incomplete upload."),
+                    count => format!("Aborted {} incomplete uploads.", count),
+                }))
             }
         }
     }
 }
 
+static CANCELLED_WARNING: &str = "Cancelled.";
 static INVALID_COMMAND_WARNING: &str = "Unknown command. For available commands,";
 static INVALID_TARGET_WARNING: &str = "Invalid argument(s) for this command";
 static TARGET_EXISTS_WARNING: &str = "The specified file already exists, doing nothing";
+static TARGET_EXISTS_PROMPT: &str = "The specified file already exists. Overwrite? [y/N/a(ll)] ";
+static UNCONFIRMED_WARNING: &str = "Add --yes to go ahead without being asked.";
+static CONFIRM_PROMPT: &str = "Go ahead? [y/N] ";
+
+// Like "GOVERNANCE until 2030-01-01T00:00:00Z", or "none"
+fn describe_retention(status: &ObjectLockStatus) -> String {
+    match (&status.retention_mode, status.retain_until) {
+        (Some(mode), Some(retain_until)) => {
+            format!("{} until {}", mode, format_timestamp(retain_until))
+        }
+        (Some(mode), None) => mode.clone(),
+        (None, _) => String::from("none"),
+    }
+}
+
+fn describe_legal_hold(status: &ObjectLockStatus) -> String {
+    String::from(if status.legal_hold { "on" } else { "off" })
+}
+
+// The fields that `lock` and `stat --lock` give scripts
+fn object_lock_fields(status: &ObjectLockStatus) -> Row {
+    vec![
+        ("retention_mode", json!(status.retention_mode)),
+        (
+            "retain_until",
+            json!(status.retain_until.map(format_timestamp)),
+        ),
+        ("legal_hold", json!(status.legal_hold)),
+    ]
+}
+
+enum OverwriteAnswer {
+    No,
+    Yes,
+    Always,
+}
+
+async fn ask_overwrite<R: LineReader>(reader: &mut R) -> OverwriteAnswer {
+    match reader.read_answer(TARGET_EXISTS_PROMPT).await {
+        Some(answer) => match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => OverwriteAnswer::Yes,
+            "a" | "all" => OverwriteAnswer::Always,
+            _ => OverwriteAnswer::No,
+        },
+        None => OverwriteAnswer::No,
+    }
+}
+
+// Asks whether to go ahead with the change that an `ErrorKind::Unconfirmed` error describes
+async fn ask_confirmation<R: LineReader>(reader: &mut R, change: &str) -> bool {
+    match reader
+        .read_answer(&format!("{} {}", change, CONFIRM_PROMPT))
+        .await
+    {
+        Some(answer) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        None => false,
+    }
+}
+
+// Unknown commands come with a more specific message when we have a guess at what was meant
+fn invalid_command_message(e: &RBError, help_hint: &str) -> String {
+    match e.source() {
+        Some(suggestion) => suggestion.to_string(),
+        None => format!("{} {}", INVALID_COMMAND_WARNING, help_hint),
+    }
+}
+
+// Turns an error into the message we show for it when we aren't going to handle it any more cleverly than that
+fn describe_error(e: &RBError) -> String {
+    let message = match e.kind() {
+        ErrorKind::Cancelled => String::from(CANCELLED_WARNING),
+        ErrorKind::InvalidCommand => invalid_command_message(e, "run with --help"),
+        ErrorKind::InvalidTarget => String::from(INVALID_TARGET_WARNING),
+        ErrorKind::TargetAlreadyExists => String::from(TARGET_EXISTS_WARNING),
+        ErrorKind::Unconfirmed => format!("{} {}", e, UNCONFIRMED_WARNING),
+        // RBError's own Display already says what was going on and explains S3's error codes
+        _ => return e.to_string(),
+    };
+    match e.context() {
+        Some(context) => format!("{} (while {})", message, context),
+        None => message,
+    }
+}
+
+// Runs one command with nobody around to answer prompts, printing its output if it has any
+async fn run_batch_command(runner: &mut Runner, cmd_str: &str) -> Result<(), RBError> {
+    let (cmd, redirect) =
+        parse_session_command(cmd_str.to_owned(), &runner.last_listing, &runner.variables)?;
+    let output = runner
+        .run_redirectable_command(&cmd, redirect.as_ref())
+        .await?
+        .render_text();
+    match redirect {
+        Some(redirect) => runner.write_redirected(&output, &redirect).await,
+        None => {
+            let mut stdout = io::stdout();
+            write!(stdout, "{}", output)
+                .and_then(|_| stdout.flush())
+                .map_err(RBError::wrap_io)
+        }
+    }
+}
+
+// Runs the commands given with -c, in order, within one session. Each -c may hold several commands separated by `;`.
+async fn run_command_args(
+    mut runner: Runner,
+    commands: &[String],
+    keep_going: bool,
+) -> Result<(), RBError> {
+    let mut first_error = None;
+    let cmd_strs = commands
+        .iter()
+        .flat_map(|cmds| tokenize::split_commands(cmds))
+        .map(str::trim)
+        .filter(|cmd_str| !cmd_str.is_empty());
+
+    for cmd_str in cmd_strs {
+        match run_batch_command(&mut runner, cmd_str).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UserExit => break,
+            Err(e) => {
+                print_error(
+                    &e,
+                    &format!("Error running '{}': {}", cmd_str, describe_error(&e)),
+                );
+                if !keep_going {
+                    return Err(e);
+                }
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    first_error.map_or(Ok(()), Err)
+}
+
+// Runs each line of a script (a file, or whatever was piped to stdin) as a command, skipping blank lines and `#`
+// comments. Unless keep_going is set, the first failing command stops the script; otherwise we run everything and then
+// report the first failure.
+async fn run_script<R>(mut runner: Runner, script: R, keep_going: bool) -> Result<(), RBError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = script.lines();
+    let mut line_number = 0;
+    let mut first_error = None;
+
+    while let Some(mut line) = lines.next_line().await.map_err(RBError::wrap_io)? {
+        line_number += 1;
+        // Errors are reported against the line that the command started on
+        let start_line_number = line_number;
+        while tokenize::is_incomplete(&line) {
+            match lines.next_line().await.map_err(RBError::wrap_io)? {
+                Some(next_line) => {
+                    line_number += 1;
+                    line.push('\n');
+                    line.push_str(&next_line);
+                }
+                None => break,
+            }
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        match run_batch_command(&mut runner, trimmed).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UserExit => break,
+            Err(e) => {
+                print_error(
+                    &e,
+                    &format!(
+                        "Error on line {} ({}): {}",
+                        start_line_number,
+                        trimmed,
+                        describe_error(&e)
+                    ),
+                );
+                if !keep_going {
+                    return Err(e);
+                }
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    first_error.map_or(Ok(()), Err)
+}
+
+const DEFAULT_PROMPT: &str = "[{path}] > ";
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+// Where the interactive prompt's history is kept between sessions: the platform's per-user data directory, e.g.
+// ~/.local/share on Linux or AppData\Roaming on Windows
+fn history_path() -> Option<PathBuf> {
+    dirs_next::data_dir().map(|data_dir| data_dir.join("rustbucket").join("history"))
+}
 
-async fn run_loop(rl: &mut rustyline::Editor<()>, mut runner: Runner) -> Result<(), RBError> {
+// The interactive prompt: reads commands from `reader` and writes their output to `output` until the input runs out
+// or the user exits. `after_command` gets a chance to catch the reader up with the session after each command.
+async fn run_loop<R, W, F>(
+    reader: &mut R,
+    output: &mut W,
+    runner: &mut Runner,
+    prompt_template: &str,
+    mut after_command: F,
+) -> Result<(), RBError>
+where
+    R: LineReader,
+    W: Write,
+    F: FnMut(&mut R, &Runner),
+{
+    // Once the user answers "all" to an overwrite prompt, we stop asking for the rest of the session
+    let mut always_overwrite = false;
     loop {
-        match rl.readline("> ") {
-            Err(ReadlineError::Interrupted) => break,
-            Err(ReadlineError::Eof) => break,
-            Err(e) => return Err(RBError::wrap_io(e)),
-            Ok(line) => {
-                let cmd_res = parse_command(line);
-                if let Err(e) = cmd_res {
-                    match e.kind() {
+        for finished_job in runner.jobs.newly_finished().await {
+            writeln!(output, "{}", finished_job).map_err(RBError::wrap_io)?;
+        }
+        // Tab completion goes by the listing cache too
+        runner.invalidate_background_changes();
+        let prompt = runner.render_prompt(prompt_template);
+        let line = match reader.read_command(&prompt).await? {
+            Some(line) => line,
+            None => break,
+        };
+
+        let (cmd, redirect) =
+            match parse_session_command(line, &runner.last_listing, &runner.variables) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    let message = match e.kind() {
                         ErrorKind::UserExit => break,
-                        ErrorKind::InvalidCommand => {
-                            println!("{} type \"help\"", INVALID_COMMAND_WARNING);
-                            continue;
-                        }
-                        ErrorKind::InvalidTarget => {
-                            println!("{}", INVALID_TARGET_WARNING);
-                            continue;
-                        }
-                        ErrorKind::TargetAlreadyExists => {
-                            println!("{}", TARGET_EXISTS_WARNING);
-                            continue;
-                        }
-                        _ => return Err(e),
+                        ErrorKind::InvalidCommand => invalid_command_message(&e, "type \"help\""),
+                        ErrorKind::InvalidTarget => String::from(INVALID_TARGET_WARNING),
+                        ErrorKind::TargetAlreadyExists => String::from(TARGET_EXISTS_WARNING),
+                        _ => describe_error(&e),
                     };
+                    writeln!(output, "{}", message).map_err(RBError::wrap_io)?;
+                    continue;
+                }
+            };
+
+        let cmd = if always_overwrite { cmd.forced() } else { cmd };
+        let mut result = runner
+            .run_redirectable_command(&cmd, redirect.as_ref())
+            .await;
+        if matches!(&result, Err(e) if e.kind() == ErrorKind::TargetAlreadyExists) {
+            match ask_overwrite(reader).await {
+                OverwriteAnswer::No => {}
+                OverwriteAnswer::Yes => {
+                    result = runner
+                        .run_redirectable_command(&cmd.forced(), redirect.as_ref())
+                        .await
+                }
+                OverwriteAnswer::Always => {
+                    always_overwrite = true;
+                    result = runner
+                        .run_redirectable_command(&cmd.forced(), redirect.as_ref())
+                        .await;
                 }
+            }
+        }
+        let unconfirmed_change = match &result {
+            Err(e) if e.kind() == ErrorKind::Unconfirmed => Some(e.to_string()),
+            _ => None,
+        };
+        if let Some(change) = unconfirmed_change {
+            if ask_confirmation(reader, &change).await {
+                result = runner
+                    .run_redirectable_command(&cmd.confirmed(), redirect.as_ref())
+                    .await;
+            }
+        }
 
-                let cmd = cmd_res.unwrap();
-                match runner.run_command(&cmd).await {
-                    Ok(s) => println!("{}", s),
-                    Err(e) => match e.kind() {
-                        // TODO: Add better UX for "gracefully" handling S3 and IO error types
-                        ErrorKind::InvalidTarget => println!("{}", INVALID_TARGET_WARNING),
-                        ErrorKind::TargetAlreadyExists => println!("{}", TARGET_EXISTS_WARNING),
-                        _ => return Err(e),
+        let message = match result {
+            Ok(command_output) => {
+                let text = command_output.render_text();
+                match &redirect {
+                    Some(redirect) => match runner.write_redirected(&text, redirect).await {
+                        Ok(()) => None,
+                        Err(e) => Some(format!("Couldn't redirect the output: {}", e)),
                     },
-                };
+                    None => {
+                        write!(output, "{}", text).map_err(RBError::wrap_io)?;
+                        None
+                    }
+                }
             }
+            Err(e) => match e.kind() {
+                ErrorKind::UserExit => break,
+                // Start a fresh line in case we interrupted a progress display
+                ErrorKind::Cancelled => Some(format!("\n{}", CANCELLED_WARNING)),
+                ErrorKind::InvalidTarget => Some(String::from(INVALID_TARGET_WARNING)),
+                ErrorKind::TargetAlreadyExists => Some(String::from(TARGET_EXISTS_WARNING)),
+                // The user was asked, and said no
+                ErrorKind::Unconfirmed => Some(String::from(CANCELLED_WARNING)),
+                // Whatever went wrong only went wrong for this one command, so it's no reason to end the whole session.
+                // The session only ends if we can't read the next line or write to the output.
+                _ => Some(describe_error(&e)),
+            },
         };
+        if let Some(message) = message {
+            writeln!(output, "{}", message).map_err(RBError::wrap_io)?;
+        }
+
+        after_command(reader, runner);
     }
     Ok(())
 }
 
+/// A rustbucket session for use as a library. It takes commands as the same text a user would type at the prompt, and
+/// keeps track of the working directories (and everything else the prompt would remember) between them.
+pub struct Session {
+    runner: Runner,
+    observer: Option<Arc<dyn TransferObserver>>,
+}
+
+impl Session {
+    /// Starts a session in `remote_dir` (e.g. `/my-bucket/some/prefix`, or `/`) and `local_dir`, with the default retry
+    /// policy. Unlike the binary, this doesn't read the config file, set up logging, or look for credentials beyond
+    /// what `client_config` says to use. Note that `lcd` changes the whole process's working directory.
+    pub fn new(
+        client_config: ClientConfig,
+        remote_dir: &str,
+        local_dir: &Path,
+    ) -> Result<Session, RBError> {
+        Session::with_client(RBS3::new(client_config)?, remote_dir, local_dir)
+    }
+
+    /// Like `new`, but with a client that's already been set up, e.g. with `RBS3::builder()`
+    pub fn with_client(s3: RBS3, remote_dir: &str, local_dir: &Path) -> Result<Session, RBError> {
+        let retry_policy = RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_RETRY_BASE_DELAY,
+        };
+        let remote_cwd = commands::resolve_remote_path(Path::new("/"), remote_dir);
+        let local_cwd = local_dir.canonicalize().map_err(RBError::wrap_io)?;
+        Ok(Session {
+            runner: Runner::new(local_cwd, remote_cwd, s3, retry_policy),
+            observer: None,
+        })
+    }
+
+    /// Has `observer` told about the progress of every transfer from now on, including ones in background jobs
+    pub fn set_observer(&mut self, observer: Arc<dyn TransferObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Runs one command line. There's nobody to answer prompts, so a transfer whose destination already exists fails
+    /// with `ErrorKind::TargetAlreadyExists` unless it was given `-f` or another policy, and `exit` returns
+    /// `ErrorKind::UserExit`. If the command's output was redirected to a file or pipeline, this returns
+    /// `CommandOutput::Empty`.
+    pub async fn execute(&mut self, input: &str) -> Result<CommandOutput, RBError> {
+        self.execute_until(input, future::pending::<()>()).await
+    }
+
+    /// Like `execute`, but gives up on the command with `ErrorKind::Cancelled` if `cancel` finishes first, e.g. a
+    /// `CancellationToken::cancelled()` or a timeout. The command's future is dropped, so a partial download is removed
+    /// just as it would be after Ctrl-C at the prompt.
+    pub async fn execute_until<F: Future>(
+        &mut self,
+        input: &str,
+        cancel: F,
+    ) -> Result<CommandOutput, RBError> {
+        let (cmd, redirect) = parse_session_command(
+            input.to_owned(),
+            &self.runner.last_listing,
+            &self.runner.variables,
+        )?;
+        self.runner.output_redirected = redirect.is_some();
+        let run = async {
+            match self.observer.clone() {
+                Some(observer) => observe_transfers(observer, self.runner.run_command(&cmd)).await,
+                None => self.runner.run_command(&cmd).await,
+            }
+        };
+        let output = tokio::select! {
+            result = run => result?,
+            _ = cancel => return Err(RBError::new(ErrorKind::Cancelled)),
+        };
+        match redirect {
+            Some(redirect) => {
+                self.runner
+                    .write_redirected(&output.render_text(), &redirect)
+                    .await?;
+                Ok(CommandOutput::Empty)
+            }
+            None => Ok(output),
+        }
+    }
+
+    /// Runs an interactive session, just like the binary's prompt, but reading commands from `reader` and writing
+    /// their output to `output`, until the input runs out or `exit` is entered. A command that fails has its error
+    /// written to `output` like any other result; this only returns an error if reading or writing fails. Progress,
+    /// warnings, and other chatter still go to stderr.
+    pub async fn run_interactive<R, W>(
+        &mut self,
+        reader: &mut R,
+        output: &mut W,
+    ) -> Result<(), RBError>
+    where
+        R: LineReader,
+        W: Write,
+    {
+        let run = run_loop(reader, output, &mut self.runner, DEFAULT_PROMPT, |_, _| {});
+        match self.observer.clone() {
+            Some(observer) => observe_transfers(observer, run).await,
+            None => run.await,
+        }
+    }
+
+    pub fn remote_dir(&self) -> &Path {
+        &self.runner.remote_cwd
+    }
+
+    pub fn local_dir(&self) -> &Path {
+        &self.runner.local_cwd
+    }
+}
+
 pub async fn run(config: Config) -> Result<(), RBError> {
+    output::set_quiet(config.quiet);
+    let file_config = config_file::load(config.config_file.as_deref())?;
+    output::init_logging(config.verbosity);
+    output::set_format(if config.json {
+        OutputFormat::Json
+    } else if config.porcelain {
+        OutputFormat::Porcelain
+    } else {
+        OutputFormat::Human
+    });
+
+    let region = match config.region {
+        Some(region_name) => parse_region(&region_name)?,
+        None => Region::default(),
+    };
+    let local_backend = match config.backend.as_deref() {
+        None | Some("s3") => None,
+        Some(spec) => match spec.strip_prefix("local:") {
+            Some(root) => Some(LocalBackend::new(commands::expand_local_path(root))?),
+            None => {
+                return Err(RBError::wrap_config(format!(
+                    "Unknown backend '{}': expected s3 or local:PATH",
+                    spec
+                )))
+            }
+        },
+    };
+    // Anonymous requests don't need credentials, so don't go prompting for an MFA code. Neither does a local backend.
+    let credentials = if config.no_sign_request || local_backend.is_some() {
+        None
+    } else {
+        credentials::session_credentials(&region)?
+    };
+    let client_config = ClientConfig {
+        region,
+        force_path_style: config.force_path_style,
+        no_sign_request: config.no_sign_request,
+        credentials,
+        connect_timeout: config.connect_timeout,
+        read_timeout: config.read_timeout,
+        no_verify_ssl: config.no_verify_ssl,
+        use_fips_endpoint: config.use_fips_endpoint || credentials::configured_use_fips_endpoint()?,
+    };
+    let resumed_session = if config.resume || file_config.resume.unwrap_or(false) {
+        SessionState::load()?
+    } else {
+        None
+    };
+    let local_cwd = match config.local_dir {
+        Some(local_dir) => {
+            // Shells only expand a leading ~ in some positions (not in `--local-dir=~/foo`, for instance)
+            let local_cwd = commands::expand_local_path(&local_dir.to_string_lossy())
+                .canonicalize()
+                .map_err(RBError::wrap_io)?;
+            set_current_dir(&local_cwd).map_err(RBError::wrap_io)?;
+            local_cwd
+        }
+        // The saved local directory may have gone away since then, in which case we just start where we are
+        None => match resumed_session
+            .as_ref()
+            .filter(|session| set_current_dir(&session.local_cwd).is_ok())
+        {
+            Some(session) => session.local_cwd.clone(),
+            None => current_dir().unwrap_or(PathBuf::from("~")),
+        },
+    };
+    let remote_cwd = match (config.remote_dir, &resumed_session) {
+        (Some(remote_dir), _) => commands::resolve_remote_path(Path::new("/"), &remote_dir),
+        (None, Some(session)) => session.remote_cwd.clone(),
+        (None, None) => PathBuf::from("/"),
+    };
+    if config.no_verify_ssl {
+        eprintln!(
+            "WARNING: TLS certificate verification is disabled. Connections to S3 are NOT secure!"
+        );
+    }
+    let retry_policy = RetryPolicy {
+        max_attempts: match config.max_attempts {
+            Some(max_attempts) => max_attempts,
+            None => credentials::configured_max_attempts()?.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+        }
+        .max(1),
+        base_delay: config.retry_base_delay.unwrap_or(DEFAULT_RETRY_BASE_DELAY),
+    };
     let mut runner = Runner::new(
-        current_dir().unwrap_or(PathBuf::from("~")),
-        PathBuf::from("/"),
+        local_cwd,
+        remote_cwd,
+        RBS3::new(client_config)?,
+        retry_policy,
     );
+    runner.local_backend = local_backend;
+    runner.print_listing_pages = true;
+    if let Some(session) = resumed_session {
+        runner.bucket_dirs = session.bucket_dirs;
+    }
+    runner.notify_after = match (config.notify_after, file_config.notify_after) {
+        (Some(notify_after), _) => Some(notify_after),
+        (None, Some(seconds)) if seconds > 0.0 && seconds.is_finite() => {
+            Some(Duration::from_secs_f64(seconds))
+        }
+        (None, Some(_)) => {
+            return Err(RBError::wrap_config(
+                "notify_after must be a positive number of seconds",
+            ))
+        }
+        (None, None) => None,
+    };
+    runner.time_style = file_config.time_style.unwrap_or_default();
 
-    // Single command passed with flag
-    if let Some(cmd_input) = config.single_command {
-        return match parse_command(cmd_input) {
-            Err(e) => match e.kind() {
-                ErrorKind::UserExit => Ok(()),
-                ErrorKind::InvalidCommand => {
-                    eprintln!("{} run with --help", INVALID_COMMAND_WARNING);
-                    Err(e)
-                }
-                ErrorKind::InvalidTarget => {
-                    eprintln!("{}", INVALID_TARGET_WARNING);
-                    Err(e)
-                }
-                ErrorKind::TargetAlreadyExists => {
-                    eprintln!("{}", TARGET_EXISTS_WARNING);
-                    Err(e)
-                }
-                _ => Err(e),
-            },
-            Ok(cmd) => {
-                // It's cool if this one has no error handling besides, "exit with the error," since it's running as a
-                // one-off command anyway
-                println!("{}", runner.run_command(&cmd).await?);
-                Ok(())
-            }
-        };
+    // Commands passed with -c
+    if !config.commands.is_empty() {
+        return run_command_args(runner, &config.commands, config.keep_going).await;
+    }
+
+    // Commands read from a script file
+    if let Some(script_path) = config.script_file {
+        let script_file = File::open(&script_path).await.map_err(RBError::wrap_io)?;
+        return run_script(runner, BufReader::new(script_file), config.keep_going).await;
+    }
+
+    // Commands piped in on stdin, e.g. `echo -e "cd bucket\nls" | rustbucket`. There's no one to answer prompts, so
+    // this works just like a script file.
+    if !std::io::stdin().is_terminal() {
+        return run_script(
+            runner,
+            BufReader::new(tokio::io::stdin()),
+            config.keep_going,
+        )
+        .await;
     }
 
     // Interactive prompt mode
-    let mut rl = rustyline::Editor::<()>::new();
-    // if let Err(e) = rl.load_history(&history_path) {
-    //     println!("No previous history. (error: {})", e);
-    // }
-    let result = run_loop(&mut rl, runner).await;
-    // if let Err(e) = rl.save_history(&history_path) {
-    //     eprintln!("Error trying to save interactive prompt history: {}", e);
-    // }
+    let history_size = config
+        .history_size
+        .or(file_config.history_size)
+        .unwrap_or(DEFAULT_HISTORY_SIZE);
+    let edit_mode = match file_config.edit_mode {
+        Some(EditModeSetting::Vi) => EditMode::Vi,
+        Some(EditModeSetting::Emacs) | None => EditMode::Emacs,
+    };
+    let editor_config = rustyline::Config::builder()
+        .max_history_size(history_size)
+        .auto_add_history(false)
+        .edit_mode(edit_mode)
+        .build();
+    let mut rl = rustyline::Editor::<RBHelper>::with_config(editor_config);
+    // Both modes already have the usual word movement and kill ring (Ctrl-W/Alt-D to kill, Ctrl-Y/Alt-Y to yank) built
+    // in; these add the Ctrl+arrow word movement that most terminals' line editing has too
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Left, Modifiers::CTRL),
+        Cmd::Move(Movement::BackwardWord(1, Word::Emacs)),
+    );
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Right, Modifiers::CTRL),
+        Cmd::Move(Movement::ForwardWord(1, At::AfterEnd, Word::Emacs)),
+    );
+    rl.set_helper(Some(RBHelper::new(
+        runner.backend(),
+        runner.remote_cwd.clone(),
+        runner.local_cwd.clone(),
+    )));
+
+    // A history size of 0 means the user doesn't want any history kept at all
+    let history_path = history_path().filter(|_| history_size > 0);
+    if let Some(path) = &history_path {
+        // A missing history file just means this is the first session
+        if let Err(e) = rl.load_history(path) {
+            if !matches!(&e, ReadlineError::Io(io_err) if io_err.kind() == io::ErrorKind::NotFound)
+            {
+                eprintln!("Error trying to load interactive prompt history: {}", e);
+            }
+        }
+    }
+    let prompt_template = file_config.prompt.as_deref().unwrap_or(DEFAULT_PROMPT);
+    let mut reader = RustylineReader::new(rl, history_path);
+    let result = run_loop(
+        &mut reader,
+        &mut io::stdout(),
+        &mut runner,
+        prompt_template,
+        |reader, runner| {
+            if let Some(helper) = reader.helper_mut() {
+                helper.update(
+                    runner.backend(),
+                    &runner.remote_cwd,
+                    &runner.local_cwd,
+                    runner.listing_cache.generation(),
+                );
+            }
+        },
+    )
+    .await;
+    reader.save_history();
+    if let Err(e) = runner.session_state().save() {
+        eprintln!("Error trying to save session for --resume: {}", e);
+    }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::tests::{mock_client, select_response, MockDispatcher};
+
+    fn mock_session(dispatcher: MockDispatcher) -> Session {
+        Session::with_client(mock_client(dispatcher), "/", Path::new(".")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cd_into_a_bucket_goes_back_to_where_it_was_left() {
+        let mut session = mock_session(MockDispatcher::new(200, ""));
+        session.execute("cd /photos/2023/june").await.unwrap();
+        session.execute("cd /docs").await.unwrap();
+
+        session.execute("cd /photos").await.unwrap();
+        assert_eq!(session.remote_dir(), Path::new("/photos/2023/june"));
+        session.execute("cd /photos").await.unwrap();
+        assert_eq!(session.remote_dir(), Path::new("/photos"));
+    }
+
+    // A ListObjectsV2 response for /photos/2023, out of order like S3's can be across pages
+    const LIST_OBJECTS_RESPONSE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>photos</Name>
+  <Prefix>2023/</Prefix>
+  <KeyCount>3</KeyCount>
+  <MaxKeys>1000</MaxKeys>
+  <Delimiter>/</Delimiter>
+  <IsTruncated>false</IsTruncated>
+  <Contents>
+    <Key>2023/b.jpg</Key>
+    <LastModified>2023-06-01T12:00:00.000Z</LastModified>
+    <ETag>"abc"</ETag>
+    <Size>2048</Size>
+    <StorageClass>STANDARD</StorageClass>
+  </Contents>
+  <Contents>
+    <Key>2023/a.jpg</Key>
+    <LastModified>2023-06-02T12:00:00.000Z</LastModified>
+    <ETag>"def"</ETag>
+    <Size>1024</Size>
+    <StorageClass>GLACIER</StorageClass>
+  </Contents>
+  <CommonPrefixes>
+    <Prefix>2023/june/</Prefix>
+  </CommonPrefixes>
+</ListBucketResult>"#;
+
+    #[tokio::test]
+    async fn ls_lists_what_s3_returns() {
+        let dispatcher = MockDispatcher::new(200, LIST_OBJECTS_RESPONSE);
+        let mut session = mock_session(dispatcher.clone());
+        session.execute("cd /photos/2023").await.unwrap();
+
+        let entries = match session.execute("ls").await.unwrap() {
+            CommandOutput::Objects { entries, .. } => entries,
+            other => panic!("unexpected output {:?}", other),
+        };
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["june/", "a.jpg", "b.jpg"]);
+        assert_eq!(entries[1].size, Some(1024));
+        assert_eq!(entries[1].storage_class.as_deref(), Some("GLACIER"));
+        assert_eq!(
+            *dispatcher.requests.lock().unwrap(),
+            vec![(String::from("GET"), String::from("/photos"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn ls_at_the_prompt_prints_pages_and_caches_them() {
+        let dispatcher = MockDispatcher::new(200, LIST_OBJECTS_RESPONSE);
+        let mut session = mock_session(dispatcher.clone());
+        session.runner.print_listing_pages = true;
+        session.execute("cd /photos/2023").await.unwrap();
+
+        assert_eq!(session.execute("ls").await.unwrap(), CommandOutput::Empty);
+        assert_eq!(
+            session.runner.last_listing,
+            vec![
+                "/photos/2023/june/",
+                "/photos/2023/b.jpg",
+                "/photos/2023/a.jpg"
+            ]
+        );
+
+        // The second listing comes from the cache, sorted
+        let entries = match session.execute("ls").await.unwrap() {
+            CommandOutput::Objects { entries, .. } => entries,
+            other => panic!("unexpected output {:?}", other),
+        };
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["june/", "a.jpg", "b.jpg"]);
+        assert_eq!(dispatcher.requests.lock().unwrap().len(), 1);
+    }
+
+    // How many times S3 has been asked to list objects
+    fn list_requests(dispatcher: &MockDispatcher) -> usize {
+        dispatcher
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(method, path)| method == "GET" && path == "/photos")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn background_and_queued_uploads_invalidate_listings_when_theyre_done() {
+        let local_dir =
+            std::env::temp_dir().join(format!("rustbucket-invalidate-{}", std::process::id()));
+        std::fs::create_dir_all(&local_dir).unwrap();
+        std::fs::write(local_dir.join("c.jpg"), "new photo").unwrap();
+        let dispatcher = MockDispatcher::new(200, LIST_OBJECTS_RESPONSE);
+        let mut session =
+            Session::with_client(mock_client(dispatcher.clone()), "/photos/2023", &local_dir)
+                .unwrap();
+
+        session.execute("ls").await.unwrap();
+        session.execute("ls").await.unwrap();
+        assert_eq!(list_requests(&dispatcher), 1);
+
+        session.execute("put -f c.jpg &").await.unwrap();
+        session.execute("wait").await.unwrap();
+        session.execute("ls").await.unwrap();
+        assert_eq!(list_requests(&dispatcher), 2);
+
+        session.execute("queue add put -f c.jpg").await.unwrap();
+        session.execute("ls").await.unwrap();
+        assert_eq!(list_requests(&dispatcher), 2);
+        session.execute("queue start").await.unwrap();
+        session.execute("ls").await.unwrap();
+        assert_eq!(list_requests(&dispatcher), 3);
+        std::fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_acl_and_tag_edits_change_their_targets() {
+        for line in [
+            "meta set a.jpg camera=x100",
+            "acl set a.jpg public-read",
+            "tags set a.jpg album=june",
+            "tags rm a.jpg album",
+        ] {
+            let (cmd, _) = parse_session_command(line.to_owned(), &[], &HashMap::new()).unwrap();
+            assert_eq!(cmd.changed_remote_paths(), vec!["a.jpg"], "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_downloads_the_object_body() {
+        let local_dir = std::env::temp_dir().join(format!("rustbucket-get-{}", std::process::id()));
+        std::fs::create_dir_all(&local_dir).unwrap();
+        let dispatcher = MockDispatcher::new(200, "hello from s3");
+        let mut session =
+            Session::with_client(mock_client(dispatcher.clone()), "/photos", &local_dir).unwrap();
+
+        session.execute("get -f a.txt").await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(local_dir.join("a.txt")).unwrap(),
+            "hello from s3"
+        );
+        assert!(dispatcher
+            .requests
+            .lock()
+            .unwrap()
+            .contains(&(String::from("GET"), String::from("/photos/a.txt"))));
+        std::fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn select_output_can_be_redirected() {
+        let local_dir =
+            std::env::temp_dir().join(format!("rustbucket-select-{}", std::process::id()));
+        std::fs::create_dir_all(&local_dir).unwrap();
+        let dispatcher = MockDispatcher::new(200, select_response(&["a,1\n", "b,2\n"]));
+        let mut session =
+            Session::with_client(mock_client(dispatcher), "/data", &local_dir).unwrap();
+
+        let output = session
+            .execute(r#"select rows.csv "SELECT * FROM s3object" > out.csv"#)
+            .await
+            .unwrap();
+        assert_eq!(output.render(), "");
+        assert_eq!(
+            std::fs::read_to_string(local_dir.join("out.csv")).unwrap(),
+            "a,1\nb,2\n"
+        );
+        std::fs::remove_dir_all(&local_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stat_can_show_object_lock_status() {
+        let (cmd, _) = parse_command("stat --lock report.csv").unwrap();
+        assert!(matches!(cmd, Command::StatObjects { lock: true, .. }));
+
+        let dispatcher = MockDispatcher::new(200, "")
+            .then(200, "")
+            .then(
+                200,
+                "<Retention><Mode>GOVERNANCE</Mode>\
+                 <RetainUntilDate>2030-01-01T00:00:00Z</RetainUntilDate></Retention>",
+            )
+            .then(200, "<LegalHold><Status>ON</Status></LegalHold>");
+        let mut session = mock_session(dispatcher);
+        let output = session
+            .execute("stat --lock /data/report.csv")
+            .await
+            .unwrap();
+        match output {
+            CommandOutput::Rows { rows, table, .. } => {
+                assert_eq!(rows.len(), 1);
+                assert!(rows[0].contains(&("retention_mode", json!("GOVERNANCE"))));
+                assert!(rows[0].contains(&("retain_until", json!("2030-01-01T00:00:00Z"))));
+                assert!(rows[0].contains(&("legal_hold", json!(true))));
+                assert!(table[1].contains("GOVERNANCE until 2030-01-01T00:00:00Z"));
+            }
+            other => panic!("unexpected output {:?}", other),
+        }
+    }
+
+    // Plays back canned lines, answering every question the same way
+    struct ScriptedReader {
+        lines: Vec<&'static str>,
+        answer: &'static str,
+    }
+
+    #[async_trait::async_trait]
+    impl LineReader for ScriptedReader {
+        async fn read_command(&mut self, _prompt: &str) -> Result<Option<String>, RBError> {
+            tokio::task::yield_now().await;
+            Ok((!self.lines.is_empty()).then(|| self.lines.remove(0).to_owned()))
+        }
+
+        async fn read_answer(&mut self, _prompt: &str) -> Option<String> {
+            Some(self.answer.to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn interactive_sessions_read_lines_asynchronously() {
+        let mut session = mock_session(MockDispatcher::new(200, ""));
+        let mut reader = ScriptedReader {
+            lines: vec!["cd /photos/2023", "pwd", "frobnicate", "pwd"],
+            answer: "n",
+        };
+        let mut output = Vec::new();
+        session
+            .run_interactive(&mut reader, &mut output)
+            .await
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Remote directory is now: /photos/2023",
+                "Remote directory is now: /photos/2023",
+                "Unknown command. For available commands, type \"help\"",
+                "Remote directory is now: /photos/2023",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aborting_every_upload_needs_confirmation() {
+        let dispatcher = MockDispatcher::new(
+            200,
+            r#"<ListMultipartUploadsResult><Bucket>photos</Bucket><IsTruncated>false</IsTruncated></ListMultipartUploadsResult>"#,
+        );
+        let mut session = mock_session(dispatcher.clone());
+        session.execute("cd /photos").await.unwrap();
+
+        let e = session.execute("mpu abort --all").await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unconfirmed);
+        assert!(dispatcher.requests.lock().unwrap().is_empty());
+
+        assert_eq!(
+            session.execute("mpu abort --all --yes").await.unwrap(),
+            CommandOutput::Message(String::from("There were no incomplete uploads to abort."))
+        );
+    }
+
+    #[tokio::test]
+    async fn overwriting_everything_doesnt_confirm_versioning_changes() {
+        let dispatcher = MockDispatcher::new(200, "");
+        let mut session = mock_session(dispatcher.clone());
+        session.execute("cd /photos").await.unwrap();
+        let mut reader = ScriptedReader {
+            lines: vec!["versioning enable"],
+            answer: "n",
+        };
+        let mut output = Vec::new();
+        session
+            .run_interactive(&mut reader, &mut output)
+            .await
+            .unwrap();
+        assert!(dispatcher.requests.lock().unwrap().is_empty());
+
+        let (cmd, _) = parse_command("versioning suspend").unwrap();
+        assert!(matches!(
+            cmd.forced(),
+            Command::SetVersioning {
+                confirmed: false,
+                ..
+            }
+        ));
+        assert!(matches!(
+            cmd.confirmed(),
+            Command::SetVersioning {
+                confirmed: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn df_is_confirmed_but_not_forced() {
+        let (df, _) = parse_command("df").unwrap();
+        assert!(matches!(
+            df.forced(),
+            Command::DiskUsage { confirmed: false }
+        ));
+        assert!(matches!(
+            df.confirmed(),
+            Command::DiskUsage { confirmed: true }
+        ));
+    }
+
+    #[test]
+    fn q_still_means_quit() {
+        assert_eq!(resolve_command_word("q").unwrap(), "quit");
+        assert_eq!(resolve_command_word("qu").unwrap(), "quit");
+        assert_eq!(resolve_command_word("que").unwrap(), "queue");
+        assert_eq!(parse_command("q").unwrap_err().kind(), ErrorKind::UserExit);
+    }
+
+    #[test]
+    fn quoted_words_are_not_references_or_redirects() {
+        let variables = HashMap::new();
+        let listing = vec!["/bucket/a".to_owned(), "/bucket/b".to_owned()];
+
+        let words = tokenize::tokenize("rm %2 '%1'", &variables).unwrap();
+        let expanded = expand_listing_references(words, &listing).unwrap();
+        assert_eq!(expanded, vec!["rm", "/bucket/b", "%1"]);
+
+        let mut words = tokenize::tokenize("ls \\>x '>' y", &variables).unwrap();
+        assert!(split_redirect(&mut words).unwrap().is_none());
+        assert_eq!(words.len(), 4);
+
+        let mut words = tokenize::tokenize("ls '>x' >> out", &variables).unwrap();
+        match split_redirect(&mut words).unwrap() {
+            Some(Redirect::File { path, append }) => {
+                assert_eq!(path, "out");
+                assert!(append);
+            }
+            other => panic!("unexpected redirect {:?}", other),
+        }
+        assert_eq!(words.len(), 2);
+
+        let mut words = tokenize::tokenize("ls >'my file'", &variables).unwrap();
+        match split_redirect(&mut words).unwrap() {
+            Some(Redirect::File { path, append }) => {
+                assert_eq!(path, "my file");
+                assert!(!append);
+            }
+            other => panic!("unexpected redirect {:?}", other),
+        }
+    }
+}
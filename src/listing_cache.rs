@@ -0,0 +1,184 @@
+use crate::s3::{ObjectEntry, S3Path};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Listings go stale as soon as anything else writes to the bucket, so they're only trusted for a little while
+const LISTING_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// Keyed by bucket (None for the bucket list itself) and key prefix (None for the top of the bucket)
+type ListingKey = (Option<String>, Option<String>);
+
+/// Recent remote directory listings, so that listing the same place again soon after doesn't go back to S3. Commands
+/// that change objects invalidate the listings they'd show up in, and `refresh` throws everything away.
+#[derive(Debug, Default)]
+pub struct ListingCache {
+    listings: HashMap<ListingKey, (Instant, Vec<ObjectEntry>)>,
+    // Bumped whenever listings are thrown away, so that other caches (like tab completion's) know to follow suit
+    generation: u64,
+}
+
+impl ListingCache {
+    pub fn get(&self, s3_path: &S3Path) -> Option<Vec<ObjectEntry>> {
+        self.listings
+            .get(&listing_key(s3_path))
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < LISTING_CACHE_TTL)
+            .map(|(_, entries)| entries.clone())
+    }
+
+    pub fn insert(&mut self, s3_path: &S3Path, entries: Vec<ObjectEntry>) {
+        self.listings
+            .insert(listing_key(s3_path), (Instant::now(), entries));
+    }
+
+    /// Forgets every listing that a change at `s3_path` could show up in: the "directories" above it, and everything
+    /// under it. A path without a key stands for its whole bucket, and one without a bucket for everything.
+    pub fn invalidate(&mut self, s3_path: &S3Path) {
+        match s3_path {
+            S3Path { bucket: None, .. } => self.listings.clear(),
+            S3Path {
+                bucket: Some(bucket),
+                key,
+            } => {
+                // A glob could match anything below the part of it that comes before the first wildcard
+                let key = key
+                    .as_deref()
+                    .unwrap_or("")
+                    .split(['*', '?', '['])
+                    .next()
+                    .unwrap_or("");
+                self.listings
+                    .retain(|(cached_bucket, cached_key), _| match cached_bucket {
+                        // A change inside a bucket never changes the list of buckets
+                        None => true,
+                        Some(cached_bucket) if cached_bucket != bucket => true,
+                        Some(_) => {
+                            let cached_key = cached_key.as_deref().unwrap_or("");
+                            !key.starts_with(cached_key) && !cached_key.starts_with(key)
+                        }
+                    });
+            }
+        }
+        self.generation += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.listings.clear();
+        self.generation += 1;
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+fn listing_key(s3_path: &S3Path) -> ListingKey {
+    (s3_path.bucket.clone(), s3_path.key.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(bucket: Option<&str>, key: Option<&str>) -> S3Path {
+        S3Path {
+            bucket: bucket.map(String::from),
+            key: key.map(String::from),
+        }
+    }
+
+    fn entries(name: &str) -> Vec<ObjectEntry> {
+        vec![ObjectEntry {
+            name: String::from(name),
+            is_prefix: false,
+            size: Some(1),
+            last_modified: None,
+            etag: None,
+            storage_class: None,
+        }]
+    }
+
+    fn cached_with(paths: &[(Option<&str>, Option<&str>)]) -> ListingCache {
+        let mut cache = ListingCache::default();
+        for &(bucket, key) in paths {
+            cache.insert(&path(bucket, key), entries("a"));
+        }
+        cache
+    }
+
+    #[test]
+    fn listings_are_cached_until_they_expire() {
+        let mut cache = cached_with(&[(Some("bucket"), Some("dir/"))]);
+        assert_eq!(
+            cache.get(&path(Some("bucket"), Some("dir/"))),
+            Some(entries("a"))
+        );
+        assert_eq!(cache.get(&path(Some("bucket"), None)), None);
+
+        for (fetched_at, _) in cache.listings.values_mut() {
+            *fetched_at = Instant::now() - LISTING_CACHE_TTL;
+        }
+        assert_eq!(cache.get(&path(Some("bucket"), Some("dir/"))), None);
+    }
+
+    #[test]
+    fn changes_invalidate_the_directories_above_and_below_them() {
+        let mut cache = cached_with(&[
+            (None, None),
+            (Some("bucket"), None),
+            (Some("bucket"), Some("dir/")),
+            (Some("bucket"), Some("dir/sub/")),
+            (Some("bucket"), Some("other/")),
+            (Some("another"), Some("dir/")),
+        ]);
+        let generation = cache.generation();
+
+        cache.invalidate(&path(Some("bucket"), Some("dir/file")));
+        assert!(cache.generation() > generation);
+        assert!(cache.get(&path(None, None)).is_some());
+        assert!(cache.get(&path(Some("bucket"), None)).is_none());
+        assert!(cache.get(&path(Some("bucket"), Some("dir/"))).is_none());
+        assert!(cache.get(&path(Some("bucket"), Some("dir/sub/"))).is_some());
+        assert!(cache.get(&path(Some("bucket"), Some("other/"))).is_some());
+        assert!(cache.get(&path(Some("another"), Some("dir/"))).is_some());
+
+        cache.invalidate(&path(Some("bucket"), Some("dir/")));
+        assert!(cache.get(&path(Some("bucket"), Some("dir/sub/"))).is_none());
+        assert!(cache.get(&path(Some("bucket"), Some("other/"))).is_some());
+    }
+
+    #[test]
+    fn globs_invalidate_everything_they_could_match() {
+        let mut cache = cached_with(&[
+            (Some("bucket"), Some("logs/2021/")),
+            (Some("bucket"), Some("logs/2022/")),
+            (Some("bucket"), Some("images/")),
+        ]);
+
+        cache.invalidate(&path(Some("bucket"), Some("logs/*/app.log")));
+        assert!(cache
+            .get(&path(Some("bucket"), Some("logs/2021/")))
+            .is_none());
+        assert!(cache
+            .get(&path(Some("bucket"), Some("logs/2022/")))
+            .is_none());
+        assert!(cache.get(&path(Some("bucket"), Some("images/"))).is_some());
+    }
+
+    #[test]
+    fn bucket_wide_changes_leave_other_buckets_alone() {
+        let mut cache = cached_with(&[
+            (None, None),
+            (Some("bucket"), Some("dir/")),
+            (Some("another"), None),
+        ]);
+
+        cache.invalidate(&path(Some("bucket"), None));
+        assert!(cache.get(&path(Some("bucket"), Some("dir/"))).is_none());
+        assert!(cache.get(&path(Some("another"), None)).is_some());
+        assert!(cache.get(&path(None, None)).is_some());
+
+        cache.invalidate(&path(None, None));
+        assert!(cache.listings.is_empty());
+    }
+}
@@ -0,0 +1,575 @@
+use crate::backend::StorageBackend;
+use crate::checksum::ChecksumAlgorithm;
+use crate::error::{ErrorKind, RBError};
+use crate::s3::{
+    BucketEncryption, CorsRule, DeleteFailure, GetOptions, IncompleteUpload, ObjectEntry,
+    ObjectGrant, ObjectHead, ObjectLockStatus, SelectQuery, Usage, VersionEntry,
+};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{metadata, read_dir};
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::fs;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Serves "buckets" from the directories inside a local root directory, with each file under a bucket's directory
+/// standing in for an object. It's for demos, and for rehearsing scripts without any cloud credentials.
+///
+/// Object metadata, checksums, versions, and byte ranges aren't supported.
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Result<Self, RBError> {
+        let root = root.canonicalize().map_err(RBError::wrap_io)?;
+        if !root.is_dir() {
+            return Err(RBError::wrap_config(format!(
+                "Local backend root {} is not a directory",
+                root.display()
+            )));
+        }
+        Ok(LocalBackend { root })
+    }
+
+    // Keys can contain anything, but they mustn't be able to reach outside of the root. A bucket has to be exactly one
+    // directory name, so not `.` or `..` either.
+    fn object_path(&self, bucket: &str, key: &str) -> Result<PathBuf, RBError> {
+        let mut bucket_components = Path::new(bucket).components();
+        let bucket_is_one_name = matches!(bucket_components.next(), Some(Component::Normal(_)))
+            && bucket_components.next().is_none()
+            && !bucket.contains('/');
+        let key_escapes = Path::new(key)
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)));
+        if !bucket_is_one_name || key_escapes {
+            return Err(RBError::new(ErrorKind::InvalidTarget));
+        }
+        Ok(self.root.join(bucket).join(key))
+    }
+
+    // Every key in a bucket, found by walking its directory
+    fn all_keys(&self, bucket: &str) -> Result<Vec<String>, RBError> {
+        let bucket_path = self.object_path(bucket, "")?;
+        let mut keys = Vec::new();
+        let mut dirs_to_visit = vec![bucket_path.clone()];
+        while let Some(dir) = dirs_to_visit.pop() {
+            for entry in read_dir(&dir).map_err(RBError::wrap_io)? {
+                let path = entry.map_err(RBError::wrap_io)?.path();
+                if path.is_dir() {
+                    dirs_to_visit.push(path);
+                } else if let Ok(relative_path) = path.strip_prefix(&bucket_path) {
+                    let key_parts: Vec<String> = relative_path
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                        .collect();
+                    keys.push(key_parts.join("/"));
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    // Groups the keys under `prefix` the way S3 does with a `/` delimiter: keys with another `/` after the prefix
+    // collapse into a single "directory" entry. Returns the directories and the files, relative to `prefix`.
+    fn group_under_prefix(
+        &self,
+        bucket: &str,
+        prefix: &str,
+    ) -> Result<(Vec<String>, Vec<String>), RBError> {
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        for key in self.all_keys(bucket)? {
+            if let Some(rest) = key.strip_prefix(prefix) {
+                match rest.find('/') {
+                    Some(slash_index) => dirs.push(rest[..=slash_index].to_owned()),
+                    None => files.push(rest.to_owned()),
+                }
+            }
+        }
+        dirs.sort_unstable();
+        dirs.dedup();
+        files.sort_unstable();
+        Ok((dirs, files))
+    }
+}
+
+// What S3 calls the only version of an object in a bucket that has never been versioned
+const NULL_VERSION_ID: &str = "null";
+
+// Byte ranges and versions only make sense against S3
+fn check_get_options(options: &GetOptions) -> Result<(), RBError> {
+    if options.version_id.is_some() || options.range.is_some() {
+        return Err(RBError::new(ErrorKind::InvalidTarget));
+    }
+    Ok(())
+}
+
+async fn create_parent_dirs(path: &Path) -> Result<(), RBError> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).await.map_err(RBError::wrap_io),
+        None => Ok(()),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn list_buckets(&self) -> Result<Vec<String>, RBError> {
+        let mut buckets = Vec::new();
+        for entry in read_dir(&self.root).map_err(RBError::wrap_io)? {
+            let entry = entry.map_err(RBError::wrap_io)?;
+            if entry.path().is_dir() {
+                buckets.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        buckets.sort_unstable();
+        Ok(buckets)
+    }
+
+    async fn list_entries(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> Result<Vec<ObjectEntry>, RBError> {
+        debug!(
+            "listing local files at bucket {}, prefix {:?}",
+            bucket, prefix
+        );
+        let prefix = prefix.unwrap_or_default();
+        let (dirs, files) = self.group_under_prefix(&bucket, &prefix)?;
+        let mut results: Vec<ObjectEntry> = dirs
+            .into_iter()
+            .map(|name| ObjectEntry {
+                name,
+                is_prefix: true,
+                size: None,
+                last_modified: None,
+                etag: None,
+                storage_class: None,
+            })
+            .collect();
+        for name in files {
+            let meta = metadata(self.object_path(&bucket, &format!("{}{}", prefix, name))?)
+                .map_err(RBError::wrap_io)?;
+            // Local files have no ETag or storage class to speak of
+            results.push(ObjectEntry {
+                name,
+                is_prefix: false,
+                size: Some(meta.len()),
+                last_modified: meta.modified().ok().map(DateTime::<Utc>::from),
+                etag: None,
+                storage_class: None,
+            });
+        }
+        Ok(results)
+    }
+
+    async fn list_keys(&self, bucket: String, prefix: String) -> Result<Vec<String>, RBError> {
+        let mut keys: Vec<String> = self
+            .all_keys(&bucket)?
+            .into_iter()
+            .filter(|key| key.starts_with(&prefix))
+            .collect();
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    async fn usage(
+        &self,
+        bucket: String,
+        prefix: String,
+        max_objects: Option<u64>,
+    ) -> Result<Option<Usage>, RBError> {
+        let mut usage = Usage::default();
+        for key in self.list_keys(bucket.clone(), prefix).await? {
+            usage.objects += 1;
+            if max_objects.is_some_and(|max_objects| usage.objects > max_objects) {
+                return Ok(None);
+            }
+            usage.bytes += metadata(self.object_path(&bucket, &key)?)
+                .map_err(RBError::wrap_io)?
+                .len();
+        }
+        Ok(Some(usage))
+    }
+
+    async fn list_completions(
+        &self,
+        bucket: String,
+        dir_prefix: String,
+        partial: &str,
+    ) -> Result<Vec<String>, RBError> {
+        let (dirs, files) =
+            self.group_under_prefix(&bucket, &format!("{}{}", dir_prefix, partial))?;
+        Ok(dirs
+            .into_iter()
+            .chain(files)
+            .map(|name| format!("{}{}", partial, name))
+            .collect())
+    }
+
+    async fn object_exists(&self, bucket: String, key: String) -> Result<bool, RBError> {
+        Ok(self.object_path(&bucket, &key)?.is_file())
+    }
+
+    async fn head_object(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+    ) -> Result<Option<ObjectHead>, RBError> {
+        check_get_options(options)?;
+        let path = self.object_path(&bucket, &key)?;
+        match metadata(&path) {
+            Ok(meta) if meta.is_file() => Ok(Some(ObjectHead {
+                size: meta.len(),
+                last_modified: meta.modified().ok().map(DateTime::<Utc>::from),
+                restore: None,
+                metadata: HashMap::new(),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(RBError::wrap_io(e)),
+        }
+    }
+
+    async fn download_object(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+        dest_path: &Path,
+    ) -> Result<(), RBError> {
+        check_get_options(options)?;
+        let path = self.object_path(&bucket, &key)?;
+        debug!("copying local object {:?} to file {:?}", path, dest_path);
+        fs::copy(&path, dest_path).await.map_err(RBError::wrap_io)?;
+        Ok(())
+    }
+
+    async fn download_object_to_stdout(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+    ) -> Result<(), RBError> {
+        check_get_options(options)?;
+        let path = self.object_path(&bucket, &key)?;
+        let mut object_file = fs::File::open(path).await.map_err(RBError::wrap_io)?;
+        let mut stdout = io::stdout();
+        io::copy(&mut object_file, &mut stdout)
+            .await
+            .map_err(RBError::wrap_io)?;
+        stdout.flush().await.map_err(RBError::wrap_io)
+    }
+
+    // Only S3 itself can run the queries
+    async fn select_object(
+        &self,
+        _bucket: String,
+        _key: String,
+        _query: &SelectQuery,
+        _out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    async fn put_object(
+        &self,
+        bucket: String,
+        key: String,
+        source_path: &Path,
+        _metadata: HashMap<String, String>,
+        _checksum: Option<ChecksumAlgorithm>,
+    ) -> Result<(), RBError> {
+        let path = self.object_path(&bucket, &key)?;
+        debug!("copying file {:?} to local object {:?}", source_path, path);
+        create_parent_dirs(&path).await?;
+        fs::copy(source_path, &path)
+            .await
+            .map_err(RBError::wrap_io)?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, bucket: String, key: String) -> Result<(), RBError> {
+        let path = self.object_path(&bucket, &key)?;
+        fs::remove_file(path).await.map_err(RBError::wrap_io)
+    }
+
+    async fn delete_objects(
+        &self,
+        bucket: String,
+        keys: Vec<String>,
+    ) -> Result<Vec<DeleteFailure>, RBError> {
+        let mut failures = Vec::new();
+        for key in keys {
+            if let Err(e) = self.delete_object(bucket.clone(), key.clone()).await {
+                failures.push(DeleteFailure {
+                    key,
+                    kind: e.kind(),
+                    message: e.to_string(),
+                });
+            }
+        }
+        Ok(failures)
+    }
+
+    // An unversioned S3 bucket still calls each object's only version `null`, so local files get the same
+    async fn delete_object_version(
+        &self,
+        bucket: String,
+        key: String,
+        version_id: String,
+    ) -> Result<(), RBError> {
+        if version_id != NULL_VERSION_ID {
+            return Err(RBError::new(ErrorKind::NotFound));
+        }
+        self.delete_object(bucket, key).await
+    }
+
+    async fn copy_object(
+        &self,
+        source_bucket: String,
+        source_key: String,
+        dest_bucket: String,
+        dest_key: String,
+    ) -> Result<u64, RBError> {
+        let source_path = self.object_path(&source_bucket, &source_key)?;
+        let dest_path = self.object_path(&dest_bucket, &dest_key)?;
+        create_parent_dirs(&dest_path).await?;
+        fs::copy(&source_path, &dest_path)
+            .await
+            .map_err(RBError::wrap_io)
+    }
+
+    // Local files are all stored the same way
+    async fn change_storage_class(
+        &self,
+        _bucket: String,
+        _key: String,
+        _storage_class: String,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    // Local files have nowhere to keep metadata
+    async fn set_object_metadata(
+        &self,
+        _bucket: String,
+        _key: String,
+        _metadata: HashMap<String, String>,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    // Local files don't have a content type of their own, just an extension
+    async fn set_content_type(
+        &self,
+        _bucket: String,
+        _key: String,
+        _content_type: String,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    // Local files are never archived, so there's nothing to restore
+    async fn restore_object(
+        &self,
+        _bucket: String,
+        _key: String,
+        _days: i64,
+        _tier: Option<String>,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    // Local files are always written in one go, so there's never an upload left half-done
+    async fn list_incomplete_uploads(
+        &self,
+        _bucket: String,
+        _prefix: String,
+    ) -> Result<Vec<IncompleteUpload>, RBError> {
+        Ok(Vec::new())
+    }
+
+    async fn list_object_versions(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> Result<Vec<VersionEntry>, RBError> {
+        let mut versions = Vec::new();
+        for key in self.list_keys(bucket.clone(), prefix).await? {
+            let meta = metadata(self.object_path(&bucket, &key)?).map_err(RBError::wrap_io)?;
+            versions.push(VersionEntry {
+                key,
+                version_id: String::from(NULL_VERSION_ID),
+                is_latest: true,
+                is_delete_marker: false,
+                size: Some(meta.len()),
+                last_modified: meta.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+        Ok(versions)
+    }
+
+    // Local files are only as private as the file system makes them, which isn't something an S3 ACL can describe
+    async fn object_grants(
+        &self,
+        _bucket: String,
+        _key: String,
+    ) -> Result<Vec<ObjectGrant>, RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    async fn set_object_acl(
+        &self,
+        _bucket: String,
+        _key: String,
+        _acl: String,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    // Local files have nowhere to keep tags
+    async fn object_tags(
+        &self,
+        _bucket: String,
+        _key: String,
+    ) -> Result<BTreeMap<String, String>, RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    async fn set_object_tags(
+        &self,
+        _bucket: String,
+        _key: String,
+        _tags: BTreeMap<String, String>,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+
+    // Browsers never talk to local buckets
+    async fn bucket_cors_rules(&self, _bucket: String) -> Result<Vec<CorsRule>, RBError> {
+        Ok(Vec::new())
+    }
+
+    // Whatever encryption there is happens at the filesystem level, out of our sight
+    async fn bucket_encryption(
+        &self,
+        _bucket: String,
+    ) -> Result<Option<BucketEncryption>, RBError> {
+        Ok(None)
+    }
+
+    // Nothing stops a local file from being deleted, so none of them are ever locked
+    async fn object_lock_status(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<ObjectLockStatus, RBError> {
+        if !self.object_path(&bucket, &key)?.is_file() {
+            return Err(RBError::new(ErrorKind::NotFound));
+        }
+        Ok(ObjectLockStatus::default())
+    }
+
+    async fn abort_incomplete_upload(
+        &self,
+        _bucket: String,
+        _key: String,
+        _upload_id: String,
+    ) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::NotFound))
+    }
+
+    // Overwriting a local file loses what was there before, so local buckets are never versioned
+    async fn bucket_versioning(&self, _bucket: String) -> Result<Option<String>, RBError> {
+        Ok(None)
+    }
+
+    async fn set_bucket_versioning(&self, _bucket: String, _enabled: bool) -> Result<(), RBError> {
+        Err(RBError::new(ErrorKind::InvalidTarget))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    /// Makes a fresh root directory for a local backend, holding `files` (bucket-relative paths like `bucket/a/b.txt`)
+    /// with their own paths as their contents
+    pub(crate) fn temp_root(name: &str, files: &[&str]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rustbucket-{}-{}", name, std::process::id()));
+        if root.exists() {
+            remove_dir_all(&root).unwrap();
+        }
+        create_dir_all(&root).unwrap();
+        for file in files {
+            let path = root.join(file);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, file).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn buckets_and_keys_cant_reach_outside_the_root() {
+        let root = temp_root("local-paths", &["bucket/a/b.txt"]);
+        let backend = LocalBackend::new(root.clone()).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(
+            backend.object_path("bucket", "a/b.txt").unwrap(),
+            root.join("bucket/a/b.txt")
+        );
+        for bucket in &["..", ".", "a/b", "bucket/", "", "/etc"] {
+            let err = backend.object_path(bucket, "key").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidTarget, "bucket {:?}", bucket);
+        }
+        for key in &["../other/key", "a/../../b", "/etc/passwd", "./a"] {
+            let err = backend.object_path("bucket", key).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidTarget, "key {:?}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn copying_and_deleting_objects() {
+        let root = temp_root("local-copy", &["bucket/a/b.txt", "other/c.txt"]);
+        let backend = LocalBackend::new(root.clone()).unwrap();
+
+        let bytes = backend
+            .copy_object(
+                "bucket".into(),
+                "a/b.txt".into(),
+                "other".into(),
+                "x/y.txt".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(bytes, "bucket/a/b.txt".len() as u64);
+        assert_eq!(
+            std::fs::read_to_string(root.join("other/x/y.txt")).unwrap(),
+            "bucket/a/b.txt"
+        );
+
+        backend
+            .delete_object("bucket".into(), "a/b.txt".into())
+            .await
+            .unwrap();
+        assert!(!root.join("bucket/a/b.txt").exists());
+        let failures = backend
+            .delete_objects("other".into(), vec!["c.txt".into(), "../bucket".into()])
+            .await
+            .unwrap();
+        assert_eq!(failures.len(), 1);
+        assert!(!root.join("other/c.txt").exists());
+        assert!(root.join("bucket").exists());
+    }
+}
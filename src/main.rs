@@ -16,41 +16,322 @@
  * along with rustbucket.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::io::{stdin, IsTerminal};
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
 use clap::*;
 
 use rustbucket::Config;
 
-#[tokio::main]
-async fn main() {
-    let matches = clap::app_from_crate!(", ")
+fn validate_seconds(value: String) -> std::result::Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(()),
+        _ => Err(String::from("must be a positive number of seconds")),
+    }
+}
+
+// The value of an optional flag, or clap's usual error and exit if it doesn't parse (though the flag's validator should
+// already have caught that)
+fn optional_value<T>(matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T: FromStr,
+{
+    if matches.is_present(name) {
+        Some(value_t!(matches, name, T).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    }
+}
+
+fn seconds_arg(matches: &ArgMatches, name: &str) -> Option<Duration> {
+    optional_value(matches, name).map(Duration::from_secs_f64)
+}
+
+// Built in a function (rather than inline in main) because generating shell completions needs its own copy of the app
+fn build_cli() -> App<'static, 'static> {
+    // Not app_from_crate!, because its crate_authors! expansion no longer compiles (it trips the deny-by-default
+    // dangerous_implicit_autorefs lint). There's only one author to list anyway.
+    App::new(crate_name!())
+        .version(crate_version!())
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(crate_description!())
+        .after_help(
+            "EXIT CODES:
+    0    Success
+    1    Unexpected failure
+    2    Unknown command, invalid arguments, or invalid config file
+    3    File, object, or bucket not found
+    4    Access denied or credentials problem
+    5    Transfer or other S3/file operation failed
+    6    Destination already exists
+    130  Cancelled with Ctrl-C
+
+PORCELAIN FORMAT (v1):
+    Each line is a tab-separated record; tabs, newlines, and backslashes in fields are escaped as \\t, \\n, and \\\\.
+    entry       <name>
+    <name>      <value>                  (remote_directory, local_directory, region)
+    message     <text>
+    <outcome>   <source> <destination>   (downloaded, uploaded, skipped)
+    object      <name> <size> <last modified> <etag> <storage class>   (ls -l)
+    prefix      <name>                   (ls -l)
+    usage       <objects> <bytes>        (du)
+    bucket_usage <bucket> <objects> <bytes>   (df)
+    stat        <path> <size> <last modified> [<retention mode> <retain until> <legal hold>]   (--lock)
+    object_lock <retention mode> <retain until> <legal hold>   (lock)
+    error       <kind> <exit code> <message>
+    New fields are only ever appended to the end of a record.
+
+CONFIG FILE:
+    Settings are read from config.toml in rustbucket's config directory (e.g. ~/.config/rustbucket/config.toml):
+    prompt = \"[{path}] > \"    Placeholders: {path}, {bucket}, {prefix}, {region}, {profile}, {local}
+    history_size = 1000
+    edit_mode = \"emacs\"       Or \"vi\" for vi-style keybindings
+    resume = false            Always act as if --resume were given
+    notify_after = 60         Ring the terminal bell after any command that took at least this many seconds
+    time_style = \"relative\"   How ls -l shows modification times: \"relative\" (e.g. 3 hours ago) or \"full\"",
+        )
         .arg(
             Arg::with_name("debug")
                 .short("d")
-                .help("Enable debug logging"),
+                .help("Enable debug logging (same as -v)"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Log each S3 request; repeat (-vv) to also log HTTP wire traffic. RUST_LOG overrides this"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Suppress the banner, progress, and other informational messages"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print each command's result (and any errors) as a JSON document"),
+        )
+        .arg(
+            Arg::with_name("porcelain")
+                .long("porcelain")
+                .conflicts_with("json")
+                .help("Print each command's result as stable, tab-delimited lines (see below)"),
         )
         .arg(
             Arg::with_name("command")
                 .short("c")
                 .empty_values(false)
                 .value_name("COMMAND")
-                .help("Execute a one-off command instead of opening interactive prompt"),
+                .multiple(true)
+                .number_of_values(1)
+                .help("Execute one-off command(s) instead of opening interactive prompt. May be given more than once, and multiple commands may be separated with ';'"),
+        )
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .long("file")
+                .empty_values(false)
+                .value_name("SCRIPT")
+                .conflicts_with("command")
+                .help("Execute the commands in a file, one per line, instead of opening interactive prompt"),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Keep running the rest of a script file (or piped commands) after a command fails"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .empty_values(false)
+                .value_name("FILE")
+                .help("Read settings from this file instead of the default config.toml (see CONFIG FILE below)"),
+        )
+        .arg(
+            Arg::with_name("history-size")
+                .long("history-size")
+                .value_name("COUNT")
+                .validator(|value| value.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("How many commands to remember in the interactive prompt's history, 0 to keep none (defaults to 1000)"),
+        )
+        .arg(
+            Arg::with_name("notify-after")
+                .long("notify-after")
+                .value_name("SECONDS")
+                .validator(validate_seconds)
+                .help("Ring the terminal bell when a command that took at least this long finishes, e.g. a big download"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .empty_values(false)
+                .value_name("BACKEND")
+                .help("Where buckets live: s3 (the default), or local:PATH to serve each directory in PATH as a bucket, for demos and offline practice"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Start in the remote and local directories where the last interactive session left off"),
+        )
+        .arg(
+            Arg::with_name("remote-dir")
+                .long("remote-dir")
+                .empty_values(false)
+                .value_name("PATH")
+                .help("Remote directory to start in, e.g. /my-bucket/some/prefix (defaults to /)"),
+        )
+        .arg(
+            Arg::with_name("local-dir")
+                .long("local-dir")
+                .empty_values(false)
+                .value_name("PATH")
+                .help("Local directory to start in (defaults to the current directory)"),
+        )
+        .arg(
+            Arg::with_name("region")
+                .long("region")
+                .empty_values(false)
+                .value_name("REGION")
+                .help("AWS region to connect to (defaults to your AWS environment/profile configuration)"),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("SECONDS")
+                .validator(validate_seconds)
+                .help("Give up on connecting to S3 after this many seconds"),
+        )
+        .arg(
+            Arg::with_name("read-timeout")
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .validator(validate_seconds)
+                .help("Give up on an S3 request that hasn't been answered after this many seconds"),
+        )
+        .arg(
+            Arg::with_name("max-attempts")
+                .long("max-attempts")
+                .value_name("COUNT")
+                .validator(|value| match value.parse::<u32>() {
+                    Ok(count) if count > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive whole number")),
+                })
+                .help("Try a command this many times in total when S3 requests fail transiently (defaults to AWS_MAX_ATTEMPTS, your profile's max_attempts, or 3)"),
+        )
+        .arg(
+            Arg::with_name("retry-base-delay")
+                .long("retry-base-delay")
+                .value_name("SECONDS")
+                .validator(validate_seconds)
+                .help("Wait this long before the first retry, doubling for each one after (defaults to 0.5)"),
+        )
+        .arg(
+            Arg::with_name("use-fips-endpoint")
+                .long("use-fips-endpoint")
+                .help("Use the region's FIPS 140-2 S3 endpoint (also set by AWS_USE_FIPS_ENDPOINT or use_fips_endpoint)"),
+        )
+        .arg(
+            Arg::with_name("no-verify-ssl")
+                .long("no-verify-ssl")
+                .help("INSECURE: don't verify TLS certificates, e.g. for a self-hosted endpoint with a self-signed cert"),
+        )
+        .arg(
+            Arg::with_name("force-path-style")
+                .long("force-path-style")
+                .help(
+                    "Only give path-style (https://endpoint/bucket/key) URLs from `url`, and address buckets that way in \
+                     the requests rustbucket builds itself (checksummed uploads and select). Other requests are always \
+                     path-style",
+                ),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("no-sign-request")
+                .long("no-sign-request")
+                .help("Don't sign requests or look for credentials; useful for browsing public buckets"),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Print a shell completion script for rustbucket to stdout")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants())
+                        .help("The shell to generate completions for"),
+                ),
+        )
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = build_cli().get_matches();
+
+    if let Some(completion_matches) = matches.subcommand_matches("completions") {
+        let shell = value_t!(completion_matches, "shell", Shell).unwrap_or_else(|e| e.exit());
+        build_cli().gen_completions_to(crate_name!(), shell, &mut std::io::stdout());
+        return;
+    }
 
     let conf = Config {
-        debug: matches.is_present("debug"),
-        single_command: matches.value_of("command").map(|s| s.to_owned()),
+        verbosity: matches
+            .occurrences_of("verbose")
+            .max(matches.is_present("debug") as u64),
+        commands: matches
+            .values_of("command")
+            .map_or(vec![], |cmds| cmds.map(|s| s.to_owned()).collect()),
+        region: matches.value_of("region").map(|s| s.to_owned()),
+        force_path_style: matches.is_present("force-path-style"),
+        no_sign_request: matches.is_present("no-sign-request"),
+        connect_timeout: seconds_arg(&matches, "connect-timeout"),
+        read_timeout: seconds_arg(&matches, "read-timeout"),
+        max_attempts: optional_value(&matches, "max-attempts"),
+        retry_base_delay: seconds_arg(&matches, "retry-base-delay"),
+        no_verify_ssl: matches.is_present("no-verify-ssl"),
+        use_fips_endpoint: matches.is_present("use-fips-endpoint"),
+        script_file: matches.value_of("file").map(PathBuf::from),
+        keep_going: matches.is_present("keep-going"),
+        quiet: matches.is_present("quiet"),
+        history_size: optional_value(&matches, "history-size"),
+        config_file: matches.value_of("config").map(PathBuf::from),
+        resume: matches.is_present("resume"),
+        remote_dir: matches.value_of("remote-dir").map(String::from),
+        local_dir: matches.value_of("local-dir").map(PathBuf::from),
+        json: matches.is_present("json"),
+        porcelain: matches.is_present("porcelain"),
+        notify_after: seconds_arg(&matches, "notify-after"),
+        backend: matches.value_of("backend").map(String::from),
     };
 
-    println!("rustbucket {}", crate_version!());
-    println!(
-        "This program comes with ABSOLUTELY NO WARRANTY.
+    // In one-off command, script, and piped-stdin modes, stdout is reserved for the commands' output (e.g. `get key -`
+    // streams the object body there), so the banner and sign-off go to stderr instead
+    let one_off = !conf.commands.is_empty() || conf.script_file.is_some() || !stdin().is_terminal();
+    let banner = format!(
+        "rustbucket {}
+This program comes with ABSOLUTELY NO WARRANTY.
 This is free software, and you are welcome to redistribute it.
 ",
+        crate_version!()
     );
+    let quiet = conf.quiet;
+    if !quiet {
+        if one_off {
+            eprintln!("{}", banner);
+        } else {
+            println!("{}", banner);
+        }
+    }
 
     match rustbucket::run(conf).await {
+        Ok(_) if quiet => {}
+        Ok(_) if one_off => eprintln!("Bye!"),
         Ok(_) => println!("Bye!"),
-        Err(e) => eprintln!("Crash: {}", e),
+        Err(e) => {
+            eprintln!("Crash: {}", e);
+            exit(e.exit_code());
+        }
     };
 }
@@ -0,0 +1,558 @@
+use crate::commands::{TransferOutcome, TransferReport};
+use crate::error::RBError;
+use crate::s3::ObjectEntry;
+use crate::table::{Align, Table};
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tracing_subscriber::EnvFilter;
+
+// Set once at startup from the -q flag. It's global because informational output happens all over the place, and
+// threading a flag through every one of those call sites would be a lot of plumbing for a single bool.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+tokio::task_local! {
+    // Set for background jobs, which would otherwise draw their progress over whatever the user is doing at the prompt
+    static IN_BACKGROUND: bool;
+}
+
+/// Runs a task as a background job, which is always quiet
+pub async fn in_background<F: Future>(task: F) -> F::Output {
+    IN_BACKGROUND.scope(true, task).await
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+        || IN_BACKGROUND
+            .try_with(|in_background| *in_background)
+            .unwrap_or(false)
+}
+
+/// Like `eprintln!`, but for informational chatter that `-q` should silence. Anything that's actually the result of a
+/// command, or a warning/error, should not use this.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::output::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Sets up diagnostic logging on stderr. RUST_LOG takes precedence when set; otherwise `verbosity` picks the filter:
+/// 1 (-v) shows our own per-request logs, and 2 or more (-vv) adds wire-level logs from rusoto and the HTTP client.
+/// If the process already has a global subscriber (say, `run` is being called from another program), that one is kept.
+pub fn init_logging(verbosity: u64) {
+    let default_filter = match verbosity {
+        0 => "warn",
+        1 => "warn,rustbucket=debug",
+        _ => "debug,rustbucket=trace,rusoto_core=trace,hyper=trace",
+    };
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter));
+    // rusoto and hyper log through the `log` crate; the subscriber picks those records up too
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .without_time()
+        .try_init()
+        .ok();
+}
+
+/// How command results (and errors) are written out
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    /// Friendly text meant for people
+    Human,
+    /// One JSON document per command, meant for scripts that shouldn't care how the human wording changes
+    Json,
+    /// Tab-delimited lines with a fixed field order, for awk/cut. The v1 format is documented in `--help`; within a
+    /// version, fields may only ever be appended to the end of a line. Reordering or removing one needs a new version.
+    Porcelain,
+}
+
+// Also set once at startup, for the same reason as QUIET
+static FORMAT: AtomicU8 = AtomicU8::new(OutputFormat::Human as u8);
+
+pub fn set_format(format: OutputFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn format() -> OutputFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        f if f == OutputFormat::Json as u8 => OutputFormat::Json,
+        f if f == OutputFormat::Porcelain as u8 => OutputFormat::Porcelain,
+        _ => OutputFormat::Human,
+    }
+}
+
+// Tabs and newlines would break the line/field structure, so they're escaped the same way git quotes odd paths
+fn porcelain_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn porcelain_line(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|field| porcelain_field(field))
+        .collect::<Vec<String>>()
+        .join("\t")
+}
+
+/// How long listings show people when objects were last modified
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeStyle {
+    /// Like "3 hours ago" for the last week, and just the date for anything older
+    #[default]
+    Relative,
+    /// The exact time, as an ISO 8601 timestamp in UTC (`ls -l --full-time`)
+    Full,
+}
+
+const NO_FILES_MESSAGE: &str = "There are no files at this path.\n";
+
+/// How `ls` shows what it finds
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ListingFormat {
+    /// Just the names, except that people are also shown which objects are archived
+    Short,
+    /// Each object's size, modification time, and storage class too (`ls -l`)
+    Long,
+    /// Every detail as RFC 4180 CSV with a header row, whatever the output format (`ls --csv`), for spreadsheets and
+    /// data tools
+    Csv,
+    /// Just the names, each ending in a NUL character rather than a line break, whatever the output format
+    /// (`ls -0`). Unlike a line break, a NUL can't be part of a key, so this is safe to feed to `xargs -0`.
+    Print0,
+}
+
+/// What a command produced. It's kept apart from how it's shown, so that the same result can be rendered as human text,
+/// JSON, or porcelain, and so that library users can look at it without parsing any of those.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum CommandOutput {
+    /// Nothing to show, e.g. because the output was redirected to a file or pipeline
+    Empty,
+    /// A list of names (files, prefixes, buckets, jobs...), and what to say instead if there aren't any
+    Listing {
+        entries: Vec<String>,
+        empty_message: String,
+    },
+    /// A single named value, like the current directory or region, and how to say it to a person
+    Value {
+        name: String,
+        value: String,
+        message: String,
+    },
+    /// A free-form message with no more specific structure to it
+    Message(String),
+    /// What each get or put did
+    Transfers(Vec<TransferReport>),
+    /// Objects and prefixes along with their details, from `ls`
+    Objects {
+        entries: Vec<ObjectEntry>,
+        format: ListingFormat,
+        /// How a long listing shows modification times to people
+        time_style: TimeStyle,
+    },
+    /// Data that's passed along exactly as it came, whatever the output format, like the records from `select`
+    Records(String),
+    /// Rows of named fields, like the size of each object from `stat`. People see them as `table`; JSON gets an object
+    /// per row in an array under `kind`, and porcelain a line per row starting with `kind`, with the fields in order.
+    Rows {
+        kind: String,
+        rows: Vec<Row>,
+        table: Vec<String>,
+        empty_message: String,
+    },
+}
+
+/// The fields of one row of `CommandOutput::Rows`, named and in order
+pub type Row = Vec<(&'static str, serde_json::Value)>;
+
+impl CommandOutput {
+    pub fn listing(entries: Vec<String>, empty_message: &str) -> Self {
+        CommandOutput::Listing {
+            entries,
+            empty_message: String::from(empty_message),
+        }
+    }
+
+    pub fn value(name: &str, value: &str, message: String) -> Self {
+        CommandOutput::Value {
+            name: String::from(name),
+            value: String::from(value),
+            message,
+        }
+    }
+
+    pub fn rows(kind: &str, rows: Vec<Row>, table: Table, empty_message: &str) -> Self {
+        CommandOutput::Rows {
+            kind: String::from(kind),
+            rows,
+            table: table.render(),
+            empty_message: String::from(empty_message),
+        }
+    }
+
+    /// Like `render`, but ready to be written out as is: ending in a line break, unless it's empty, NUL-delimited, or
+    /// CSV. A line break after NUL-delimited entries would be read as one more entry, and CSV records already end in
+    /// their own CRLF.
+    pub fn render_text(&self) -> String {
+        let rendered = self.render();
+        match self {
+            _ if rendered.is_empty() => rendered,
+            CommandOutput::Objects {
+                format: ListingFormat::Print0 | ListingFormat::Csv,
+                ..
+            }
+            | CommandOutput::Records(_) => rendered,
+            _ => rendered + "\n",
+        }
+    }
+
+    /// Renders this in whichever output format (human, JSON, or porcelain) the process is using
+    pub fn render(&self) -> String {
+        match self {
+            CommandOutput::Empty => String::new(),
+            CommandOutput::Listing {
+                entries,
+                empty_message,
+            } => render_listing(entries, empty_message),
+            CommandOutput::Value {
+                name,
+                value,
+                message,
+            } => render_value(name, value, message),
+            CommandOutput::Message(message) => render_message(message),
+            CommandOutput::Records(records) => records.clone(),
+            CommandOutput::Rows {
+                kind,
+                rows,
+                table,
+                empty_message,
+            } => render_rows(kind, rows, table, empty_message),
+            CommandOutput::Transfers(reports) => reports
+                .iter()
+                .map(render_transfer)
+                .filter(|rendered| !rendered.is_empty())
+                .collect::<Vec<String>>()
+                .join("\n"),
+            CommandOutput::Objects {
+                entries,
+                format: ListingFormat::Csv,
+                ..
+            } => render_csv(entries),
+            CommandOutput::Objects {
+                entries,
+                format: ListingFormat::Print0,
+                ..
+            } => entries
+                .iter()
+                .map(|entry| format!("{}\0", entry.name))
+                .collect(),
+            CommandOutput::Objects {
+                entries,
+                format: ListingFormat::Short,
+                ..
+            } => render_names(entries),
+            CommandOutput::Objects {
+                entries,
+                time_style,
+                ..
+            } => render_objects(entries, *time_style),
+        }
+    }
+}
+
+// A list of names (files, prefixes, buckets...), or `empty_message` if there aren't any
+fn render_listing(entries: &[String], empty_message: &str) -> String {
+    match format() {
+        OutputFormat::Human if entries.is_empty() => String::from(empty_message),
+        OutputFormat::Human => entries.join("\n"),
+        OutputFormat::Json => json!({ "entries": entries }).to_string(),
+        OutputFormat::Porcelain => entries
+            .iter()
+            .map(|entry| porcelain_line(&["entry", entry]))
+            .collect::<Vec<String>>()
+            .join("\n"),
+    }
+}
+
+fn render_value(name: &str, value: &str, human_message: &str) -> String {
+    match format() {
+        OutputFormat::Human => String::from(human_message),
+        OutputFormat::Json => json!({ name: value }).to_string(),
+        OutputFormat::Porcelain => porcelain_line(&[name, value]),
+    }
+}
+
+fn render_message(message: &str) -> String {
+    match format() {
+        OutputFormat::Human => String::from(message),
+        OutputFormat::Json => json!({ "message": message }).to_string(),
+        OutputFormat::Porcelain => porcelain_line(&["message", message]),
+    }
+}
+
+fn render_rows(kind: &str, rows: &[Row], table: &[String], empty_message: &str) -> String {
+    match format() {
+        OutputFormat::Human if rows.is_empty() => String::from(empty_message),
+        OutputFormat::Human => table.join("\n"),
+        OutputFormat::Json => json!({
+            kind: rows
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|(name, value)| (String::from(*name), value.clone()))
+                        .collect::<serde_json::Map<String, serde_json::Value>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .to_string(),
+        OutputFormat::Porcelain => rows
+            .iter()
+            .map(|row| {
+                let fields: Vec<String> = row
+                    .iter()
+                    .map(|(_, value)| match value {
+                        serde_json::Value::Null => String::new(),
+                        serde_json::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect();
+                let mut line = vec![kind];
+                line.extend(fields.iter().map(String::as_str));
+                porcelain_line(&line)
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    }
+}
+
+fn render_transfer(report: &TransferReport) -> String {
+    match (format(), report.outcome) {
+        // The object itself went to stdout, so there's nothing more we can add there without corrupting it
+        (_, TransferOutcome::Streamed) => String::new(),
+        (OutputFormat::Human, _) => report.to_string(),
+        (OutputFormat::Json, _) => serde_json::to_string(report).unwrap_or_default(),
+        (OutputFormat::Porcelain, outcome) => {
+            let outcome = serde_json::to_value(outcome).unwrap_or_default();
+            porcelain_line(&[
+                outcome.as_str().unwrap_or_default(),
+                &report.source,
+                &report.destination,
+            ])
+        }
+    }
+}
+
+// A plain listing. For people, prefixes already stand out with their trailing `/`, and archived objects get their
+// storage class after the name, since they can't be downloaded as they are. Scripts get the bare names.
+fn render_names(entries: &[ObjectEntry]) -> String {
+    match format() {
+        OutputFormat::Human if entries.is_empty() => String::from(NO_FILES_MESSAGE),
+        OutputFormat::Human => entries
+            .iter()
+            .map(|entry| match &entry.storage_class {
+                Some(class) if entry.is_archived() => format!("{}  [{}]", entry.name, class),
+                _ => entry.name.clone(),
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        _ => {
+            let names: Vec<String> = entries.iter().map(|entry| entry.name.clone()).collect();
+            render_listing(&names, NO_FILES_MESSAGE)
+        }
+    }
+}
+
+/// An exact time for scripts and manifests, as an ISO 8601 timestamp in UTC to the second
+pub fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// "just now", "5 minutes ago", and so on up to "6 days ago", then the (local) date. Times in the future, which a skewed
+// clock can produce, get the date too.
+fn format_relative_time(timestamp: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let age = now.signed_duration_since(timestamp);
+    let ago = |count: i64, unit: &str| match count {
+        1 => format!("1 {} ago", unit),
+        _ => format!("{} {}s ago", count, unit),
+    };
+    if age < chrono::Duration::zero() || age >= chrono::Duration::weeks(1) {
+        timestamp
+            .with_timezone(&Local)
+            .format("%Y-%m-%d")
+            .to_string()
+    } else if age < chrono::Duration::minutes(1) {
+        String::from("just now")
+    } else if age < chrono::Duration::hours(1) {
+        ago(age.num_minutes(), "minute")
+    } else if age < chrono::Duration::days(1) {
+        ago(age.num_hours(), "hour")
+    } else {
+        ago(age.num_days(), "day")
+    }
+}
+
+// Like `ls -l`, but with the storage class in place of the permissions. Prefixes are marked the way the AWS CLI marks
+// them, with PRE where the size would be.
+fn render_objects(entries: &[ObjectEntry], time_style: TimeStyle) -> String {
+    match format() {
+        OutputFormat::Human => {
+            let now = Utc::now();
+            let mut table = Table::new().align(0, Align::Right);
+            for entry in entries {
+                let size = match entry.size {
+                    _ if entry.is_prefix => String::from("PRE"),
+                    Some(size) => size.to_string(),
+                    None => String::new(),
+                };
+                let last_modified = entry
+                    .last_modified
+                    .map(|timestamp| match time_style {
+                        TimeStyle::Relative => format_relative_time(timestamp, now),
+                        TimeStyle::Full => format_timestamp(timestamp),
+                    })
+                    .unwrap_or_default();
+                table.push(vec![
+                    size,
+                    last_modified,
+                    entry.storage_class.clone().unwrap_or_default(),
+                    entry.name.clone(),
+                ]);
+            }
+            table.render().join("\n")
+        }
+        OutputFormat::Json => json!({
+            "objects": entries
+                .iter()
+                .map(|entry| json!({
+                    "name": entry.name,
+                    "is_prefix": entry.is_prefix,
+                    "size": entry.size,
+                    "last_modified": entry.last_modified.map(format_timestamp),
+                    "etag": entry.etag,
+                    "storage_class": entry.storage_class,
+                }))
+                .collect::<Vec<serde_json::Value>>()
+        })
+        .to_string(),
+        OutputFormat::Porcelain => entries
+            .iter()
+            .map(|entry| {
+                if entry.is_prefix {
+                    return porcelain_line(&["prefix", &entry.name]);
+                }
+                porcelain_line(&[
+                    "object",
+                    &entry.name,
+                    &entry.size.map(|size| size.to_string()).unwrap_or_default(),
+                    &entry
+                        .last_modified
+                        .map(format_timestamp)
+                        .unwrap_or_default(),
+                    entry.etag.as_deref().unwrap_or_default(),
+                    entry.storage_class.as_deref().unwrap_or_default(),
+                ])
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    }
+}
+
+// Fields with commas, quotes, or line breaks in them are quoted, with any quotes inside doubled
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+fn render_csv(entries: &[ObjectEntry]) -> String {
+    let mut records = vec![String::from("key,size,last_modified,etag,storage_class")];
+    records.extend(entries.iter().map(|entry| {
+        [
+            csv_field(&entry.name),
+            entry.size.map(|size| size.to_string()).unwrap_or_default(),
+            entry
+                .last_modified
+                .map(format_timestamp)
+                .unwrap_or_default(),
+            csv_field(entry.etag.as_deref().unwrap_or_default()),
+            csv_field(entry.storage_class.as_deref().unwrap_or_default()),
+        ]
+        .join(",")
+    }));
+    // RFC 4180 records end with CRLF, the last one included
+    records
+        .iter()
+        .map(|record| record.clone() + "\r\n")
+        .collect()
+}
+
+/// Prints an error that we're reporting to the user rather than crashing over. `message` is the human-friendly
+/// description; in JSON mode it's included alongside the error kind and the exit code it would map to.
+pub fn print_error(e: &RBError, message: &str) {
+    match format() {
+        OutputFormat::Human => eprintln!("{}", message),
+        OutputFormat::Json => println!(
+            "{}",
+            json!({
+                "error": {
+                    "kind": format!("{:?}", e.kind()),
+                    "code": e.exit_code(),
+                    "message": message,
+                }
+            })
+        ),
+        OutputFormat::Porcelain => println!(
+            "{}",
+            porcelain_line(&[
+                "error",
+                &format!("{:?}", e.kind()),
+                &e.exit_code().to_string(),
+                message,
+            ])
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_csv_record_ends_in_crlf() {
+        let output = CommandOutput::Objects {
+            entries: vec![ObjectEntry {
+                name: String::from("reports/a,b.csv"),
+                is_prefix: false,
+                size: Some(42),
+                last_modified: None,
+                etag: Some(String::from("abc")),
+                storage_class: Some(String::from("STANDARD")),
+            }],
+            format: ListingFormat::Csv,
+            time_style: TimeStyle::Relative,
+        };
+        assert_eq!(
+            output.render_text(),
+            "key,size,last_modified,etag,storage_class\r\n\"reports/a,b.csv\",42,,abc,STANDARD\r\n"
+        );
+    }
+}
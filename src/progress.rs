@@ -0,0 +1,234 @@
+use crate::error::RBError;
+use crate::output::is_quiet;
+
+use std::future::Future;
+use std::io::{stderr, IsTerminal, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+// How often we redraw the progress line, and also how often we take a throughput sample
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+// Weight given to the newest throughput sample in the smoothed average. Lower is smoother but slower to react.
+const SMOOTHING_FACTOR: f64 = 0.3;
+
+const MEGABYTE: f64 = 1024.0 * 1024.0;
+
+/// Gets told about transfers as they happen, for programs that use rustbucket as a library and want to show progress
+/// their own way. Every method does nothing by default, so implementations only need the ones they care about.
+pub trait TransferObserver: Send + Sync {
+    /// Another `count` bytes of the object at `key` have been downloaded or uploaded
+    fn bytes_transferred(&self, _key: &str, _count: u64) {}
+    /// The object at `key` has been transferred in full, `total_bytes` in all
+    fn transfer_finished(&self, _key: &str, _total_bytes: u64) {}
+    /// A command failed with a transient error and is about to be run again, as attempt number `attempt`
+    fn retry_occurred(&self, _attempt: u32, _error: &RBError) {}
+}
+
+tokio::task_local! {
+    // For the same reason output's IN_BACKGROUND is a task-local: otherwise the observer would have to be passed down
+    // through every command and backend function that might end up transferring something
+    static OBSERVER: Arc<dyn TransferObserver>;
+}
+
+/// Runs a task with `observer` told about every transfer (and retry) that happens in it
+pub async fn observe_transfers<F: Future>(
+    observer: Arc<dyn TransferObserver>,
+    task: F,
+) -> F::Output {
+    OBSERVER.scope(observer, task).await
+}
+
+/// The observer that the current task is running under, if there is one
+pub fn current_observer() -> Option<Arc<dyn TransferObserver>> {
+    OBSERVER.try_with(Arc::clone).ok()
+}
+
+/// Tracks how far along a single transfer is and periodically prints its throughput and ETA to stderr
+pub struct TransferProgress {
+    label: String,
+    total_bytes: Option<u64>,
+    transferred_bytes: u64,
+    started_at: Instant,
+    last_sample_at: Instant,
+    last_sample_bytes: u64,
+    current_rate: f64,
+    smoothed_rate: Option<f64>,
+    enabled: bool,
+    finished: bool,
+    observer: Option<Arc<dyn TransferObserver>>,
+}
+
+impl TransferProgress {
+    pub fn new(label: String, total_bytes: Option<u64>) -> Self {
+        let now = Instant::now();
+        TransferProgress {
+            label,
+            total_bytes,
+            transferred_bytes: 0,
+            started_at: now,
+            last_sample_at: now,
+            last_sample_bytes: 0,
+            current_rate: 0.0,
+            smoothed_rate: None,
+            // A constantly-redrawn line is only useful to a human; don't litter logs and pipes with it
+            enabled: stderr().is_terminal() && !is_quiet(),
+            finished: false,
+            observer: current_observer(),
+        }
+    }
+
+    pub fn add_bytes(&mut self, count: u64) {
+        self.transferred_bytes += count;
+        if let Some(observer) = &self.observer {
+            observer.bytes_transferred(&self.label, count);
+        }
+
+        let now = Instant::now();
+        let since_last_sample = now.duration_since(self.last_sample_at);
+        if since_last_sample >= REPORT_INTERVAL {
+            let sample_bytes = self.transferred_bytes - self.last_sample_bytes;
+            self.current_rate = sample_bytes as f64 / since_last_sample.as_secs_f64();
+            self.smoothed_rate = Some(match self.smoothed_rate {
+                Some(rate) => {
+                    SMOOTHING_FACTOR * self.current_rate + (1.0 - SMOOTHING_FACTOR) * rate
+                }
+                None => self.current_rate,
+            });
+            self.last_sample_at = now;
+            self.last_sample_bytes = self.transferred_bytes;
+            self.render();
+        }
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        let total = self.total_bytes?;
+        let rate = self.smoothed_rate.filter(|rate| *rate > 0.0)?;
+        let remaining = total.saturating_sub(self.transferred_bytes);
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+
+    fn render(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        let amount = match self.total_bytes {
+            Some(total) if total > 0 => format!(
+                "{:.1}/{:.1} MB ({:.0}%)",
+                self.transferred_bytes as f64 / MEGABYTE,
+                total as f64 / MEGABYTE,
+                100.0 * self.transferred_bytes as f64 / total as f64
+            ),
+            _ => format!("{:.1} MB", self.transferred_bytes as f64 / MEGABYTE),
+        };
+        let eta = self
+            .eta()
+            .map_or_else(|| String::from("--:--"), format_duration);
+
+        eprint!(
+            "\r{}: {}  {:.2} MB/s (avg {:.2} MB/s)  ETA {}  ",
+            self.label,
+            amount,
+            self.current_rate / MEGABYTE,
+            self.smoothed_rate.unwrap_or(0.0) / MEGABYTE,
+            eta
+        );
+        let _ = stderr().flush();
+    }
+
+    /// Prints the final summary line for this transfer, ending the line that `render` has been redrawing. Only the
+    /// first call does anything.
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if let Some(observer) = &self.observer {
+            observer.transfer_finished(&self.label, self.transferred_bytes);
+        }
+        if !self.enabled {
+            return;
+        }
+
+        let elapsed = self.started_at.elapsed();
+        let average_rate = if elapsed.as_secs_f64() > 0.0 {
+            self.transferred_bytes as f64 / elapsed.as_secs_f64()
+        } else {
+            0.0
+        };
+        eprintln!(
+            "\r{}: {:.1} MB in {} ({:.2} MB/s average){}",
+            self.label,
+            self.transferred_bytes as f64 / MEGABYTE,
+            format_duration(elapsed),
+            average_rate / MEGABYTE,
+            " ".repeat(20) // Blank out any leftovers from the longer in-progress line
+        );
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// The summary line for `time`: how long a command took and, if it transferred anything, how fast
+pub fn format_timing(elapsed: Duration, objects: u64, bytes: u64) -> String {
+    let secs = elapsed.as_secs_f64();
+    let mut summary = format!("Elapsed: {:.2}s", secs);
+    if objects > 0 && secs > 0.0 {
+        summary.push_str(&format!(
+            ", {} object(s) at {:.2}/s, {:.1} MB at {:.2} MB/s",
+            objects,
+            objects as f64 / secs,
+            bytes as f64 / MEGABYTE,
+            bytes as f64 / MEGABYTE / secs
+        ));
+    }
+    summary
+}
+
+/// Wraps an `AsyncRead` so that everything read through it is counted towards a `TransferProgress`. The progress is
+/// finished automatically when the inner reader hits EOF.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: TransferProgress,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, progress: TransferProgress) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R> AsyncRead for ProgressReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let had_room = buf.remaining() > 0;
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read_count = buf.filled().len() - filled_before;
+            if read_count == 0 && had_room {
+                self.progress.finish();
+            } else {
+                self.progress.add_bytes(read_count as u64);
+            }
+        }
+        poll
+    }
+}
@@ -0,0 +1,136 @@
+use crate::backend::StorageBackend;
+use crate::commands::resolve_remote_path;
+use crate::output;
+use crate::{describe_error, run_transfer, Command};
+
+use std::path::PathBuf;
+
+use futures::stream::{self, StreamExt};
+
+enum QueueEntryState {
+    Pending,
+    Done,
+    Failed(String),
+}
+
+// A transfer waiting in the queue. The working directories are the ones from when it was added, so that its relative
+// paths still mean the same thing however much the user has moved around since.
+struct QueueEntry {
+    command: Command,
+    description: String,
+    remote_cwd: PathBuf,
+    local_cwd: PathBuf,
+    state: QueueEntryState,
+}
+
+impl QueueEntry {
+    fn status_line(&self, position: usize) -> String {
+        match &self.state {
+            QueueEntryState::Pending => format!("{}. Pending  {}", position, self.description),
+            QueueEntryState::Done => format!("{}. Done     {}", position, self.description),
+            QueueEntryState::Failed(reason) => {
+                format!("{}. Failed   {}: {}", position, self.description, reason)
+            }
+        }
+    }
+}
+
+/// Transfers that have been lined up with `queue add`, to be run together with `queue start`
+#[derive(Default)]
+pub struct TransferQueue {
+    entries: Vec<QueueEntry>,
+}
+
+impl TransferQueue {
+    pub fn add(
+        &mut self,
+        command: Command,
+        description: String,
+        remote_cwd: PathBuf,
+        local_cwd: PathBuf,
+    ) {
+        self.entries.push(QueueEntry {
+            command,
+            description,
+            remote_cwd,
+            local_cwd,
+            state: QueueEntryState::Pending,
+        });
+    }
+
+    /// The (absolute) remote paths that the transfers in the queue change
+    pub fn changed_remote_paths(&self) -> Vec<PathBuf> {
+        self.entries
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .command
+                    .changed_remote_paths()
+                    .into_iter()
+                    .map(move |path| resolve_remote_path(&entry.remote_cwd, path))
+            })
+            .collect()
+    }
+
+    /// Forgets every transfer in the queue, whether it has run or not
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// A status line for every transfer in the queue, numbered from 1
+    pub fn status_lines(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| entry.status_line(index + 1))
+            .collect()
+    }
+
+    /// Runs every transfer in the queue that hasn't succeeded yet, up to `parallel` of them at a time, and returns how
+    /// many of those succeeded and how many failed. Each one's status is updated (and printed) as soon as it finishes,
+    /// so that if the run is cancelled partway through, the next `queue start` picks up where this one left off.
+    pub async fn run(&mut self, s3: &dyn StorageBackend, parallel: usize) -> (usize, usize) {
+        let to_run: Vec<(usize, Command, PathBuf, PathBuf)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !matches!(entry.state, QueueEntryState::Done))
+            .map(|(index, entry)| {
+                (
+                    index,
+                    entry.command.clone(),
+                    entry.remote_cwd.clone(),
+                    entry.local_cwd.clone(),
+                )
+            })
+            .collect();
+
+        // Several progress lines redrawing over each other would be unreadable, so the transfers run quietly and we
+        // report each one as it finishes instead
+        let mut finished = stream::iter(to_run)
+            .map(|(index, command, remote_cwd, local_cwd)| async move {
+                let result =
+                    output::in_background(run_transfer(s3, &remote_cwd, &local_cwd, &command))
+                        .await;
+                (index, result)
+            })
+            .buffer_unordered(parallel.max(1));
+
+        let (mut succeeded, mut failed) = (0, 0);
+        while let Some((index, result)) = finished.next().await {
+            let entry = &mut self.entries[index];
+            entry.state = match result {
+                Ok(_) => {
+                    succeeded += 1;
+                    QueueEntryState::Done
+                }
+                Err(e) => {
+                    failed += 1;
+                    QueueEntryState::Failed(describe_error(&e))
+                }
+            };
+            status!("{}", entry.status_line(index + 1));
+        }
+        (succeeded, failed)
+    }
+}
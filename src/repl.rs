@@ -0,0 +1,116 @@
+use crate::completion::RBHelper;
+use crate::error::RBError;
+
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use tokio::task::spawn_blocking;
+
+// How many new prompt history entries to collect before saving them
+const HISTORY_SAVE_INTERVAL: usize = 10;
+
+/// Where an interactive session gets its input. The binary reads from the terminal with rustyline, but a program
+/// embedding a session (a test, a TUI, a shell served over SSH...) can supply lines however it likes. Background jobs
+/// keep running while a line is awaited, so a reader that has to block should do it off the runtime's threads.
+#[async_trait]
+pub trait LineReader: Send {
+    /// Shows `prompt` and reads the next command line. `Ok(None)` means the input has run out, or that the user has
+    /// asked to leave (e.g. with Ctrl-D), and ends the session.
+    async fn read_command(&mut self, prompt: &str) -> Result<Option<String>, RBError>;
+
+    /// Asks a question, like whether to overwrite a file, and reads the answer. `None` counts as "no".
+    async fn read_answer(&mut self, prompt: &str) -> Option<String>;
+}
+
+/// The terminal prompt, with tab completion and history kept between sessions
+pub struct RustylineReader {
+    // Only None while a line is being read on a blocking thread
+    editor: Option<Editor<RBHelper>>,
+    history_path: Option<PathBuf>,
+    unsaved_history_entries: usize,
+}
+
+impl RustylineReader {
+    /// Wraps an editor that's already been configured and has had its history loaded. The history is saved back to
+    /// `history_path` (if there is one) every so often, and when `save_history` is called.
+    pub fn new(editor: Editor<RBHelper>, history_path: Option<PathBuf>) -> Self {
+        RustylineReader {
+            editor: Some(editor),
+            history_path,
+            unsaved_history_entries: 0,
+        }
+    }
+
+    pub fn helper_mut(&mut self) -> Option<&mut RBHelper> {
+        self.editor.as_mut()?.helper_mut()
+    }
+
+    pub fn save_history(&mut self) {
+        let history_path = match &self.history_path {
+            Some(history_path) => history_path,
+            None => return,
+        };
+        if let Some(history_dir) = history_path.parent() {
+            if let Err(e) = create_dir_all(history_dir) {
+                eprintln!("Error trying to save interactive prompt history: {}", e);
+                return;
+            }
+        }
+        if let Some(editor) = &mut self.editor {
+            if let Err(e) = editor.save_history(history_path) {
+                eprintln!("Error trying to save interactive prompt history: {}", e);
+            }
+        }
+        self.unsaved_history_entries = 0;
+    }
+
+    // rustyline blocks until the user finishes the line, so it waits on a blocking thread, where tab completion can
+    // still reach the runtime
+    async fn readline(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+        let mut editor = self
+            .editor
+            .take()
+            .expect("only one line can be read at a time");
+        let prompt = prompt.to_owned();
+        let (editor, result) = spawn_blocking(move || {
+            let result = editor.readline(&prompt);
+            (editor, result)
+        })
+        .await
+        .expect("reading a line panicked");
+        self.editor = Some(editor);
+        result
+    }
+}
+
+#[async_trait]
+impl LineReader for RustylineReader {
+    async fn read_command(&mut self, prompt: &str) -> Result<Option<String>, RBError> {
+        match self.readline(prompt).await {
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(RBError::wrap_io(e)),
+            Ok(line) => {
+                let editor = self
+                    .editor
+                    .as_mut()
+                    .expect("the editor is back after reading");
+                if editor.add_history_entry(line.as_str()) {
+                    self.unsaved_history_entries += 1;
+                }
+                // Save every so often, rather than only on exit, so that a crash doesn't lose the whole session's worth
+                if self.unsaved_history_entries >= HISTORY_SAVE_INTERVAL {
+                    self.save_history();
+                }
+                Ok(Some(line))
+            }
+        }
+    }
+
+    // Ctrl-C, Ctrl-D, or anything else weird while answering is a "no"
+    async fn read_answer(&mut self, prompt: &str) -> Option<String> {
+        self.readline(prompt).await.ok()
+    }
+}
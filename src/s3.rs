@@ -1,13 +1,137 @@
+use crate::backend::StorageBackend;
+use crate::checksum::ChecksumAlgorithm;
+use crate::credentials::SessionCredentials;
 use crate::error::{ErrorKind, RBError};
+use crate::progress::{ProgressReader, TransferProgress};
 
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::default::Default;
+use std::error::Error;
+use std::fmt;
 use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::Duration;
 
-use rusoto_core::ByteStream;
-use rusoto_s3::{GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use hyper::client::HttpConnector;
+#[cfg(feature = "rustls")]
+use hyper_rustls::HttpsConnector;
+#[cfg(feature = "native-tls")]
+use hyper_tls::HttpsConnector;
+use rusoto_core::credential::{
+    Anonymous, AwsCredentials, DefaultCredentialsProvider, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::event_stream::{DeserializeEvent, EventStream};
+use rusoto_core::param::{Params, ServiceParams};
+use rusoto_core::region::ParseRegionError;
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{ByteStream, Client, HttpClient, Region, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadError, AbortMultipartUploadRequest, CompleteMultipartUploadRequest,
+    CompletedMultipartUpload, CompletedPart, CopyObjectError, CopyObjectRequest, CreateBucketError,
+    CreateMultipartUploadRequest, Delete, DeleteObjectRequest, DeleteObjectsRequest,
+    GetBucketCorsRequest, GetBucketEncryptionRequest, GetBucketLocationRequest,
+    GetBucketVersioningRequest, GetObjectAclError, GetObjectAclRequest, GetObjectError,
+    GetObjectLegalHoldRequest, GetObjectRequest, GetObjectRetentionRequest,
+    GetObjectTaggingRequest, GlacierJobParameters, Grantee, HeadBucketError, HeadObjectError,
+    HeadObjectOutput, HeadObjectRequest, ListMultipartUploadsRequest, ListObjectVersionsRequest,
+    ListObjectsError, ListObjectsV2Error, ListObjectsV2Request, ObjectIdentifier,
+    PutBucketVersioningRequest, PutObjectAclError, PutObjectAclRequest, PutObjectError,
+    PutObjectRequest, PutObjectTaggingRequest, RestoreObjectError, RestoreObjectRequest,
+    RestoreRequest, S3Client, SelectObjectContentError, Tag, Tagging, UploadPartCopyRequest,
+    VersioningConfiguration, S3,
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
 use tokio::{fs::File, io};
 use tokio_util::io::ReaderStream;
+use tracing::debug;
+
+/// An S3 access point, which stands in for a bucket name wherever one is expected. Access point aliases already look
+/// like bucket names, but ARNs (`arn:aws:s3:REGION:ACCOUNT:accesspoint/NAME`) have a slash of their own, so in remote
+/// paths they're spelled with a colon there instead, to keep the whole ARN in the path's first component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessPointArn {
+    pub partition: String,
+    pub region: String,
+    pub account: String,
+    pub name: String,
+}
+
+impl AccessPointArn {
+    /// Splits a path that starts with an access point ARN into the ARN and whatever follows it. Either a slash or a
+    /// colon may separate `accesspoint` from the access point's name.
+    pub fn split_path(path: &str) -> Option<(Self, &str)> {
+        let mut fields = path.splitn(6, ':');
+        if fields.next()? != "arn" {
+            return None;
+        }
+        let partition = fields.next()?;
+        let service = fields.next()?;
+        let region = fields.next()?;
+        let account = fields.next()?;
+        let resource = fields.next()?;
+        if service != "s3" || region.is_empty() || account.is_empty() {
+            return None;
+        }
+        let name_and_rest = resource
+            .strip_prefix("accesspoint/")
+            .or_else(|| resource.strip_prefix("accesspoint:"))?;
+        let (name, rest) = match name_and_rest.find('/') {
+            Some(slash_index) => (
+                &name_and_rest[..slash_index],
+                &name_and_rest[slash_index + 1..],
+            ),
+            None => (name_and_rest, ""),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        Some((
+            AccessPointArn {
+                partition: partition.to_owned(),
+                region: region.to_owned(),
+                account: account.to_owned(),
+                name: name.to_owned(),
+            },
+            rest,
+        ))
+    }
+
+    /// The ARN as AWS writes it
+    pub fn arn(&self) -> String {
+        format!(
+            "arn:{}:s3:{}:{}:accesspoint/{}",
+            self.partition, self.region, self.account, self.name
+        )
+    }
+
+    /// The ARN as it appears in remote paths, in place of a bucket name
+    pub fn path_component(&self) -> String {
+        format!(
+            "arn:{}:s3:{}:{}:accesspoint:{}",
+            self.partition, self.region, self.account, self.name
+        )
+    }
+
+    /// Access points can only be addressed by hostname, never by path like buckets can
+    pub fn hostname(&self) -> String {
+        let domain = if self.partition == "aws-cn" {
+            "amazonaws.com.cn"
+        } else {
+            "amazonaws.com"
+        };
+        format!(
+            "{}-{}.s3-accesspoint.{}.{}",
+            self.name, self.account, self.region, domain
+        )
+    }
+}
 
 pub struct S3Path {
     pub bucket: Option<String>,
@@ -54,8 +178,8 @@ impl S3Path {
                     Some(key_str.to_owned())
                 };
 
-                println!(
-                    "Debug: generated S3Path with bucket {:?} and key {:?}",
+                debug!(
+                    "generated S3Path with bucket {:?} and key {:?}",
                     bucket, key
                 );
                 Ok(Self { bucket, key })
@@ -64,200 +188,2886 @@ impl S3Path {
     }
 }
 
-pub struct RBS3 {
-    client: S3Client,
+/// The parts of an object's (or local file's) metadata that we use to compare transfer sources with their destinations
+pub struct ObjectHead {
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// Only archived objects that have been asked to be restored have a restore status
+    pub restore: Option<RestoreStatus>,
+    /// User metadata (`x-amz-meta-*`), by key without the prefix
+    pub metadata: HashMap<String, String>,
 }
 
-impl RBS3 {
-    pub fn new() -> Self {
-        RBS3 {
-            client: S3Client::new(Default::default()),
+/// How far along the restore of an archived (Glacier or Deep Archive) object is
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreStatus {
+    /// S3 is still bringing the object back, which takes anywhere from minutes to hours depending on the tier
+    InProgress,
+    /// The restored copy can be downloaded, until it expires
+    Ready { expiry: Option<DateTime<Utc>> },
+}
+
+impl RestoreStatus {
+    /// Parses HeadObject's `x-amz-restore` header, which looks like `ongoing-request="true"` or
+    /// `ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT"`
+    fn from_header(header: &str) -> Option<Self> {
+        if header.contains("ongoing-request=\"true\"") {
+            return Some(RestoreStatus::InProgress);
         }
+        if !header.contains("ongoing-request=\"false\"") {
+            return None;
+        }
+        // The date has a comma of its own, so it's picked out by its quotes rather than by splitting on commas
+        let expiry = header
+            .split("expiry-date=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+            .map(|date| date.with_timezone(&Utc));
+        Some(RestoreStatus::Ready { expiry })
     }
+}
 
-    pub async fn list_buckets(&self) -> Result<Vec<String>, RBError> {
-        let result = self.client.list_buckets().await.map_err(RBError::wrap_s3)?;
+/// One entry in a listing of a bucket "directory": either an object, or a prefix that groups more of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectEntry {
+    /// Relative to the listed prefix. Prefixes end in `/`.
+    pub name: String,
+    pub is_prefix: bool,
+    /// Only objects have a size, modification time, ETag, and storage class
+    pub size: Option<u64>,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// Without the quotes that S3 puts around it
+    pub etag: Option<String>,
+    pub storage_class: Option<String>,
+}
 
-        let buckets: Vec<String> = result
-            .buckets
-            .unwrap_or(vec![])
-            .into_iter()
-            .filter_map(|bucket| bucket.name)
-            .collect();
+/// A multipart upload that was started but never completed or aborted. S3 keeps (and bills for) its parts until it's
+/// aborted, and they don't show up in any object listing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncompleteUpload {
+    pub key: String,
+    pub upload_id: String,
+    pub initiated: Option<DateTime<Utc>>,
+}
 
-        Ok(buckets)
+/// A key that a batch delete couldn't delete, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteFailure {
+    pub key: String,
+    /// The kind of error it would have been if the key had been deleted on its own
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+/// One version of an object in a versioned bucket. A delete marker is the version that deleting an object leaves in its
+/// place: while it's the latest version, the object looks deleted, and removing it brings the object back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionEntry {
+    pub key: String,
+    /// `null` for the version an object had before versioning was turned on
+    pub version_id: String,
+    pub is_latest: bool,
+    pub is_delete_marker: bool,
+    /// Delete markers have no size
+    pub size: Option<u64>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// An object's Object Lock settings. A locked object can't be deleted or overwritten until its retention period is up,
+/// or while it's under a legal hold.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectLockStatus {
+    /// GOVERNANCE or COMPLIANCE, if the object has a retention period
+    pub retention_mode: Option<String>,
+    pub retain_until: Option<DateTime<Utc>>,
+    pub legal_hold: bool,
+}
+
+/// How a bucket encrypts objects when the request that writes them doesn't ask for anything in particular
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketEncryption {
+    /// `AES256` for SSE-S3, or `aws:kms` for SSE-KMS
+    pub algorithm: String,
+    /// For SSE-KMS, the key's ID or ARN, if it isn't the AWS managed key
+    pub kms_key_id: Option<String>,
+    /// Whether SSE-KMS uses an S3 Bucket Key, which cuts down on requests to KMS
+    pub bucket_key_enabled: bool,
+}
+
+/// How many objects there are under a prefix, and how many bytes they take up all together
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// One entry of an object's access control list: who may do what with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectGrant {
+    /// An account's display name, ID, or email address, or the name of a predefined group like `AllUsers`
+    pub grantee: String,
+    /// `CanonicalUser`, `AmazonCustomerByEmail`, or `Group`
+    pub grantee_type: String,
+    /// `READ`, `WRITE`, `READ_ACP`, `WRITE_ACP`, or `FULL_CONTROL`
+    pub permission: String,
+}
+
+impl ObjectGrant {
+    /// Whether the grant is to anyone at all, or to anyone with an AWS account, which is rarely on purpose
+    pub fn is_public(&self) -> bool {
+        self.grantee_type == "Group"
+            && (self.grantee == "AllUsers" || self.grantee == "AuthenticatedUsers")
     }
+}
 
-    pub async fn list_files(
-        &self,
-        bucket: String,
-        prefix: Option<String>,
-    ) -> Result<Vec<String>, RBError> {
-        println!(
-            "Debug: listing files at bucket {}, prefix {}",
-            bucket,
-            prefix.as_ref().unwrap_or(&String::from("<no prefix>"))
-        );
-        let mut params = ListObjectsV2Request {
-            bucket,
-            prefix: prefix.clone(),
-            delimiter: Some(String::from("/")),
-            ..Default::default()
-        };
+/// The canned ACLs that can be given to an object, by the names S3's API uses
+pub const CANNED_ACLS: [&str; 7] = [
+    "private",
+    "public-read",
+    "public-read-write",
+    "authenticated-read",
+    "aws-exec-read",
+    "bucket-owner-read",
+    "bucket-owner-full-control",
+];
 
-        let mut results: Vec<String> = Vec::new();
-        let mut files: Vec<String> = Vec::new();
+/// One of a bucket's CORS rules: which cross-origin requests browsers are allowed to make to it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorsRule {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers that the browser may send
+    pub allowed_headers: Vec<String>,
+    /// Response headers that scripts may read
+    pub expose_headers: Vec<String>,
+    /// How long browsers may cache the answer to a preflight request
+    pub max_age_seconds: Option<i64>,
+}
 
-        loop {
-            let output = self
-                .client
-                .list_objects_v2(params.clone())
-                .await
-                .map_err(RBError::wrap_s3)?;
+/// Every storage class that S3 knows, by the names its API uses
+pub const STORAGE_CLASSES: [&str; 9] = [
+    "STANDARD",
+    "REDUCED_REDUNDANCY",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+    "OUTPOSTS",
+    "GLACIER_IR",
+];
 
-            if let Some(prefixes) = output.common_prefixes {
-                results.extend(
-                    prefixes
-                        .into_iter()
-                        .filter_map(|object| object.prefix)
-                        .filter_map(|common_prefix| {
-                            if let Some(pfx_str) = prefix.as_ref().map(|pfx| pfx.as_str()) {
-                                common_prefix
-                                    .strip_prefix(pfx_str)
-                                    .map(|cleaned_str| cleaned_str.to_owned())
-                            } else {
-                                Some(common_prefix)
-                            }
-                        }),
-                );
-            }
+/// How quickly (and expensively) S3 restores an archived object, by the names its API uses. Deep Archive doesn't do
+/// Expedited.
+pub const RESTORE_TIERS: [&str; 3] = ["Bulk", "Standard", "Expedited"];
 
-            if let Some(objects) = output.contents {
-                files.extend(
-                    objects
-                        .into_iter()
-                        .filter_map(|object| object.key)
-                        .filter_map(|key| {
-                            if let Some(pfx_str) = prefix.as_ref().map(|pfx| pfx.as_str()) {
-                                key.strip_prefix(pfx_str).and_then(|key_no_prefix| {
-                                    if !key_no_prefix.contains('/') {
-                                        Some(key_no_prefix.to_owned())
-                                    } else {
-                                        None
-                                    }
-                                })
-                            } else if !key.contains('/') {
-                                Some(key)
-                            } else {
-                                None
-                            }
-                        }),
-                );
-            };
+// Objects in these storage classes have to be restored before they can be downloaded
+const ARCHIVE_STORAGE_CLASSES: &[&str] = &["GLACIER", "DEEP_ARCHIVE"];
 
-            // It's convenient to not use `if let Some()` here because params.continuation_token is also an Option
-            if output.next_continuation_token.is_some() {
-                params.continuation_token = output.next_continuation_token;
-            // don't break; we'll loop and call the s3 function again with the new continuation token
-            } else {
-                break;
-            }
+impl ObjectEntry {
+    /// Whether this is an object in an archive storage class, which can't be downloaded until it's been restored
+    pub fn is_archived(&self) -> bool {
+        self.storage_class
+            .as_deref()
+            .is_some_and(|class| ARCHIVE_STORAGE_CLASSES.contains(&class))
+    }
+}
+
+/// Optional parameters for fetching an object's body or metadata
+#[derive(Debug, Clone, Default)]
+pub struct GetOptions {
+    /// Fetch this specific version of the object rather than the latest one
+    pub version_id: Option<String>,
+    /// An HTTP Range header value like `bytes=0-1023`; only applies to fetching the body
+    pub range: Option<String>,
+}
+
+/// The formats that S3 Select can read objects in and write results out in. Parquet only works for reading.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SelectFormat {
+    /// With a header line naming the columns
+    Csv,
+    /// One or more JSON documents, one after another
+    Json,
+    Parquet,
+}
+
+impl SelectFormat {
+    /// Guesses an object's format from its key's extension, looking past a `.gz` or `.bz2` on the end
+    pub fn from_key(key: &str) -> Option<Self> {
+        let key = key.to_lowercase();
+        let key = key
+            .strip_suffix(".gz")
+            .or_else(|| key.strip_suffix(".bz2"))
+            .unwrap_or(&key);
+        match key.rsplit_once('.')?.1 {
+            "csv" => Some(SelectFormat::Csv),
+            "json" | "jsonl" | "ndjson" => Some(SelectFormat::Json),
+            "parquet" => Some(SelectFormat::Parquet),
+            _ => None,
         }
+    }
+}
 
-        // Do this stuff at the end so that all the directories appear at the top and the files at the bottom
-        results.sort_unstable();
-        files.sort_unstable();
-        results.extend(files);
+/// An S3 Select query: SQL to run against one object, and the formats it's read and written in
+#[derive(Debug, Clone)]
+pub struct SelectQuery {
+    pub expression: String,
+    pub input: SelectFormat,
+    pub output: SelectFormat,
+}
 
-        Ok(results)
+// S3 error codes that mean the request was signed with credentials that are no longer valid
+const EXPIRED_CREDENTIALS_CODES: [&str; 2] = ["ExpiredToken", "TokenRefreshRequired"];
+
+/// Everything needed to construct an `RBS3` client
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub region: Region,
+    /// Always address buckets as `https://endpoint/bucket/key` rather than `https://bucket.endpoint/key`. rusoto_s3
+    /// only ever makes path-style requests, so this only changes the requests that we build ourselves.
+    pub force_path_style: bool,
+    /// Don't sign requests at all, for browsing public buckets without any credentials
+    pub no_sign_request: bool,
+    /// Credentials to use instead of rusoto's default credential chain
+    pub credentials: Option<SessionCredentials>,
+    /// How long to wait for a TCP connection to be established
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for a response once a request has been sent
+    pub read_timeout: Option<Duration>,
+    /// Accept any TLS certificate, even self-signed or expired ones. Insecure; only for lab setups.
+    pub no_verify_ssl: bool,
+    /// Talk to the region's FIPS 140-2 validated S3 endpoint
+    pub use_fips_endpoint: bool,
+}
+
+// Rusoto only accepts a response timeout as an argument to each individual dispatch, and neither S3Client nor
+// Client::sign_and_dispatch pass one along, so this applies ours to every request that goes through it
+struct TimeoutHttpClient {
+    inner: HttpClient<HttpsConnector<HttpConnector>>,
+    read_timeout: Option<Duration>,
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!(
+    "rustbucket needs a TLS backend: enable either the native-tls or the rustls feature"
+);
+
+#[cfg(all(feature = "native-tls", feature = "rustls"))]
+compile_error!(
+    "the native-tls and rustls features can't both be enabled: build with `--no-default-features --features rustls`"
+);
+
+// The platform's TLS stack, which trusts whatever certificates the OS does (including any a company has installed)
+#[cfg(feature = "native-tls")]
+fn https_connector(
+    connector: HttpConnector,
+    config: &ClientConfig,
+) -> Result<HttpsConnector<HttpConnector>, RBError> {
+    let tls_connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(config.no_verify_ssl)
+        .build()
+        .map_err(RBError::wrap_io)?;
+    Ok(HttpsConnector::from((
+        connector,
+        tokio_native_tls::TlsConnector::from(tls_connector),
+    )))
+}
+
+// rustls needs no system libraries, so it's the one to use for static (e.g. musl) builds. It trusts the Mozilla root
+// certificates that are compiled in, not the OS's.
+#[cfg(feature = "rustls")]
+fn https_connector(
+    connector: HttpConnector,
+    config: &ClientConfig,
+) -> Result<HttpsConnector<HttpConnector>, RBError> {
+    let mut tls_config = rustls::ClientConfig::new();
+    tls_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if config.no_verify_ssl {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(std::sync::Arc::new(NoCertificateVerification));
     }
+    Ok(HttpsConnector::from((connector, tls_config)))
+}
 
-    pub async fn object_exists(&self, bucket: String, key: String) -> Result<bool, RBError> {
-        println!(
-            "Debug: Checking if file exists at bucket {}, key {}",
-            bucket, key
-        );
-        let params = ListObjectsV2Request {
-            bucket,
-            prefix: Some(key),
-            ..Default::default()
-        };
+// What --no-verify-ssl means under rustls
+#[cfg(feature = "rustls")]
+struct NoCertificateVerification;
 
-        let output = self
-            .client
-            .list_objects_v2(params.clone())
-            .await
-            .map_err(RBError::wrap_s3)?;
+#[cfg(feature = "rustls")]
+impl rustls::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+// rusoto_s3 addresses every request by path (`/bucket/key`), which access points don't accept, so requests whose
+// bucket is an access point ARN are moved over to the access point's own hostname and region here. That changes what
+// the request was signed for, so they're signed again on the way.
+struct AccessPointDispatcher<D, P> {
+    inner: Arc<D>,
+    credentials: Arc<P>,
+}
+
+impl<D, P> DispatchSignedRequest for AccessPointDispatcher<D, P>
+where
+    D: DispatchSignedRequest + Send + Sync + 'static,
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let (access_point, key) =
+            match AccessPointArn::split_path(request.path.trim_start_matches('/')) {
+                Some((access_point, key)) => (access_point, key.to_owned()),
+                None => return self.inner.dispatch(request, timeout),
+            };
+        let inner = self.inner.clone();
+        let credentials = self.credentials.clone();
+        Box::pin(async move {
+            request.region = access_point
+                .region
+                .parse()
+                .map_err(|e: ParseRegionError| HttpDispatchError::new(e.to_string()))?;
+            request.set_hostname(Some(access_point.hostname()));
+            request.path = format!("/{}", key);
+            let credentials = credentials
+                .credentials()
+                .await
+                .map_err(|e| HttpDispatchError::new(e.to_string()))?;
+            if !credentials.is_anonymous() {
+                request.sign(&credentials);
+            }
+            inner.dispatch(request, timeout).await
+        })
+    }
+}
+
+// A client whose requests are signed with `credentials` and sent through `dispatcher`, by way of the access point
+// rerouting above
+fn core_client<P, D>(credentials: P, dispatcher: D) -> Client
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
+    let credentials = Arc::new(credentials);
+    Client::new_with(
+        credentials.clone(),
+        AccessPointDispatcher {
+            inner: Arc::new(dispatcher),
+            credentials,
+        },
+    )
+}
 
-        Ok(output.key_count.map_or(false, |count| count != 0))
+impl TimeoutHttpClient {
+    fn new(config: &ClientConfig) -> Result<Self, RBError> {
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false); // The HTTPS connector wrapping this one takes care of https:// URLs
+        connector.set_connect_timeout(config.connect_timeout);
+        let https_connector = https_connector(connector, config)?;
+        Ok(TimeoutHttpClient {
+            inner: HttpClient::from_connector(https_connector),
+            read_timeout: config.read_timeout,
+        })
     }
+}
 
-    pub async fn download_object(
+impl DispatchSignedRequest for TimeoutHttpClient {
+    fn dispatch(
         &self,
-        bucket: String,
-        key: String,
-        dest_path: &Path,
-    ) -> Result<(), RBError> {
-        println!(
-            "Debug: downloading bucket {} key {} to file {:?}",
-            bucket, key, dest_path
-        );
-        let params = GetObjectRequest {
-            bucket,
-            key: key.clone(),
-            ..Default::default()
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        self.inner.dispatch(request, timeout.or(self.read_timeout))
+    }
+}
+
+/// rustbucket's S3 client. Clones are cheap and share the underlying connection pool.
+#[derive(Clone)]
+pub struct RBS3 {
+    client: S3Client,
+    // We hang onto the lower-level client too, for the few requests that rusoto_s3 can't express (like ones with
+    // headers newer than rusoto's S3 model)
+    core_client: Client,
+    config: ClientConfig,
+}
+
+/// Puts an `RBS3` together one setting at a time, starting from the default region and credential chain
+#[derive(Debug, Clone, Default)]
+pub struct RBS3Builder {
+    config: ClientConfig,
+}
+
+impl RBS3Builder {
+    pub fn region(mut self, region: Region) -> Self {
+        self.config.region = region;
+        self
+    }
+
+    /// Sends requests to `endpoint` (e.g. `http://localhost:9000` for a local MinIO) instead of AWS, signing them for
+    /// whichever region has been set so far. Setting a region afterwards goes back to AWS's endpoint for it.
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.region = Region::Custom {
+            name: self.config.region.name().to_owned(),
+            endpoint: endpoint.into(),
         };
+        self
+    }
 
-        let mut dest_file = File::create(dest_path).await.map_err(RBError::wrap_io)?;
+    pub fn credentials(mut self, credentials: SessionCredentials) -> Self {
+        self.config.credentials = Some(credentials);
+        self
+    }
 
-        let object = self
-            .client
-            .get_object(params)
-            .await
-            .map_err(RBError::wrap_s3)?;
+    /// Don't sign requests at all, for browsing public buckets
+    pub fn anonymous(mut self) -> Self {
+        self.config.no_sign_request = true;
+        self
+    }
 
-        if let Some(body) = object.body {
-            let mut object_stream = body.into_async_read();
-            io::copy(&mut object_stream, &mut dest_file)
-                .await
-                .map_err(RBError::wrap_io)?;
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
 
-            Ok(())
-        } else {
-            eprintln!("Object at key {} has no body!", key);
-            Err(RBError::new(ErrorKind::S3))
+    /// How long to wait for a response once a request has been sent
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn force_path_style(mut self, force_path_style: bool) -> Self {
+        self.config.force_path_style = force_path_style;
+        self
+    }
+
+    /// Accept any TLS certificate. Insecure; only for lab setups.
+    pub fn no_verify_ssl(mut self, no_verify_ssl: bool) -> Self {
+        self.config.no_verify_ssl = no_verify_ssl;
+        self
+    }
+
+    pub fn use_fips_endpoint(mut self, use_fips_endpoint: bool) -> Self {
+        self.config.use_fips_endpoint = use_fips_endpoint;
+        self
+    }
+
+    pub fn build(self) -> Result<RBS3, RBError> {
+        RBS3::new(self.config)
+    }
+}
+
+// CopyObject can only copy objects up to 5 GiB. Bigger ones have to be copied a part at a time.
+const MAX_COPY_OBJECT_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+// Parts of a multipart copy are this big, or bigger when that's what it takes to stay within S3's limit on parts
+const COPY_PART_SIZE: i64 = 512 * 1024 * 1024;
+const MAX_PART_COUNT: i64 = 10_000;
+
+// What a copy changes about an object along the way. Whatever's left as None is copied as it was.
+#[derive(Debug, Default)]
+struct CopyChanges {
+    storage_class: Option<String>,
+    // Replaces all of the user metadata
+    metadata: Option<HashMap<String, String>>,
+    content_type: Option<String>,
+}
+
+// An object's ACL as the values of the x-amz-grant-* headers, each a comma-separated list of grantees. Objects can't
+// be granted WRITE, so there's no header for it.
+#[derive(Debug, Default)]
+struct ObjectGrants {
+    full_control: Option<String>,
+    read: Option<String>,
+    read_acp: Option<String>,
+    write_acp: Option<String>,
+}
+
+// The most keys that one DeleteObjects request can delete
+const DELETE_OBJECTS_BATCH_SIZE: usize = 1000;
+
+// S3 error codes that mean we're being asked to slow down, even when they don't come with a 503
+const THROTTLING_CODES: [&str; 2] = ["SlowDown", "RequestLimitExceeded"];
+// S3 error codes for each of the finer-grained kinds of failure, for when the HTTP status alone doesn't say
+const NOT_FOUND_CODES: [&str; 3] = ["NoSuchBucket", "NoSuchKey", "NoSuchVersion"];
+const ACCESS_DENIED_CODES: [&str; 4] = [
+    "AccessDenied",
+    "AllAccessDisabled",
+    "InvalidAccessKeyId",
+    "SignatureDoesNotMatch",
+];
+const CONFLICT_CODES: [&str; 3] = [
+    "BucketAlreadyExists",
+    "OperationAborted",
+    "PreconditionFailed",
+];
+
+/// A failure that S3 itself reported, identified by its error code (like `NoSuchBucket`). It displays as advice on what
+/// to do about it, rather than as the raw response.
+#[derive(Debug)]
+pub struct S3ServiceError {
+    pub code: String,
+    pub message: String,
+    /// Where the bucket actually is, when S3 says it's in a different region than the one we asked
+    pub bucket_region: Option<String>,
+    source_error: Box<dyn Error + Send + Sync + 'static>,
+}
+
+impl fmt::Display for S3ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code.as_str() {
+            "NoSuchBucket" => write!(
+                f,
+                "There's no such bucket. Check its name, or use `cd /` and `ls` to see the buckets you can reach."
+            ),
+            "NoSuchKey" => write!(f, "There's no object at that key."),
+            "AccessDenied" => write!(
+                f,
+                "Access denied. Your credentials aren't allowed to do that here; check your IAM permissions and the \
+                 bucket's policy."
+            ),
+            "SlowDown" => write!(
+                f,
+                "S3 is throttling requests. Wait a little and try again, or transfer fewer objects at once."
+            ),
+            "InvalidAccessKeyId" => write!(
+                f,
+                "AWS doesn't recognize this access key ID. Check your credentials, or choose a different profile with \
+                 AWS_PROFILE."
+            ),
+            "SignatureDoesNotMatch" => write!(
+                f,
+                "The request signature doesn't match. Check that your secret access key is correct."
+            ),
+            "PermanentRedirect" | "AuthorizationHeaderMalformed" => match &self.bucket_region {
+                Some(region) => write!(
+                    f,
+                    "This bucket is in {}. Switch to that region with `region {}`.",
+                    region, region
+                ),
+                None => write!(
+                    f,
+                    "This bucket is in a different region. Switch to it with `region NAME`."
+                ),
+            },
+            _ if self.message.is_empty() => write!(f, "S3 error: {}", self.code),
+            _ => write!(f, "S3 error {}: {}", self.code, self.message),
         }
     }
+}
 
-    pub async fn put_object(
-        &self,
-        bucket: String,
-        key: String,
-        source_path: &Path,
-    ) -> Result<(), RBError> {
-        println!(
-            "Debug: uploading file {:?} to bucket {} key {}",
-            source_path, bucket, key
-        );
+impl S3ServiceError {
+    /// Whether S3 turned the request down because the bucket is in a different region than the one we asked
+    pub fn is_wrong_region(&self) -> bool {
+        matches!(
+            self.code.as_str(),
+            "PermanentRedirect" | "AuthorizationHeaderMalformed"
+        )
+    }
+}
 
-        let src_file = File::open(source_path).await.map_err(RBError::wrap_io)?;
+impl Error for S3ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source_error.as_ref())
+    }
+}
 
-        let file_meta = src_file.metadata().await.map_err(RBError::wrap_io)?;
-        let file_size: i64 = TryFrom::try_from(file_meta.len()).map_err(RBError::wrap_io)?;
+// The contents of the first `<Tag>...</Tag>` in an S3 XML error body
+fn xml_element(body: &str, tag: &str) -> Option<String> {
+    let start = body.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + body[start..].find(&format!("</{}>", tag))?;
+    Some(body[start..end].to_owned())
+}
 
-        let params = PutObjectRequest {
-            bucket,
-            key,
-            body: Some(ByteStream::new(ReaderStream::new(src_file))),
-            content_length: Some(file_size),
-            ..Default::default()
-        };
+// Returns the name of the error's variant, if it's one of the given error types
+macro_rules! service_error_code {
+    ($err:expr, $($error_type:ident { $($variant:ident),+ }),+ $(,)?) => {
+        $(
+            if let Some(e) = $err.downcast_ref::<$error_type>() {
+                return Some(match e {
+                    $($error_type::$variant(_) => stringify!($variant),)+
+                });
+            }
+        )+
+    };
+}
 
-        self.client
-            .put_object(params)
-            .await
-            .map_err(RBError::wrap_s3)?;
+// rusoto_s3 only parses a handful of S3's error codes into variants of their own, which are named after the codes. Every
+// other code arrives as RusotoError::Unknown, with the response body still there to read it from.
+fn service_error_code(err: &dyn Any) -> Option<&'static str> {
+    service_error_code!(
+        err,
+        AbortMultipartUploadError { NoSuchUpload },
+        CopyObjectError {
+            ObjectNotInActiveTierError
+        },
+        CreateBucketError {
+            BucketAlreadyExists,
+            BucketAlreadyOwnedByYou
+        },
+        GetObjectError {
+            InvalidObjectState,
+            NoSuchKey
+        },
+        GetObjectAclError { NoSuchKey },
+        HeadBucketError { NoSuchBucket },
+        HeadObjectError { NoSuchKey },
+        ListObjectsError { NoSuchBucket },
+        ListObjectsV2Error { NoSuchBucket },
+        PutObjectAclError { NoSuchKey },
+        RestoreObjectError {
+            ObjectAlreadyInActiveTierError
+        },
+    );
+    None
+}
 
-        Ok(())
+// Picks the S3 error code (and message, and the bucket's real region if S3 told us) out of a failed request
+fn service_error_details<E>(err: &RusotoError<E>) -> Option<(String, String, Option<String>)>
+where
+    E: Error + 'static,
+{
+    match err {
+        RusotoError::Service(service_error) => {
+            let code = service_error_code(service_error)?;
+            Some((code.to_owned(), service_error.to_string(), None))
+        }
+        RusotoError::Unknown(response) => {
+            let body = String::from_utf8_lossy(&response.body);
+            let code = match xml_element(&body, "Code") {
+                Some(code) => code,
+                // HEAD requests get no body to explain a redirect, but it's a redirect all the same
+                None if response.status.as_u16() == 301 => String::from("PermanentRedirect"),
+                None => return None,
+            };
+            let message = xml_element(&body, "Message").unwrap_or_default();
+            let bucket_region = response
+                .headers
+                .get("x-amz-bucket-region")
+                .cloned()
+                .or_else(|| xml_element(&body, "Region"));
+            Some((code, message, bucket_region))
+        }
+        _ => None,
+    }
+}
+
+// S3 answers requests for an object's Object Lock settings with an error, rather than an empty answer, when the object
+// has none or its bucket doesn't use Object Lock at all
+fn is_missing_lock_config<E>(err: &RusotoError<E>) -> bool
+where
+    E: Error + 'static,
+{
+    match service_error_details(err) {
+        Some((code, message, _)) => {
+            code == "NoSuchObjectLockConfiguration"
+                || (code == "InvalidRequest" && message.contains("Object Lock"))
+        }
+        None => false,
+    }
+}
+
+// Restoring an object that's already being restored is reported as an error, though the restore itself is going fine
+fn is_restore_in_progress<E>(err: &RusotoError<E>) -> bool
+where
+    E: Error + 'static,
+{
+    service_error_details(err).is_some_and(|(code, _, _)| code == "RestoreAlreadyInProgress")
+}
+
+// A bucket without any CORS rules is reported as an error, rather than as an empty list of rules
+fn is_missing_cors_config<E>(err: &RusotoError<E>) -> bool
+where
+    E: Error + 'static,
+{
+    service_error_details(err).is_some_and(|(code, _, _)| code == "NoSuchCORSConfiguration")
+}
+
+// Buckets made before S3 started encrypting everything by default might still have no encryption settings at all
+fn is_missing_encryption_config<E>(err: &RusotoError<E>) -> bool
+where
+    E: Error + 'static,
+{
+    service_error_details(err)
+        .is_some_and(|(code, _, _)| code == "ServerSideEncryptionConfigurationNotFoundError")
+}
+
+// Errors partway through an event stream (like S3 Select's results) come without a service error type of their own, so
+// give them SelectObjectContent's for classifying
+fn wrap_event_stream_error(err: RusotoError<()>) -> RBError {
+    let err = match err {
+        RusotoError::Service(()) => return RBError::new(ErrorKind::S3),
+        RusotoError::HttpDispatch(e) => RusotoError::<SelectObjectContentError>::HttpDispatch(e),
+        RusotoError::Credentials(e) => RusotoError::Credentials(e),
+        RusotoError::Validation(message) => RusotoError::Validation(message),
+        RusotoError::ParseError(message) => RusotoError::ParseError(message),
+        RusotoError::Unknown(response) => RusotoError::Unknown(response),
+        RusotoError::Blocking => RusotoError::Blocking,
+    };
+    wrap_rusoto_error(err)
+}
+
+// Sorts a failed request into the kind of error it is: most importantly, expired credentials (which callers recover
+// from by refreshing them and trying again) and the errors that are worth simply trying again. Errors with an S3 error
+// code are wrapped in an S3ServiceError, so that they can be explained to the user.
+fn wrap_rusoto_error<E>(err: RusotoError<E>) -> RBError
+where
+    E: Error + Send + Sync + 'static,
+{
+    let status = match &err {
+        RusotoError::Unknown(response) => response.status.as_u16(),
+        _ => 0,
+    };
+    let details = service_error_details(&err);
+    let code = details.as_ref().map_or("", |(code, _, _)| code.as_str());
+
+    let kind = match &err {
+        RusotoError::HttpDispatch(_) => ErrorKind::Network,
+        RusotoError::Credentials(_) => ErrorKind::Credentials,
+        _ if EXPIRED_CREDENTIALS_CODES.contains(&code) => ErrorKind::ExpiredCredentials,
+        _ if THROTTLING_CODES.contains(&code) || status == 429 => ErrorKind::Throttled,
+        _ if NOT_FOUND_CODES.contains(&code) || status == 404 => ErrorKind::NotFound,
+        _ if ACCESS_DENIED_CODES.contains(&code) || status == 403 => ErrorKind::AccessDenied,
+        _ if CONFLICT_CODES.contains(&code) || status == 409 || status == 412 => {
+            ErrorKind::Conflict
+        }
+        _ if status >= 500 => ErrorKind::Transient,
+        _ => ErrorKind::S3,
+    };
+
+    match details {
+        Some((code, message, bucket_region)) => RBError::wrap(
+            kind,
+            S3ServiceError {
+                code,
+                message,
+                bucket_region,
+                source_error: Box::new(err),
+            },
+        ),
+        None => RBError::wrap(kind, err),
+    }
+}
+
+// The kind of error that one key's failure inside a DeleteObjects response is. There's no HTTP status to go by for
+// these, only S3's error code.
+fn delete_failure_kind(code: &str) -> ErrorKind {
+    if EXPIRED_CREDENTIALS_CODES.contains(&code) {
+        ErrorKind::ExpiredCredentials
+    } else if THROTTLING_CODES.contains(&code) {
+        ErrorKind::Throttled
+    } else if NOT_FOUND_CODES.contains(&code) {
+        ErrorKind::NotFound
+    } else if ACCESS_DENIED_CODES.contains(&code) {
+        ErrorKind::AccessDenied
+    } else if CONFLICT_CODES.contains(&code) {
+        ErrorKind::Conflict
+    } else {
+        ErrorKind::S3
+    }
+}
+
+// Rusoto already knows the hostnames for the GovCloud and China partitions (the latter end in .amazonaws.com.cn), but
+// it doesn't know about FIPS endpoints, so for those we point it at the endpoint ourselves
+fn fips_region(region: &Region) -> Result<Region, RBError> {
+    match region {
+        // Either we've already done this, or the user set an endpoint of their own that we shouldn't second-guess
+        Region::Custom { .. } => Ok(region.clone()),
+        // There are no FIPS endpoints in the China partition
+        Region::CnNorth1 | Region::CnNorthwest1 => Err(RBError::new(ErrorKind::InvalidTarget)),
+        _ => Ok(Region::Custom {
+            name: region.name().to_owned(),
+            endpoint: format!("https://s3-fips.{}.amazonaws.com", region.name()),
+        }),
+    }
+}
+
+// Aborts a multipart upload when it's dropped, unless it was finished first. Dropping happens when a copy is cancelled
+// partway through (e.g. with Ctrl-C), when there's no chance to await anything, so the abort runs as a task of its own.
+struct IncompleteUploadGuard {
+    client: S3Client,
+    request: Option<AbortMultipartUploadRequest>,
+}
+
+impl IncompleteUploadGuard {
+    fn finish(mut self) {
+        self.request = None;
+    }
+
+    // If even the abort fails, whatever made us abort is the error worth reporting
+    async fn abort(mut self) {
+        if let Some(request) = self.request.take() {
+            let _ = self.client.abort_multipart_upload(request).await;
+        }
+    }
+}
+
+impl Drop for IncompleteUploadGuard {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            debug!(
+                "aborting cancelled multipart upload {} of bucket {} key {}",
+                request.upload_id, request.bucket, request.key
+            );
+            let client = self.client.clone();
+            if let Ok(handle) = Handle::try_current() {
+                handle.spawn(async move {
+                    let _ = client.abort_multipart_upload(request).await;
+                });
+            }
+        }
+    }
+}
+
+// Object tags as the URL query string that requests' x-amz-tagging header takes
+fn encode_tagging(tags: &BTreeMap<String, String>) -> String {
+    let encode = |text: &str| encode_path(text).replace('/', "%2F");
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+// A grantee the way the x-amz-grant-* headers name them, like `id="..."` or `uri="..."`
+fn grant_header_value(grantee: &Grantee) -> Option<String> {
+    if let Some(id) = &grantee.id {
+        Some(format!("id=\"{}\"", id))
+    } else if let Some(uri) = &grantee.uri {
+        Some(format!("uri=\"{}\"", uri))
+    } else {
+        grantee
+            .email_address
+            .as_ref()
+            .map(|email_address| format!("emailAddress=\"{}\"", email_address))
+    }
+}
+
+// The events in an S3 Select response: the records it found, and the progress, stats, and end events that we've no use
+// for
+enum SelectEvent {
+    Records(Vec<u8>),
+    Other,
+}
+
+impl DeserializeEvent for SelectEvent {
+    fn deserialize_event(event_type: &str, data: &[u8]) -> Result<Self, RusotoError<()>> {
+        Ok(match event_type {
+            "Records" => SelectEvent::Records(data.to_vec()),
+            _ => SelectEvent::Other,
+        })
+    }
+}
+
+// A SelectObjectContent request's XML body. The object's compression is guessed from its extension.
+fn select_request_body(key: &str, query: &SelectQuery) -> String {
+    let lowercase_key = key.to_lowercase();
+    let compression_type = if lowercase_key.ends_with(".gz") {
+        "GZIP"
+    } else if lowercase_key.ends_with(".bz2") {
+        "BZIP2"
+    } else {
+        "NONE"
+    };
+    let input = match query.input {
+        // Using the header lets queries name columns rather than numbering them
+        SelectFormat::Csv => "<CSV><FileHeaderInfo>USE</FileHeaderInfo></CSV>",
+        SelectFormat::Json => "<JSON><Type>DOCUMENT</Type></JSON>",
+        SelectFormat::Parquet => "<Parquet/>",
+    };
+    let output = match query.output {
+        SelectFormat::Csv => "<CSV/>",
+        SelectFormat::Json | SelectFormat::Parquet => {
+            "<JSON><RecordDelimiter>\n</RecordDelimiter></JSON>"
+        }
+    };
+    format!(
+        "<SelectObjectContentRequest xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+         <Expression>{}</Expression><ExpressionType>SQL</ExpressionType>\
+         <InputSerialization><CompressionType>{}</CompressionType>{}</InputSerialization>\
+         <OutputSerialization>{}</OutputSerialization>\
+         </SelectObjectContentRequest>",
+        escape_xml(&query.expression),
+        compression_type,
+        input,
+        output
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// CopyObject takes its source as a single URL-encoded `bucket/key` value (or `ARN/object/key` for an access point)
+fn encode_copy_source(bucket: &str, key: &str) -> String {
+    match AccessPointArn::split_path(bucket) {
+        Some((access_point, _)) => encode_path(&format!("{}/object/{}", access_point.arn(), key)),
+        None => encode_path(&format!("{}/{}", bucket, key)),
+    }
+}
+
+// URL-encodes a path made of a bucket and key, or just a key. The slashes separating their parts have to stay as they
+// are.
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Virtual-hosted addressing puts the bucket name in the hostname, so it only works for names that are valid DNS labels.
+// Dots technically are, but they break TLS certificate matching against `*.s3.amazonaws.com`.
+fn is_virtual_host_compatible(bucket: &str) -> bool {
+    (3..=63).contains(&bucket.len())
+        && bucket
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !bucket.starts_with('-')
+        && !bucket.ends_with('-')
+}
+
+impl RBS3 {
+    pub fn builder() -> RBS3Builder {
+        RBS3Builder::default()
+    }
+
+    /// A builder that starts out with all of this client's settings, for making a variation on it
+    pub fn to_builder(&self) -> RBS3Builder {
+        RBS3Builder {
+            config: self.config.clone(),
+        }
+    }
+
+    pub fn new(config: ClientConfig) -> Result<Self, RBError> {
+        let http_client = TimeoutHttpClient::new(&config)?;
+        Self::with_dispatcher(config, http_client)
+    }
+
+    /// Like `new`, but every request (including the ones we build by hand) goes through `dispatcher` instead of out
+    /// over HTTP. This is how tests can stand in for S3: rusoto_mock's `MockRequestDispatcher`, for example, answers
+    /// each request with a canned ListObjectsV2 or GetObject response.
+    pub fn with_dispatcher<D>(mut config: ClientConfig, dispatcher: D) -> Result<Self, RBError>
+    where
+        D: DispatchSignedRequest + Send + Sync + 'static,
+    {
+        if config.use_fips_endpoint {
+            config.region = fips_region(&config.region)?;
+        }
+
+        let core_client = if config.no_sign_request {
+            // Rusoto skips signing entirely when given empty credentials
+            core_client(StaticProvider::from(AwsCredentials::default()), dispatcher)
+        } else if let Some(credentials) = config.credentials.clone() {
+            core_client(credentials, dispatcher)
+        } else {
+            // Not Client::shared(), because that caches credentials for the life of the process, and we want
+            // constructing a new RBS3 to be a way to pick up fresh credentials
+            core_client(
+                DefaultCredentialsProvider::new().map_err(RBError::wrap_credentials)?,
+                dispatcher,
+            )
+        };
+
+        Ok(RBS3 {
+            client: S3Client::new_with_client(core_client.clone(), config.region.clone()),
+            core_client,
+            config,
+        })
+    }
+
+    pub fn region(&self) -> &Region {
+        &self.config.region
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Asks S3 which region a bucket is in
+    pub async fn bucket_region(&self, bucket: String) -> Result<Region, RBError> {
+        debug!("getting the location of bucket {}", bucket);
+        let output = self
+            .client
+            .get_bucket_location(GetBucketLocationRequest {
+                bucket,
+                ..Default::default()
+            })
+            .await
+            .map_err(wrap_rusoto_error)?;
+        // Buckets in us-east-1 have no location constraint, and some old buckets in eu-west-1 still say "EU"
+        let region_name = match output.location_constraint.as_deref() {
+            None | Some("") => "us-east-1",
+            Some("EU") => "eu-west-1",
+            Some(region_name) => region_name,
+        };
+        region_name.parse().map_err(RBError::wrap_s3)
+    }
+
+    /// Lists the directories and objects directly under `prefix` as a stream that fetches one page of results at a time,
+    /// so that a caller can make a start on the first entries, or stop early, without paging through a whole bucket.
+    /// Within each page, directories come before objects.
+    pub fn list_files_stream(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> impl Stream<Item = Result<ObjectEntry, RBError>> {
+        debug!(
+            "listing files at bucket {}, prefix {}",
+            bucket,
+            prefix.as_ref().unwrap_or(&String::from("<no prefix>"))
+        );
+        let client = self.client.clone();
+        let first_page = ListObjectsV2Request {
+            bucket,
+            prefix: prefix.clone(),
+            delimiter: Some(String::from("/")),
+            ..Default::default()
+        };
+
+        // The state is the request for the next page, or None once the last page has been fetched
+        stream::try_unfold(Some(first_page), move |params| {
+            let client = client.clone();
+            let prefix = prefix.clone();
+            async move {
+                let mut params = match params {
+                    Some(params) => params,
+                    None => return Ok(None),
+                };
+                let output = client
+                    .list_objects_v2(params.clone())
+                    .await
+                    .map_err(wrap_rusoto_error)?;
+
+                let relative_name = |name: String| match &prefix {
+                    Some(prefix) => name.strip_prefix(prefix.as_str()).map(str::to_owned),
+                    None => Some(name),
+                };
+                let mut page: Vec<ObjectEntry> = output
+                    .common_prefixes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|common_prefix| relative_name(common_prefix.prefix?))
+                    .map(|name| ObjectEntry {
+                        name,
+                        is_prefix: true,
+                        size: None,
+                        last_modified: None,
+                        etag: None,
+                        storage_class: None,
+                    })
+                    .collect();
+                page.extend(
+                    output
+                        .contents
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|object| {
+                            let name = relative_name(object.key?)?;
+                            // Keys further down than this "directory" belong to one of its prefixes instead
+                            if name.contains('/') {
+                                return None;
+                            }
+                            Some(ObjectEntry {
+                                name,
+                                is_prefix: false,
+                                size: object.size.and_then(|size| u64::try_from(size).ok()),
+                                last_modified: object
+                                    .last_modified
+                                    .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                                    .map(|date| date.with_timezone(&Utc)),
+                                etag: object.e_tag.map(|etag| etag.trim_matches('"').to_owned()),
+                                storage_class: object.storage_class,
+                            })
+                        }),
+                );
+
+                let next_page = output.next_continuation_token.map(|token| {
+                    params.continuation_token = Some(token);
+                    params
+                });
+                Ok(Some((page, next_page)))
+            }
+        })
+        .map_ok(|page| stream::iter(page.into_iter().map(Ok::<ObjectEntry, RBError>)))
+        .try_flatten()
+    }
+
+    // Copies an object, keeping its metadata, into `storage_class` if there is one or the bucket's default otherwise.
+    // Returns the object's size.
+    async fn copy_object_as(
+        &self,
+        source_bucket: String,
+        source_key: String,
+        dest_bucket: String,
+        dest_key: String,
+        changes: CopyChanges,
+    ) -> Result<u64, RBError> {
+        debug!(
+            "copying bucket {} key {} to bucket {} key {} ({:?})",
+            source_bucket, source_key, dest_bucket, dest_key, changes
+        );
+        let mut source = self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: source_bucket.clone(),
+                key: source_key.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(wrap_rusoto_error)?;
+        let size = source.content_length.unwrap_or(0);
+        // The content headers count as metadata too, as far as CopyObject is concerned
+        let replace_metadata = changes.metadata.is_some() || changes.content_type.is_some();
+        // A copy lands in STANDARD unless it's told otherwise, which is fine for a copy somewhere new, but not for an
+        // edit in place
+        let storage_class = if replace_metadata {
+            changes
+                .storage_class
+                .or_else(|| source.storage_class.clone())
+        } else {
+            changes.storage_class
+        };
+        if let Some(metadata) = changes.metadata {
+            source.metadata = Some(metadata);
+        }
+        if let Some(content_type) = changes.content_type {
+            source.content_type = Some(content_type);
+        }
+        // A copy somewhere new gets the bucket's default ACL, like any other new object, but an edit in place mustn't
+        // take away access that people had to it. The grants have to be read before the copy replaces them.
+        let grants = if source_bucket == dest_bucket && source_key == dest_key {
+            self.object_grants(source_bucket.clone(), source_key.clone())
+                .await?
+        } else {
+            ObjectGrants::default()
+        };
+        if size > MAX_COPY_OBJECT_SIZE {
+            let destination = CreateMultipartUploadRequest {
+                bucket: dest_bucket,
+                key: dest_key,
+                grant_full_control: grants.full_control,
+                grant_read: grants.read,
+                grant_read_acp: grants.read_acp,
+                grant_write_acp: grants.write_acp,
+                storage_class,
+                ..Default::default()
+            };
+            self.copy_object_in_parts(source, source_bucket, source_key, destination)
+                .await?;
+        } else if replace_metadata {
+            // Replacing the metadata replaces the content headers, Expires and the website redirect along with it, so
+            // they have to be sent again too
+            let params = CopyObjectRequest {
+                bucket: dest_bucket,
+                key: dest_key,
+                copy_source: encode_copy_source(&source_bucket, &source_key),
+                metadata_directive: Some(String::from("REPLACE")),
+                metadata: source.metadata,
+                cache_control: source.cache_control,
+                content_disposition: source.content_disposition,
+                content_encoding: source.content_encoding,
+                content_language: source.content_language,
+                content_type: source.content_type,
+                expires: source.expires,
+                website_redirect_location: source.website_redirect_location,
+                server_side_encryption: source.server_side_encryption,
+                ssekms_key_id: source.ssekms_key_id,
+                bucket_key_enabled: source.bucket_key_enabled,
+                grant_full_control: grants.full_control,
+                grant_read: grants.read,
+                grant_read_acp: grants.read_acp,
+                grant_write_acp: grants.write_acp,
+                storage_class,
+                ..Default::default()
+            };
+            self.client
+                .copy_object(params)
+                .await
+                .map_err(wrap_rusoto_error)?;
+        } else {
+            // The metadata comes along by itself, but the encryption doesn't: without it the copy would get the
+            // bucket's default
+            let params = CopyObjectRequest {
+                bucket: dest_bucket,
+                key: dest_key,
+                copy_source: encode_copy_source(&source_bucket, &source_key),
+                server_side_encryption: source.server_side_encryption,
+                ssekms_key_id: source.ssekms_key_id,
+                bucket_key_enabled: source.bucket_key_enabled,
+                grant_full_control: grants.full_control,
+                grant_read: grants.read,
+                grant_read_acp: grants.read_acp,
+                grant_write_acp: grants.write_acp,
+                storage_class,
+                ..Default::default()
+            };
+            self.client
+                .copy_object(params)
+                .await
+                .map_err(wrap_rusoto_error)?;
+        }
+        Ok(u64::try_from(size).unwrap_or(0))
+    }
+
+    // Copies an object that's too big for CopyObject, one UploadPartCopy at a time. `source` is the source object's
+    // HeadObject response, and `destination` names where the copy goes and anything (like a storage class) that it
+    // gets besides the source's metadata. If anything goes wrong, or the copy is cancelled, the upload is aborted rather than left
+    // behind (and billed for) with the parts copied so far.
+    async fn copy_object_in_parts(
+        &self,
+        source: HeadObjectOutput,
+        source_bucket: String,
+        source_key: String,
+        destination: CreateMultipartUploadRequest,
+    ) -> Result<(), RBError> {
+        let dest_bucket = destination.bucket.clone();
+        let dest_key = destination.key.clone();
+        let size = source.content_length.unwrap_or(0);
+        let part_size = COPY_PART_SIZE.max((size + MAX_PART_COUNT - 1) / MAX_PART_COUNT);
+        debug!("copying {} bytes in parts of {} bytes", size, part_size);
+        // CopyObject brings the object's metadata and tags along with it, but a multipart copy only copies the bytes
+        let tags = self
+            .object_tags(source_bucket.clone(), source_key.clone())
+            .await?;
+        let upload = self
+            .client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                cache_control: source.cache_control,
+                content_disposition: source.content_disposition,
+                content_encoding: source.content_encoding,
+                content_language: source.content_language,
+                content_type: source.content_type,
+                expires: source.expires,
+                metadata: source.metadata,
+                website_redirect_location: source.website_redirect_location,
+                server_side_encryption: source.server_side_encryption,
+                ssekms_key_id: source.ssekms_key_id,
+                bucket_key_enabled: source.bucket_key_enabled,
+                tagging: (!tags.is_empty()).then(|| encode_tagging(&tags)),
+                ..destination
+            })
+            .await
+            .map_err(wrap_rusoto_error)?;
+        let upload_id = upload
+            .upload_id
+            .ok_or_else(|| RBError::new(ErrorKind::S3))?;
+        let upload = IncompleteUploadGuard {
+            client: self.client.clone(),
+            request: Some(AbortMultipartUploadRequest {
+                bucket: dest_bucket.clone(),
+                key: dest_key.clone(),
+                upload_id: upload_id.clone(),
+                ..Default::default()
+            }),
+        };
+
+        let copy_source = encode_copy_source(&source_bucket, &source_key);
+        let mut parts = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let end = (start + part_size).min(size) - 1;
+            let part_number = parts.len() as i64 + 1;
+            let params = UploadPartCopyRequest {
+                bucket: dest_bucket.clone(),
+                key: dest_key.clone(),
+                upload_id: upload_id.clone(),
+                part_number,
+                copy_source: copy_source.clone(),
+                copy_source_range: Some(format!("bytes={}-{}", start, end)),
+                ..Default::default()
+            };
+            match self.client.upload_part_copy(params).await {
+                Ok(output) => parts.push(CompletedPart {
+                    e_tag: output.copy_part_result.and_then(|result| result.e_tag),
+                    part_number: Some(part_number),
+                }),
+                Err(e) => {
+                    upload.abort().await;
+                    return Err(wrap_rusoto_error(e));
+                }
+            }
+            start = end + 1;
+        }
+
+        let completed = self
+            .client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: dest_bucket.clone(),
+                key: dest_key.clone(),
+                upload_id,
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..Default::default()
+            })
+            .await;
+        match completed {
+            Ok(_) => {
+                upload.finish();
+                Ok(())
+            }
+            Err(e) => {
+                upload.abort().await;
+                Err(wrap_rusoto_error(e))
+            }
+        }
+    }
+
+    // The grants that an edit in place has to send along with its copy, so that the object doesn't lose them: a copy
+    // starts out private whatever its source's ACL was. There's nothing to send if the owner's FULL_CONTROL is all
+    // there is.
+    async fn object_grants(&self, bucket: String, key: String) -> Result<ObjectGrants, RBError> {
+        let acl = self
+            .client
+            .get_object_acl(GetObjectAclRequest {
+                bucket,
+                key,
+                ..Default::default()
+            })
+            .await
+            .map_err(wrap_rusoto_error)?;
+        let owner_id = acl.owner.as_ref().and_then(|owner| owner.id.as_ref());
+        let grants = acl.grants.unwrap_or_default();
+        let only_owner = grants.iter().all(|grant| {
+            grant
+                .grantee
+                .as_ref()
+                .and_then(|grantee| grantee.id.as_ref())
+                == owner_id
+                && grant.permission.as_deref() == Some("FULL_CONTROL")
+        });
+        let mut object_grants = ObjectGrants::default();
+        if only_owner {
+            return Ok(object_grants);
+        }
+        for grant in &grants {
+            let grantee = match grant.grantee.as_ref().and_then(grant_header_value) {
+                Some(grantee) => grantee,
+                None => continue,
+            };
+            let header = match grant.permission.as_deref() {
+                Some("FULL_CONTROL") => &mut object_grants.full_control,
+                Some("READ") => &mut object_grants.read,
+                Some("READ_ACP") => &mut object_grants.read_acp,
+                Some("WRITE_ACP") => &mut object_grants.write_acp,
+                // WRITE means nothing for an object
+                _ => continue,
+            };
+            match header {
+                Some(grantees) => {
+                    grantees.push_str(", ");
+                    grantees.push_str(&grantee);
+                }
+                None => *header = Some(grantee),
+            }
+        }
+        Ok(object_grants)
+    }
+
+    /// The URLs that an object can be fetched from, which is handy for objects that are public: virtual-hosted style
+    /// (`https://bucket.s3.region.amazonaws.com/key`) first if the bucket's name and our settings allow it, then path
+    /// style. An access point only has the one.
+    pub fn object_urls(&self, bucket: &str, key: &str) -> Vec<String> {
+        let key_path = encode_path(key);
+        if let Some((access_point, _)) = AccessPointArn::split_path(bucket) {
+            return vec![format!("https://{}/{}", access_point.hostname(), key_path)];
+        }
+        let (scheme, host, virtual_hosting) = match &self.config.region {
+            // A custom endpoint might be plain HTTP, or have a port, and we can't know if it can do virtual hosting
+            Region::Custom { endpoint, .. } => {
+                let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+                (scheme, host.trim_end_matches('/').to_owned(), false)
+            }
+            region => (
+                "https",
+                SignedRequest::new("GET", "s3", region, "/").hostname(),
+                !self.config.force_path_style,
+            ),
+        };
+
+        let mut urls = Vec::new();
+        if virtual_hosting && is_virtual_host_compatible(bucket) {
+            urls.push(format!("{}://{}.{}/{}", scheme, bucket, host, key_path));
+        }
+        urls.push(format!("{}://{}/{}/{}", scheme, host, bucket, key_path));
+        urls
+    }
+
+    // Starts a hand-built request for an object, addressed the way our config asks for. The key goes in as it is:
+    // SignedRequest percent-encodes the path (the same way rusoto_s3's own requests are encoded) when it signs and sends
+    // it, so encoding it here as well would encode it twice.
+    fn object_request(&self, method: &str, bucket: &str, key: &str) -> SignedRequest {
+        if self.config.force_path_style || !is_virtual_host_compatible(bucket) {
+            SignedRequest::new(
+                method,
+                "s3",
+                &self.config.region,
+                &format!("/{}/{}", bucket, key),
+            )
+        } else {
+            let mut request =
+                SignedRequest::new(method, "s3", &self.config.region, &format!("/{}", key));
+            let hostname = format!("{}.{}", bucket, request.hostname());
+            request.set_hostname(Some(hostname));
+            request
+        }
+    }
+
+    async fn download_object_to<W>(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+        dest: &mut W,
+    ) -> Result<(), RBError>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let params = GetObjectRequest {
+            bucket,
+            key: key.clone(),
+            version_id: options.version_id.clone(),
+            range: options.range.clone(),
+            ..Default::default()
+        };
+
+        let object = self
+            .client
+            .get_object(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+
+        if let Some(body) = object.body {
+            let progress = TransferProgress::new(
+                key.clone(),
+                object
+                    .content_length
+                    .and_then(|len| TryFrom::try_from(len).ok()),
+            );
+            let mut object_stream = ProgressReader::new(Box::pin(body.into_async_read()), progress);
+            io::copy(&mut object_stream, dest)
+                .await
+                .map_err(RBError::wrap_io)?;
+
+            Ok(())
+        } else {
+            eprintln!("Object at key {} has no body!", key);
+            Err(RBError::new(ErrorKind::S3))
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for RBS3 {
+    fn list_files_stream(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> BoxStream<'_, Result<ObjectEntry, RBError>> {
+        RBS3::list_files_stream(self, bucket, prefix).boxed()
+    }
+
+    async fn list_buckets(&self) -> Result<Vec<String>, RBError> {
+        let result = self
+            .client
+            .list_buckets()
+            .await
+            .map_err(wrap_rusoto_error)?;
+
+        let buckets: Vec<String> = result
+            .buckets
+            .unwrap_or(vec![])
+            .into_iter()
+            .filter_map(|bucket| bucket.name)
+            .collect();
+
+        Ok(buckets)
+    }
+
+    async fn list_entries(
+        &self,
+        bucket: String,
+        prefix: Option<String>,
+    ) -> Result<Vec<ObjectEntry>, RBError> {
+        let entries: Vec<ObjectEntry> =
+            self.list_files_stream(bucket, prefix).try_collect().await?;
+        let (mut results, mut files): (Vec<ObjectEntry>, Vec<ObjectEntry>) =
+            entries.into_iter().partition(|entry| entry.is_prefix);
+
+        // All the directories appear at the top and the files at the bottom
+        results.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        files.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        results.extend(files);
+
+        Ok(results)
+    }
+
+    async fn list_keys(&self, bucket: String, prefix: String) -> Result<Vec<String>, RBError> {
+        debug!("listing all keys at bucket {}, prefix {}", bucket, prefix);
+        let mut params = ListObjectsV2Request {
+            bucket,
+            prefix: Some(prefix),
+            ..Default::default()
+        };
+
+        let mut keys = Vec::new();
+        loop {
+            let output = self
+                .client
+                .list_objects_v2(params.clone())
+                .await
+                .map_err(wrap_rusoto_error)?;
+            keys.extend(
+                output
+                    .contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| object.key),
+            );
+
+            if output.next_continuation_token.is_some() {
+                params.continuation_token = output.next_continuation_token;
+            } else {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn usage(
+        &self,
+        bucket: String,
+        prefix: String,
+        max_objects: Option<u64>,
+    ) -> Result<Option<Usage>, RBError> {
+        debug!("adding up objects at bucket {}, prefix {}", bucket, prefix);
+        let mut params = ListObjectsV2Request {
+            bucket,
+            prefix: Some(prefix),
+            ..Default::default()
+        };
+
+        let mut usage = Usage::default();
+        loop {
+            let output = self
+                .client
+                .list_objects_v2(params.clone())
+                .await
+                .map_err(wrap_rusoto_error)?;
+            for object in output.contents.unwrap_or_default() {
+                usage.objects += 1;
+                usage.bytes += object
+                    .size
+                    .and_then(|size| u64::try_from(size).ok())
+                    .unwrap_or(0);
+            }
+            if max_objects.is_some_and(|max_objects| usage.objects > max_objects) {
+                return Ok(None);
+            }
+
+            if output.next_continuation_token.is_some() {
+                params.continuation_token = output.next_continuation_token;
+            } else {
+                return Ok(Some(usage));
+            }
+        }
+    }
+
+    // Only the first page of results is fetched, since nobody is going to tab through more than a thousand suggestions
+    // anyway
+    async fn list_completions(
+        &self,
+        bucket: String,
+        dir_prefix: String,
+        partial: &str,
+    ) -> Result<Vec<String>, RBError> {
+        debug!(
+            "listing completions at bucket {}, prefix {}{}",
+            bucket, dir_prefix, partial
+        );
+        let params = ListObjectsV2Request {
+            bucket,
+            prefix: Some(format!("{}{}", dir_prefix, partial)),
+            delimiter: Some(String::from("/")),
+            ..Default::default()
+        };
+        let output = self
+            .client
+            .list_objects_v2(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+
+        let prefixes = output
+            .common_prefixes
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|common_prefix| common_prefix.prefix);
+        let keys = output
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|object| object.key);
+        Ok(prefixes
+            .chain(keys)
+            .filter_map(|name| name.strip_prefix(&dir_prefix).map(str::to_owned))
+            .collect())
+    }
+
+    async fn object_exists(&self, bucket: String, key: String) -> Result<bool, RBError> {
+        debug!("checking if file exists at bucket {}, key {}", bucket, key);
+        // Asking about the exact key, rather than listing it as a prefix, so that a longer key that merely starts with
+        // this one doesn't count
+        let head = self
+            .head_object(bucket, key, &GetOptions::default())
+            .await?;
+        Ok(head.is_some())
+    }
+
+    async fn head_object(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+    ) -> Result<Option<ObjectHead>, RBError> {
+        debug!("heading object at bucket {}, key {}", bucket, key);
+        let params = HeadObjectRequest {
+            bucket,
+            key,
+            version_id: options.version_id.clone(),
+            ..Default::default()
+        };
+
+        match self.client.head_object(params).await {
+            Ok(output) => Ok(Some(ObjectHead {
+                size: output
+                    .content_length
+                    .and_then(|len| TryFrom::try_from(len).ok())
+                    .unwrap_or(0),
+                // HeadObject gives us an RFC 2822 date like "Wed, 21 Oct 2015 07:28:00 GMT"
+                last_modified: output
+                    .last_modified
+                    .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                    .map(|date| date.with_timezone(&Utc)),
+                restore: output
+                    .restore
+                    .as_deref()
+                    .and_then(RestoreStatus::from_header),
+                metadata: output.metadata.unwrap_or_default(),
+            })),
+            // HEAD responses have no body, so S3 can't tell us "NoSuchKey" and we usually just get a bare 404
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None),
+            Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 404 => Ok(None),
+            Err(e) => Err(wrap_rusoto_error(e)),
+        }
+    }
+
+    async fn download_object(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+        dest_path: &Path,
+    ) -> Result<(), RBError> {
+        debug!(
+            "downloading bucket {} key {} ({:?}) to file {:?}",
+            bucket, key, options, dest_path
+        );
+        let mut dest_file = File::create(dest_path).await.map_err(RBError::wrap_io)?;
+        self.download_object_to(bucket, key, options, &mut dest_file)
+            .await
+    }
+
+    async fn download_object_to_stdout(
+        &self,
+        bucket: String,
+        key: String,
+        options: &GetOptions,
+    ) -> Result<(), RBError> {
+        debug!(
+            "downloading bucket {} key {} ({:?}) to stdout",
+            bucket, key, options
+        );
+        let mut stdout = io::stdout();
+        self.download_object_to(bucket, key, options, &mut stdout)
+            .await?;
+        stdout.flush().await.map_err(RBError::wrap_io)
+    }
+
+    async fn select_object(
+        &self,
+        bucket: String,
+        key: String,
+        query: &SelectQuery,
+        out: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<(), RBError> {
+        debug!("selecting from bucket {} key {} ({:?})", bucket, key, query);
+        // rusoto_s3 has a SelectObjectContent, but it can't decode the response (it just panics), so this request is
+        // built and read by hand
+        let mut request = self.object_request("POST", &bucket, &key);
+        let mut params = Params::new();
+        params.put_key("select");
+        params.put("select-type", "2");
+        request.set_params(params);
+        request.set_payload(Some(select_request_body(&key, query).into_bytes()));
+
+        let mut response = self
+            .core_client
+            .sign_and_dispatch(request)
+            .await
+            .map_err(|e| wrap_rusoto_error(RusotoError::<SelectObjectContentError>::from(e)))?;
+        if !response.status.is_success() {
+            let response = response.buffer().await.map_err(RBError::wrap_s3)?;
+            return Err(wrap_rusoto_error(
+                RusotoError::<SelectObjectContentError>::Unknown(response),
+            ));
+        }
+        // Results arrive as they're found, mixed in with progress and stats events that we've no use for
+        let mut events = EventStream::<SelectEvent>::new(response);
+        while let Some(event) = events.try_next().await.map_err(wrap_event_stream_error)? {
+            if let SelectEvent::Records(records) = event {
+                out.write_all(&records).await.map_err(RBError::wrap_io)?;
+            }
+        }
+        out.flush().await.map_err(RBError::wrap_io)
+    }
+
+    async fn put_object(
+        &self,
+        bucket: String,
+        key: String,
+        source_path: &Path,
+        metadata: HashMap<String, String>,
+        checksum: Option<ChecksumAlgorithm>,
+    ) -> Result<(), RBError> {
+        debug!(
+            "uploading file {:?} to bucket {} key {}",
+            source_path, bucket, key
+        );
+
+        let checksum_header = match checksum {
+            Some(algorithm) => Some((
+                algorithm.header_name(),
+                algorithm.compute(source_path).await?,
+            )),
+            None => None,
+        };
+
+        let src_file = File::open(source_path).await.map_err(RBError::wrap_io)?;
+
+        let file_meta = src_file.metadata().await.map_err(RBError::wrap_io)?;
+        let file_size: i64 = TryFrom::try_from(file_meta.len()).map_err(RBError::wrap_io)?;
+
+        let progress = TransferProgress::new(key.clone(), Some(file_meta.len()));
+        let body = ByteStream::new_with_size(
+            ReaderStream::new(ProgressReader::new(src_file, progress)),
+            file_meta.len() as usize,
+        );
+
+        if let Some((header_name, checksum_value)) = checksum_header {
+            // rusoto_s3's PutObjectRequest predates S3's additional checksum headers, so build this request by hand
+            let mut request = self.object_request("PUT", &bucket, &key);
+            request.add_header(header_name, &checksum_value);
+            for (meta_key, meta_value) in metadata {
+                request.add_header(format!("x-amz-meta-{}", meta_key), &meta_value);
+            }
+            request.set_payload_stream(body);
+
+            let mut response = self
+                .core_client
+                .sign_and_dispatch(request)
+                .await
+                .map_err(|e| wrap_rusoto_error(RusotoError::<PutObjectError>::from(e)))?;
+            if !response.status.is_success() {
+                let response = response.buffer().await.map_err(RBError::wrap_s3)?;
+                return Err(wrap_rusoto_error(RusotoError::<PutObjectError>::Unknown(
+                    response,
+                )));
+            }
+
+            return Ok(());
+        }
+
+        let params = PutObjectRequest {
+            bucket,
+            key,
+            body: Some(body),
+            content_length: Some(file_size),
+            // Rusoto adds the `x-amz-meta-` prefix to each of these keys for us
+            metadata: if metadata.is_empty() {
+                None
+            } else {
+                Some(metadata)
+            },
+            ..Default::default()
+        };
+
+        self.client
+            .put_object(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+
+        Ok(())
+    }
+
+    async fn delete_object(&self, bucket: String, key: String) -> Result<(), RBError> {
+        debug!("deleting object at bucket {}, key {}", bucket, key);
+        let params = DeleteObjectRequest {
+            bucket,
+            key,
+            ..Default::default()
+        };
+        self.client
+            .delete_object(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(())
+    }
+
+    async fn delete_objects(
+        &self,
+        bucket: String,
+        keys: Vec<String>,
+    ) -> Result<Vec<DeleteFailure>, RBError> {
+        let mut failures = Vec::new();
+        for batch in keys.chunks(DELETE_OBJECTS_BATCH_SIZE) {
+            debug!("deleting {} objects at bucket {}", batch.len(), bucket);
+            let params = DeleteObjectsRequest {
+                bucket: bucket.clone(),
+                delete: Delete {
+                    objects: batch
+                        .iter()
+                        .map(|key| ObjectIdentifier {
+                            key: key.clone(),
+                            version_id: None,
+                        })
+                        .collect(),
+                    // Only the keys that couldn't be deleted are worth hearing about
+                    quiet: Some(true),
+                },
+                ..Default::default()
+            };
+            let output = self
+                .client
+                .delete_objects(params)
+                .await
+                .map_err(wrap_rusoto_error)?;
+            failures.extend(
+                output
+                    .errors
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|error| {
+                        let code = error.code.unwrap_or_default();
+                        Some(DeleteFailure {
+                            key: error.key?,
+                            kind: delete_failure_kind(&code),
+                            message: error.message.unwrap_or(code),
+                        })
+                    }),
+            );
+        }
+        Ok(failures)
+    }
+
+    async fn delete_object_version(
+        &self,
+        bucket: String,
+        key: String,
+        version_id: String,
+    ) -> Result<(), RBError> {
+        debug!(
+            "deleting version {} of object at bucket {}, key {}",
+            version_id, bucket, key
+        );
+        let params = DeleteObjectRequest {
+            bucket,
+            key,
+            version_id: Some(version_id),
+            ..Default::default()
+        };
+        self.client
+            .delete_object(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(())
+    }
+
+    async fn copy_object(
+        &self,
+        source_bucket: String,
+        source_key: String,
+        dest_bucket: String,
+        dest_key: String,
+    ) -> Result<u64, RBError> {
+        self.copy_object_as(
+            source_bucket,
+            source_key,
+            dest_bucket,
+            dest_key,
+            CopyChanges::default(),
+        )
+        .await
+    }
+
+    async fn change_storage_class(
+        &self,
+        bucket: String,
+        key: String,
+        storage_class: String,
+    ) -> Result<(), RBError> {
+        // S3 only lets an object be copied onto itself if something about it changes, which the storage class does
+        self.copy_object_as(
+            bucket.clone(),
+            key.clone(),
+            bucket,
+            key,
+            CopyChanges {
+                storage_class: Some(storage_class),
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_object_metadata(
+        &self,
+        bucket: String,
+        key: String,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), RBError> {
+        // S3 can't edit an object's metadata, so the object is copied onto itself with the new metadata instead
+        self.copy_object_as(
+            bucket.clone(),
+            key.clone(),
+            bucket,
+            key,
+            CopyChanges {
+                metadata: Some(metadata),
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_content_type(
+        &self,
+        bucket: String,
+        key: String,
+        content_type: String,
+    ) -> Result<(), RBError> {
+        self.copy_object_as(
+            bucket.clone(),
+            key.clone(),
+            bucket,
+            key,
+            CopyChanges {
+                content_type: Some(content_type),
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn restore_object(
+        &self,
+        bucket: String,
+        key: String,
+        days: i64,
+        tier: Option<String>,
+    ) -> Result<(), RBError> {
+        debug!(
+            "restoring object at bucket {}, key {} for {} days ({:?} tier)",
+            bucket, key, days, tier
+        );
+        let params = RestoreObjectRequest {
+            bucket,
+            key,
+            restore_request: Some(RestoreRequest {
+                days: Some(days),
+                glacier_job_parameters: tier.map(|tier| GlacierJobParameters { tier }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        match self.client.restore_object(params).await {
+            Ok(_) => Ok(()),
+            // Asking again while a restore is underway changes nothing, so there's nothing to complain about
+            Err(e) if is_restore_in_progress(&e) => Ok(()),
+            Err(e) => Err(wrap_rusoto_error(e)),
+        }
+    }
+
+    async fn list_incomplete_uploads(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> Result<Vec<IncompleteUpload>, RBError> {
+        debug!(
+            "listing incomplete multipart uploads at bucket {}, prefix {}",
+            bucket, prefix
+        );
+        let mut params = ListMultipartUploadsRequest {
+            bucket,
+            prefix: Some(prefix),
+            ..Default::default()
+        };
+
+        let mut uploads = Vec::new();
+        loop {
+            let output = self
+                .client
+                .list_multipart_uploads(params.clone())
+                .await
+                .map_err(wrap_rusoto_error)?;
+            uploads.extend(
+                output
+                    .uploads
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|upload| {
+                        Some(IncompleteUpload {
+                            key: upload.key?,
+                            upload_id: upload.upload_id?,
+                            initiated: upload
+                                .initiated
+                                .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                                .map(|date| date.with_timezone(&Utc)),
+                        })
+                    }),
+            );
+            if !output.is_truncated.unwrap_or(false) {
+                return Ok(uploads);
+            }
+            params.key_marker = output.next_key_marker;
+            params.upload_id_marker = output.next_upload_id_marker;
+        }
+    }
+
+    async fn list_object_versions(
+        &self,
+        bucket: String,
+        prefix: String,
+    ) -> Result<Vec<VersionEntry>, RBError> {
+        debug!(
+            "listing object versions at bucket {}, prefix {}",
+            bucket, prefix
+        );
+        let mut params = ListObjectVersionsRequest {
+            bucket,
+            prefix: Some(prefix),
+            ..Default::default()
+        };
+
+        let mut versions = Vec::new();
+        loop {
+            let output = self
+                .client
+                .list_object_versions(params.clone())
+                .await
+                .map_err(wrap_rusoto_error)?;
+            versions.extend(output.versions.unwrap_or_default().into_iter().filter_map(
+                |version| {
+                    Some(VersionEntry {
+                        key: version.key?,
+                        version_id: version.version_id?,
+                        is_latest: version.is_latest.unwrap_or(false),
+                        is_delete_marker: false,
+                        size: version.size.and_then(|size| u64::try_from(size).ok()),
+                        last_modified: version
+                            .last_modified
+                            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                            .map(|date| date.with_timezone(&Utc)),
+                    })
+                },
+            ));
+            versions.extend(
+                output
+                    .delete_markers
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|marker| {
+                        Some(VersionEntry {
+                            key: marker.key?,
+                            version_id: marker.version_id?,
+                            is_latest: marker.is_latest.unwrap_or(false),
+                            is_delete_marker: true,
+                            size: None,
+                            last_modified: marker
+                                .last_modified
+                                .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                                .map(|date| date.with_timezone(&Utc)),
+                        })
+                    }),
+            );
+            if !output.is_truncated.unwrap_or(false) {
+                break;
+            }
+            params.key_marker = output.next_key_marker;
+            params.version_id_marker = output.next_version_id_marker;
+        }
+        // S3 lists versions and delete markers separately, each newest first for every key
+        versions.sort_by(|a, b| {
+            a.key
+                .cmp(&b.key)
+                .then_with(|| b.last_modified.cmp(&a.last_modified))
+        });
+        Ok(versions)
+    }
+
+    async fn object_grants(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<Vec<ObjectGrant>, RBError> {
+        debug!("getting ACL of object at bucket {}, key {}", bucket, key);
+        let params = GetObjectAclRequest {
+            bucket,
+            key,
+            ..Default::default()
+        };
+        let output = self
+            .client
+            .get_object_acl(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(output
+            .grants
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|grant| {
+                let grantee = grant.grantee?;
+                // Groups are only named by a URI like http://acs.amazonaws.com/groups/global/AllUsers
+                let group = grantee
+                    .uri
+                    .as_deref()
+                    .and_then(|uri| uri.rsplit('/').next())
+                    .map(str::to_owned);
+                let name = grantee
+                    .display_name
+                    .or(grantee.email_address)
+                    .or(grantee.id)
+                    .or(group)
+                    .unwrap_or_default();
+                Some(ObjectGrant {
+                    grantee: name,
+                    grantee_type: grantee.type_,
+                    permission: grant.permission.unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+
+    async fn set_object_acl(
+        &self,
+        bucket: String,
+        key: String,
+        acl: String,
+    ) -> Result<(), RBError> {
+        debug!(
+            "setting ACL of object at bucket {}, key {} to {}",
+            bucket, key, acl
+        );
+        let params = PutObjectAclRequest {
+            bucket,
+            key,
+            acl: Some(acl),
+            ..Default::default()
+        };
+        self.client
+            .put_object_acl(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(())
+    }
+
+    async fn object_tags(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<BTreeMap<String, String>, RBError> {
+        debug!("getting tags of object at bucket {}, key {}", bucket, key);
+        let params = GetObjectTaggingRequest {
+            bucket,
+            key,
+            ..Default::default()
+        };
+        let output = self
+            .client
+            .get_object_tagging(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(output
+            .tag_set
+            .into_iter()
+            .map(|tag| (tag.key, tag.value))
+            .collect())
+    }
+
+    async fn set_object_tags(
+        &self,
+        bucket: String,
+        key: String,
+        tags: BTreeMap<String, String>,
+    ) -> Result<(), RBError> {
+        debug!(
+            "setting tags of object at bucket {}, key {} to {:?}",
+            bucket, key, tags
+        );
+        let params = PutObjectTaggingRequest {
+            bucket,
+            key,
+            tagging: Tagging {
+                tag_set: tags
+                    .into_iter()
+                    .map(|(key, value)| Tag { key, value })
+                    .collect(),
+            },
+            ..Default::default()
+        };
+        self.client
+            .put_object_tagging(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(())
+    }
+
+    async fn bucket_cors_rules(&self, bucket: String) -> Result<Vec<CorsRule>, RBError> {
+        debug!("getting CORS rules of bucket {}", bucket);
+        let params = GetBucketCorsRequest {
+            bucket,
+            ..Default::default()
+        };
+        let rules = match self.client.get_bucket_cors(params).await {
+            Ok(output) => output.cors_rules.unwrap_or_default(),
+            Err(e) if is_missing_cors_config(&e) => Vec::new(),
+            Err(e) => return Err(wrap_rusoto_error(e)),
+        };
+        Ok(rules
+            .into_iter()
+            .map(|rule| CorsRule {
+                allowed_origins: rule.allowed_origins,
+                allowed_methods: rule.allowed_methods,
+                allowed_headers: rule.allowed_headers.unwrap_or_default(),
+                expose_headers: rule.expose_headers.unwrap_or_default(),
+                max_age_seconds: rule.max_age_seconds,
+            })
+            .collect())
+    }
+
+    async fn bucket_encryption(&self, bucket: String) -> Result<Option<BucketEncryption>, RBError> {
+        debug!("getting default encryption of bucket {}", bucket);
+        let params = GetBucketEncryptionRequest {
+            bucket,
+            ..Default::default()
+        };
+        let configuration = match self.client.get_bucket_encryption(params).await {
+            Ok(output) => output.server_side_encryption_configuration,
+            Err(e) if is_missing_encryption_config(&e) => None,
+            Err(e) => return Err(wrap_rusoto_error(e)),
+        };
+        // A bucket only ever has the one rule
+        let rule = configuration.and_then(|configuration| configuration.rules.into_iter().next());
+        Ok(rule.and_then(|rule| {
+            let default = rule.apply_server_side_encryption_by_default?;
+            Some(BucketEncryption {
+                algorithm: default.sse_algorithm,
+                kms_key_id: default.kms_master_key_id,
+                bucket_key_enabled: rule.bucket_key_enabled.unwrap_or(false),
+            })
+        }))
+    }
+
+    async fn object_lock_status(
+        &self,
+        bucket: String,
+        key: String,
+    ) -> Result<ObjectLockStatus, RBError> {
+        debug!(
+            "getting object lock status of bucket {} key {}",
+            bucket, key
+        );
+        let retention_params = GetObjectRetentionRequest {
+            bucket: bucket.clone(),
+            key: key.clone(),
+            ..Default::default()
+        };
+        let retention = match self.client.get_object_retention(retention_params).await {
+            Ok(output) => output.retention.unwrap_or_default(),
+            Err(e) if is_missing_lock_config(&e) => Default::default(),
+            Err(e) => return Err(wrap_rusoto_error(e)),
+        };
+        let legal_hold_params = GetObjectLegalHoldRequest {
+            bucket,
+            key,
+            ..Default::default()
+        };
+        let legal_hold = match self.client.get_object_legal_hold(legal_hold_params).await {
+            Ok(output) => output.legal_hold.and_then(|legal_hold| legal_hold.status),
+            Err(e) if is_missing_lock_config(&e) => None,
+            Err(e) => return Err(wrap_rusoto_error(e)),
+        };
+        Ok(ObjectLockStatus {
+            retention_mode: retention.mode,
+            retain_until: retention
+                .retain_until_date
+                .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                .map(|date| date.with_timezone(&Utc)),
+            legal_hold: legal_hold.as_deref() == Some("ON"),
+        })
+    }
+
+    async fn abort_incomplete_upload(
+        &self,
+        bucket: String,
+        key: String,
+        upload_id: String,
+    ) -> Result<(), RBError> {
+        debug!(
+            "aborting multipart upload {} of bucket {} key {}",
+            upload_id, bucket, key
+        );
+        let params = AbortMultipartUploadRequest {
+            bucket,
+            key,
+            upload_id,
+            ..Default::default()
+        };
+        self.client
+            .abort_multipart_upload(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(())
+    }
+
+    async fn bucket_versioning(&self, bucket: String) -> Result<Option<String>, RBError> {
+        debug!("getting versioning status of bucket {}", bucket);
+        let output = self
+            .client
+            .get_bucket_versioning(GetBucketVersioningRequest {
+                bucket,
+                ..Default::default()
+            })
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(output.status)
+    }
+
+    async fn set_bucket_versioning(&self, bucket: String, enabled: bool) -> Result<(), RBError> {
+        let status = if enabled { "Enabled" } else { "Suspended" };
+        debug!("setting versioning of bucket {} to {}", bucket, status);
+        let params = PutBucketVersioningRequest {
+            bucket,
+            versioning_configuration: VersioningConfiguration {
+                status: Some(String::from(status)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        self.client
+            .put_bucket_versioning(params)
+            .await
+            .map_err(wrap_rusoto_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use hyper::{HeaderMap, StatusCode};
+    use rusoto_core::request::HttpResponse;
+
+    // A status code and body
+    type CannedResponse = (u16, Vec<u8>);
+
+    /// Stands in for S3 by answering requests with canned responses: the ones queued up with `then`, in order, and the
+    /// one it was made with after that. It remembers the method and (percent-encoded) path of each request it was sent,
+    /// and its headers.
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockDispatcher {
+        status: u16,
+        body: Vec<u8>,
+        queued: Arc<Mutex<VecDeque<CannedResponse>>>,
+        pub(crate) requests: Arc<Mutex<Vec<(String, String)>>>,
+        headers: Arc<Mutex<Vec<BTreeMap<String, String>>>>,
+    }
+
+    impl MockDispatcher {
+        pub(crate) fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            MockDispatcher {
+                status,
+                body: body.into(),
+                queued: Arc::default(),
+                requests: Arc::default(),
+                headers: Arc::default(),
+            }
+        }
+
+        /// A header that the `index`th request was sent with
+        pub(crate) fn header(&self, index: usize, name: &str) -> Option<String> {
+            self.headers.lock().unwrap()[index].get(name).cloned()
+        }
+
+        pub(crate) fn then(self, status: u16, body: impl Into<Vec<u8>>) -> Self {
+            self.queued.lock().unwrap().push_back((status, body.into()));
+            self
+        }
+    }
+
+    impl DispatchSignedRequest for MockDispatcher {
+        fn dispatch(
+            &self,
+            request: SignedRequest,
+            _timeout: Option<Duration>,
+        ) -> DispatchSignedRequestFuture {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((request.method().to_owned(), request.canonical_path()));
+            self.headers.lock().unwrap().push(
+                request
+                    .headers
+                    .iter()
+                    .map(|(name, values)| {
+                        let values: Vec<String> = values
+                            .iter()
+                            .map(|value| String::from_utf8_lossy(value).into_owned())
+                            .collect();
+                        (name.clone(), values.join(","))
+                    })
+                    .collect(),
+            );
+            let (status, body) = self
+                .queued
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| (self.status, self.body.clone()));
+            let response = HttpResponse {
+                status: StatusCode::from_u16(status).unwrap(),
+                body: ByteStream::from(body),
+                headers: HeaderMap::default(),
+            };
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    // Anonymous and path-style, so that requests don't need credentials and their paths include the bucket
+    pub(crate) fn mock_client(dispatcher: MockDispatcher) -> RBS3 {
+        let config = ClientConfig {
+            region: Region::UsEast1,
+            force_path_style: true,
+            no_sign_request: true,
+            ..Default::default()
+        };
+        RBS3::with_dispatcher(config, dispatcher).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checksum_put_encodes_the_key_once() {
+        let source_path = std::env::temp_dir().join("rustbucket-checksum-put-test");
+        std::fs::write(&source_path, "hello").unwrap();
+        let dispatcher = MockDispatcher::new(200, "");
+        let s3 = mock_client(dispatcher.clone());
+
+        s3.put_object(
+            String::from("bucket"),
+            String::from("some dir/100% ü.txt"),
+            &source_path,
+            HashMap::new(),
+            Some(ChecksumAlgorithm::Sha256),
+        )
+        .await
+        .unwrap();
+
+        std::fs::remove_file(&source_path).unwrap();
+        assert_eq!(
+            *dispatcher.requests.lock().unwrap(),
+            vec![(
+                String::from("PUT"),
+                String::from("/bucket/some%20dir/100%25%20%C3%BC.txt")
+            )]
+        );
+    }
+
+    #[test]
+    fn access_point_arns_are_split_from_paths() {
+        let (arn, rest) = AccessPointArn::split_path(
+            "arn:aws:s3:eu-west-1:123456789012:accesspoint:photos/2023/a.jpg",
+        )
+        .unwrap();
+        assert_eq!(
+            arn,
+            AccessPointArn {
+                partition: String::from("aws"),
+                region: String::from("eu-west-1"),
+                account: String::from("123456789012"),
+                name: String::from("photos"),
+            }
+        );
+        assert_eq!(rest, "2023/a.jpg");
+        assert_eq!(
+            arn.arn(),
+            "arn:aws:s3:eu-west-1:123456789012:accesspoint/photos"
+        );
+        assert_eq!(
+            arn.path_component(),
+            "arn:aws:s3:eu-west-1:123456789012:accesspoint:photos"
+        );
+        assert_eq!(
+            arn.hostname(),
+            "photos-123456789012.s3-accesspoint.eu-west-1.amazonaws.com"
+        );
+
+        // AWS's own spelling works too, and China has a domain of its own
+        let (arn, rest) =
+            AccessPointArn::split_path("arn:aws-cn:s3:cn-north-1:123456789012:accesspoint/logs")
+                .unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            arn.hostname(),
+            "logs-123456789012.s3-accesspoint.cn-north-1.amazonaws.com.cn"
+        );
+    }
+
+    #[test]
+    fn other_arns_and_bucket_names_are_not_access_points() {
+        for path in &[
+            "my-bucket/arn:aws:s3:us-east-1:1:accesspoint:x",
+            "arn:aws:s3:us-east-1:123456789012:accesspoint:",
+            "arn:aws:s3:us-east-1:123456789012:accesspoint/",
+            "arn:aws:s3::123456789012:accesspoint:photos",
+            "arn:aws:s3:us-east-1::accesspoint:photos",
+            "arn:aws:sqs:us-east-1:123456789012:accesspoint:photos",
+            "arn:aws:s3:us-east-1:123456789012:bucket/photos",
+            "arn:aws:s3:us-east-1",
+        ] {
+            assert_eq!(AccessPointArn::split_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn access_point_arns_stand_in_for_bucket_names_in_paths() {
+        let s3_path = S3Path::try_from_path(Path::new(
+            "/arn:aws:s3:us-east-1:123456789012:accesspoint:photos/2023/a.jpg",
+        ))
+        .unwrap();
+        assert_eq!(
+            s3_path.bucket.as_deref(),
+            Some("arn:aws:s3:us-east-1:123456789012:accesspoint:photos")
+        );
+        assert_eq!(s3_path.key.as_deref(), Some("2023/a.jpg"));
+    }
+
+    // What S3 sends back when a request fails
+    fn error_body(code: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>It went wrong</Message>\
+             <RequestId>1234</RequestId></Error>",
+            code
+        )
+    }
+
+    async fn list_error(status: u16, code: &str) -> RBError {
+        let s3 = mock_client(MockDispatcher::new(status, error_body(code)));
+        s3.list_keys(String::from("bucket"), String::new())
+            .await
+            .unwrap_err()
+    }
+
+    fn error_code(e: &RBError) -> &str {
+        let source = e.source().unwrap();
+        &source.downcast_ref::<S3ServiceError>().unwrap().code
+    }
+
+    #[tokio::test]
+    async fn error_codes_come_from_the_parsed_error_or_the_response_body() {
+        // rusoto_s3 parses this one into ListObjectsV2Error::NoSuchBucket
+        let e = list_error(404, "NoSuchBucket").await;
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(error_code(&e), "NoSuchBucket");
+
+        // and leaves these in the body of RusotoError::Unknown
+        let e = list_error(403, "AccessDenied").await;
+        assert_eq!(e.kind(), ErrorKind::AccessDenied);
+        assert_eq!(error_code(&e), "AccessDenied");
+        let e = list_error(503, "SlowDown").await;
+        assert_eq!(e.kind(), ErrorKind::Throttled);
+        assert_eq!(error_code(&e), "SlowDown");
+    }
+
+    #[test]
+    fn tags_are_encoded_as_a_query_string() {
+        let tags: BTreeMap<String, String> = vec![
+            (String::from("project"), String::from("rust bucket")),
+            (String::from("path"), String::from("a/b&c=d")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            encode_tagging(&tags),
+            "path=a%2Fb%26c%3Dd&project=rust%20bucket"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_multipart_copy_that_fails_to_complete_is_aborted() {
+        let dispatcher = MockDispatcher::new(204, "")
+            .then(
+                200,
+                "<Tagging><TagSet><Tag><Key>a</Key><Value>b</Value></Tag></TagSet></Tagging>",
+            )
+            .then(
+                200,
+                "<InitiateMultipartUploadResult><Bucket>dest</Bucket><Key>big</Key>\
+                 <UploadId>upload-1</UploadId></InitiateMultipartUploadResult>",
+            )
+            .then(
+                200,
+                "<CopyPartResult><ETag>\"part-1\"</ETag></CopyPartResult>",
+            )
+            .then(500, error_body("InternalError"));
+        let s3 = mock_client(dispatcher.clone());
+        let source = HeadObjectOutput {
+            content_length: Some(1024),
+            ..Default::default()
+        };
+
+        let e = s3
+            .copy_object_in_parts(
+                source,
+                String::from("source"),
+                String::from("big"),
+                CreateMultipartUploadRequest {
+                    bucket: String::from("dest"),
+                    key: String::from("big"),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Transient);
+        let requests = dispatcher.requests.lock().unwrap();
+        let methods: Vec<&str> = requests.iter().map(|(method, _)| method.as_str()).collect();
+        assert_eq!(methods, vec!["GET", "POST", "PUT", "POST", "DELETE"]);
+        assert_eq!(requests[4].1, "/dest/big");
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_multipart_copy_is_aborted() {
+        let dispatcher = MockDispatcher::new(204, "");
+        let s3 = mock_client(dispatcher.clone());
+        let upload = IncompleteUploadGuard {
+            client: s3.client.clone(),
+            request: Some(AbortMultipartUploadRequest {
+                bucket: String::from("dest"),
+                key: String::from("big"),
+                upload_id: String::from("upload-1"),
+                ..Default::default()
+            }),
+        };
+        drop(upload);
+
+        for _ in 0..100 {
+            if !dispatcher.requests.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            *dispatcher.requests.lock().unwrap(),
+            vec![(String::from("DELETE"), String::from("/dest/big"))]
+        );
+    }
+
+    const PUBLIC_OBJECT_ACL: &str =
+        "<AccessControlPolicy><Owner><ID>owner</ID></Owner><AccessControlList>\
+        <Grant><Grantee><ID>owner</ID></Grantee><Permission>FULL_CONTROL</Permission></Grant>\
+        <Grant><Grantee><URI>http://acs.amazonaws.com/groups/global/AllUsers</URI></Grantee>\
+        <Permission>READ</Permission></Grant>\
+        </AccessControlList></AccessControlPolicy>";
+
+    #[tokio::test]
+    async fn edits_in_place_keep_the_objects_acl() {
+        let dispatcher = MockDispatcher::new(
+            200,
+            "<CopyObjectResult><ETag>\"copy\"</ETag></CopyObjectResult>",
+        )
+        .then(200, "")
+        .then(200, PUBLIC_OBJECT_ACL);
+        let s3 = mock_client(dispatcher.clone());
+
+        s3.copy_object_as(
+            String::from("bucket"),
+            String::from("public"),
+            String::from("bucket"),
+            String::from("public"),
+            CopyChanges {
+                storage_class: Some(String::from("STANDARD_IA")),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let methods: Vec<String> = dispatcher
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(method, _)| method.clone())
+            .collect();
+        // The ACL is read before the copy replaces the object, and goes along with it rather than after it
+        assert_eq!(methods, vec!["HEAD", "GET", "PUT"]);
+        assert_eq!(
+            dispatcher.header(2, "x-amz-grant-read").as_deref(),
+            Some("uri=\"http://acs.amazonaws.com/groups/global/AllUsers\"")
+        );
+        assert_eq!(
+            dispatcher.header(2, "x-amz-grant-full-control").as_deref(),
+            Some("id=\"owner\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn copies_somewhere_new_dont_need_the_acl() {
+        let dispatcher = MockDispatcher::new(
+            200,
+            "<CopyObjectResult><ETag>\"copy\"</ETag></CopyObjectResult>",
+        )
+        .then(200, "");
+        let s3 = mock_client(dispatcher.clone());
+
+        s3.copy_object_as(
+            String::from("source"),
+            String::from("public"),
+            String::from("dest"),
+            String::from("public"),
+            CopyChanges::default(),
+        )
+        .await
+        .unwrap();
+        let methods: Vec<String> = dispatcher
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(method, _)| method.clone())
+            .collect();
+        assert_eq!(methods, vec!["HEAD", "PUT"]);
+        assert_eq!(dispatcher.header(1, "x-amz-grant-read"), None);
+    }
+
+    // CRC-32 (the IEEE one), which event stream messages are checked with
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in data {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    // One event stream message, with string headers
+    fn event_message(headers: &[(&str, &str)], payload: &[u8]) -> Vec<u8> {
+        let mut encoded_headers = Vec::new();
+        for (name, value) in headers {
+            encoded_headers.push(name.len() as u8);
+            encoded_headers.extend_from_slice(name.as_bytes());
+            encoded_headers.push(7);
+            encoded_headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            encoded_headers.extend_from_slice(value.as_bytes());
+        }
+        let total_length = 16 + encoded_headers.len() + payload.len();
+        let mut message = Vec::new();
+        message.extend_from_slice(&(total_length as u32).to_be_bytes());
+        message.extend_from_slice(&(encoded_headers.len() as u32).to_be_bytes());
+        message.extend_from_slice(&crc32(&message).to_be_bytes());
+        message.extend_from_slice(&encoded_headers);
+        message.extend_from_slice(payload);
+        message.extend_from_slice(&crc32(&message).to_be_bytes());
+        message
+    }
+
+    /// A SelectObjectContent response body carrying `records`, one Records event each, then a Stats and an End event
+    pub(crate) fn select_response(records: &[&str]) -> Vec<u8> {
+        let event = |event_type| vec![(":message-type", "event"), (":event-type", event_type)];
+        let mut body = Vec::new();
+        for chunk in records {
+            body.extend(event_message(&event("Records"), chunk.as_bytes()));
+        }
+        body.extend(event_message(&event("Stats"), b"<Stats></Stats>"));
+        body.extend(event_message(&event("End"), b""));
+        body
+    }
+
+    #[tokio::test]
+    async fn select_writes_out_the_records_it_gets_back() {
+        let dispatcher = MockDispatcher::new(200, select_response(&["a,1\nb,2\n", "c,3\n"]));
+        let s3 = mock_client(dispatcher.clone());
+        let query = SelectQuery {
+            expression: String::from("SELECT * FROM s3object s WHERE s.n < 4"),
+            input: SelectFormat::Csv,
+            output: SelectFormat::Csv,
+        };
+
+        let mut records = Vec::new();
+        s3.select_object(
+            String::from("bucket"),
+            String::from("data.csv"),
+            &query,
+            &mut records,
+        )
+        .await
+        .unwrap();
+        assert_eq!(String::from_utf8(records).unwrap(), "a,1\nb,2\nc,3\n");
+        assert_eq!(
+            *dispatcher.requests.lock().unwrap(),
+            vec![(String::from("POST"), String::from("/bucket/data.csv"))]
+        );
+    }
+
+    #[test]
+    fn select_requests_escape_the_expression() {
+        let query = SelectQuery {
+            expression: String::from("SELECT * FROM s3object s WHERE s.n < 4 AND s.a <> 'x&y'"),
+            input: SelectFormat::Json,
+            output: SelectFormat::Json,
+        };
+        let body = select_request_body("data.json.gz", &query);
+        assert!(body.contains(
+            "<Expression>SELECT * FROM s3object s WHERE s.n &lt; 4 AND s.a &lt;&gt; 'x&amp;y'</Expression>"
+        ));
+        assert!(body
+            .contains("<CompressionType>GZIP</CompressionType><JSON><Type>DOCUMENT</Type></JSON>"));
     }
 }
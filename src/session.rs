@@ -0,0 +1,51 @@
+use crate::error::RBError;
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_to_string, write};
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Where an interactive session left off, so that the next one can pick up from there with `--resume`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SessionState {
+    pub remote_cwd: PathBuf,
+    pub local_cwd: PathBuf,
+    /// The last directory visited in each bucket
+    pub bucket_dirs: HashMap<String, PathBuf>,
+}
+
+// Kept next to the config file, e.g. ~/.config/rustbucket/session.json on Linux
+fn state_path() -> Option<PathBuf> {
+    dirs_next::config_dir().map(|config_dir| config_dir.join("rustbucket").join("session.json"))
+}
+
+impl SessionState {
+    /// Returns the last saved session, if there is one
+    pub fn load() -> Result<Option<Self>, RBError> {
+        let path = match state_path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(RBError::wrap_config),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(RBError::wrap_io(e)),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), RBError> {
+        let path = match state_path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(state_dir) = path.parent() {
+            create_dir_all(state_dir).map_err(RBError::wrap_io)?;
+        }
+        let contents = serde_json::to_string_pretty(self).map_err(RBError::wrap_io)?;
+        write(path, contents).map_err(RBError::wrap_io)
+    }
+}
@@ -0,0 +1,310 @@
+use crate::error::RBError;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use rusoto_core::credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{DispatchSignedRequest, HttpClient, Region};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+// How much of a cached token's remaining lifetime we insist on before trusting it for another request
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 60;
+const DEVICE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The SSO settings from an AWS profile, either set directly on the profile (the legacy format) or pulled in from an
+/// `[sso-session name]` section
+#[derive(Debug, Clone)]
+pub struct SsoSettings {
+    pub start_url: String,
+    pub sso_region: String,
+    pub account_id: String,
+    pub role_name: String,
+    /// Set when the settings came from an `[sso-session]` section, which changes how the token cache is keyed
+    pub session_name: Option<String>,
+}
+
+impl SsoSettings {
+    pub fn from_profile(
+        profile: &HashMap<String, String>,
+        sso_session: Option<&HashMap<String, String>>,
+    ) -> Option<Self> {
+        let session_name = profile.get("sso_session").cloned();
+        let lookup = |setting: &str| {
+            sso_session
+                .and_then(|session| session.get(setting))
+                .or_else(|| profile.get(setting))
+                .cloned()
+        };
+
+        Some(SsoSettings {
+            start_url: lookup("sso_start_url")?,
+            sso_region: lookup("sso_region")?,
+            account_id: profile.get("sso_account_id")?.clone(),
+            role_name: profile.get("sso_role_name")?.clone(),
+            session_name,
+        })
+    }
+
+    // Same location and naming scheme as the AWS CLI, so that `aws sso login` and rustbucket can share tokens
+    fn token_cache_path(&self) -> Option<PathBuf> {
+        let cache_key = self.session_name.as_ref().unwrap_or(&self.start_url);
+        let digest = Sha1::digest(cache_key.as_bytes());
+        let file_name: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        dirs_next::home_dir().map(|home| {
+            home.join(".aws")
+                .join("sso")
+                .join("cache")
+                .join(file_name + ".json")
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedToken {
+    access_token: String,
+    expires_at: String,
+    #[serde(default)]
+    region: Option<String>,
+    #[serde(default)]
+    start_url: Option<String>,
+}
+
+impl CachedToken {
+    fn is_fresh(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.expires_at).is_ok_and(|expires_at| {
+            expires_at.with_timezone(&Utc) - Utc::now()
+                > chrono::Duration::seconds(TOKEN_EXPIRY_MARGIN_SECS)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterClientResponse {
+    client_id: String,
+    client_secret: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StartDeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri_complete: String,
+    #[serde(default)]
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateTokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+#[derive(Deserialize)]
+struct OidcErrorResponse {
+    error: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    // Milliseconds since the epoch
+    expiration: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetRoleCredentialsResponse {
+    role_credentials: RoleCredentials,
+}
+
+/// Gets role credentials from AWS SSO (IAM Identity Center), running the device authorization flow in the terminal
+/// when there's no unexpired SSO token cached
+pub struct SsoProvider {
+    settings: SsoSettings,
+    http_client: HttpClient,
+}
+
+impl SsoProvider {
+    pub fn new(settings: SsoSettings) -> Result<Self, RBError> {
+        Ok(SsoProvider {
+            settings,
+            http_client: HttpClient::new().map_err(RBError::wrap_io)?,
+        })
+    }
+
+    fn region(&self) -> Result<Region, CredentialsError> {
+        Region::from_str(&self.settings.sso_region).map_err(CredentialsError::new)
+    }
+
+    // Sends an unsigned JSON request; these SSO APIs authenticate with bearer tokens or client secrets instead of SigV4
+    async fn send(&self, mut request: SignedRequest) -> Result<(u16, Vec<u8>), CredentialsError> {
+        request.add_header("content-type", "application/json");
+        // Fills in the query string and host/content headers that signing would normally take care of
+        request.complement();
+        let response = self
+            .http_client
+            .dispatch(request, None)
+            .await
+            .map_err(CredentialsError::new)?
+            .buffer()
+            .await
+            .map_err(CredentialsError::new)?;
+        Ok((response.status.as_u16(), response.body.to_vec()))
+    }
+
+    async fn oidc_post(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<(u16, Vec<u8>), CredentialsError> {
+        let region = self.region()?;
+        let mut request = SignedRequest::new("POST", "oidc", &region, path);
+        request.set_hostname(Some(format!("oidc.{}.amazonaws.com", region.name())));
+        request.set_payload(Some(body.to_string().into_bytes()));
+        self.send(request).await
+    }
+
+    async fn device_authorization(&self) -> Result<CachedToken, CredentialsError> {
+        let (status, body) = self
+            .oidc_post(
+                "/client/register",
+                serde_json::json!({ "clientName": "rustbucket", "clientType": "public" }),
+            )
+            .await?;
+        let client: RegisterClientResponse = parse_json(status, &body)?;
+
+        let (status, body) = self
+            .oidc_post(
+                "/device_authorization",
+                serde_json::json!({
+                    "clientId": client.client_id,
+                    "clientSecret": client.client_secret,
+                    "startUrl": self.settings.start_url,
+                }),
+            )
+            .await?;
+        let authorization: StartDeviceAuthorizationResponse = parse_json(status, &body)?;
+
+        eprintln!(
+            "Your AWS SSO session has expired. To sign in, open this URL in a browser and confirm the code {}:\n{}",
+            authorization.user_code, authorization.verification_uri_complete
+        );
+
+        let mut interval = Duration::from_secs(authorization.interval.unwrap_or(5));
+        loop {
+            tokio::time::sleep(interval).await;
+            let (status, body) = self
+                .oidc_post(
+                    "/token",
+                    serde_json::json!({
+                        "clientId": client.client_id,
+                        "clientSecret": client.client_secret,
+                        "grantType": DEVICE_GRANT_TYPE,
+                        "deviceCode": authorization.device_code,
+                    }),
+                )
+                .await?;
+
+            if (200..300).contains(&status) {
+                let token: CreateTokenResponse = parse_json(status, &body)?;
+                eprintln!("AWS SSO sign-in successful.");
+                return Ok(CachedToken {
+                    access_token: token.access_token,
+                    expires_at: (Utc::now() + chrono::Duration::seconds(token.expires_in))
+                        .to_rfc3339(),
+                    region: Some(self.settings.sso_region.clone()),
+                    start_url: Some(self.settings.start_url.clone()),
+                });
+            }
+
+            match serde_json::from_slice::<OidcErrorResponse>(&body) {
+                Ok(err) if err.error == "authorization_pending" => continue,
+                Ok(err) if err.error == "slow_down" => interval += Duration::from_secs(5),
+                _ => return Err(http_error(status, &body)),
+            }
+        }
+    }
+
+    async fn access_token(&self) -> Result<String, CredentialsError> {
+        let cache_path = self.settings.token_cache_path();
+        let cached = cache_path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|contents| serde_json::from_str::<CachedToken>(&contents).ok())
+            .filter(CachedToken::is_fresh);
+        if let Some(token) = cached {
+            return Ok(token.access_token);
+        }
+
+        let token = self.device_authorization().await?;
+        if let Some(path) = cache_path {
+            // Failing to cache is only an inconvenience (we'll have to sign in again next time), not an error
+            let written = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&path, serde_json::to_string(&token).unwrap_or_default()));
+            if let Err(e) = written {
+                eprintln!("Unable to cache AWS SSO token at {}: {}", path.display(), e);
+            }
+        }
+        Ok(token.access_token)
+    }
+}
+
+fn http_error(status: u16, body: &[u8]) -> CredentialsError {
+    CredentialsError::new(format!(
+        "AWS SSO request failed with HTTP {}: {}",
+        status,
+        String::from_utf8_lossy(body)
+    ))
+}
+
+fn parse_json<T>(status: u16, body: &[u8]) -> Result<T, CredentialsError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    if !(200..300).contains(&status) {
+        return Err(http_error(status, body));
+    }
+    serde_json::from_slice(body).map_err(CredentialsError::new)
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for SsoProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let access_token = self.access_token().await?;
+
+        let region = self.region()?;
+        let mut request = SignedRequest::new("GET", "sso", &region, "/federation/credentials");
+        request.set_hostname(Some(format!("portal.sso.{}.amazonaws.com", region.name())));
+        request.add_param("role_name", self.settings.role_name.as_str());
+        request.add_param("account_id", self.settings.account_id.as_str());
+        request.add_header("x-amz-sso_bearer_token", &access_token);
+
+        let (status, body) = self.send(request).await?;
+        let role_credentials =
+            parse_json::<GetRoleCredentialsResponse>(status, &body)?.role_credentials;
+
+        Ok(AwsCredentials::new(
+            role_credentials.access_key_id,
+            role_credentials.secret_access_key,
+            Some(role_credentials.session_token),
+            Utc.timestamp_millis_opt(role_credentials.expiration)
+                .single(),
+        ))
+    }
+}
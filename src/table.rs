@@ -0,0 +1,185 @@
+use std::env;
+use std::io::{stdout, IsTerminal};
+
+// Space between columns
+const COLUMN_GAP: &str = "  ";
+
+// Below this, a truncated last column would be too short to be worth reading, so it's left to wrap instead
+const MIN_TRUNCATED_WIDTH: usize = 8;
+
+/// How a column's contents line up within it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Align {
+    Left,
+    /// For numbers, like sizes, so that their digits line up
+    Right,
+}
+
+/// Lines rows of fields up into columns for human-readable output. Every column is as wide as its widest field, and if
+/// there's a maximum width, the last column (usually a free-form description) is cut short to fit.
+#[derive(Debug, Default)]
+pub struct Table {
+    headers: Option<Vec<String>>,
+    rows: Vec<Vec<String>>,
+    alignments: Vec<Align>,
+    max_width: Option<usize>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Table::default()
+    }
+
+    /// Adds a header line above the rows
+    pub fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = Some(headers.iter().map(|header| String::from(*header)).collect());
+        self
+    }
+
+    /// Columns are left-aligned unless they're set otherwise here
+    pub fn align(mut self, column: usize, align: Align) -> Self {
+        if self.alignments.len() <= column {
+            self.alignments.resize(column + 1, Align::Left);
+        }
+        self.alignments[column] = align;
+        self
+    }
+
+    /// Keeps lines within `max_width` characters where possible, e.g. the terminal width from `terminal_width()`
+    pub fn max_width(mut self, max_width: Option<usize>) -> Self {
+        self.max_width = max_width;
+        self
+    }
+
+    /// Adds a row. Rows may have fewer fields than others; the missing ones are left blank.
+    pub fn push(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    /// The table's lines, headers first
+    pub fn render(&self) -> Vec<String> {
+        let all_rows: Vec<&Vec<String>> = self.headers.iter().chain(self.rows.iter()).collect();
+        let column_count = all_rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        let mut widths = vec![0; column_count];
+        for row in &all_rows {
+            for (column, field) in row.iter().enumerate() {
+                widths[column] = widths[column].max(field.chars().count());
+            }
+        }
+        if let (Some(max_width), Some(last)) = (self.max_width, column_count.checked_sub(1)) {
+            let others: usize = widths[..last].iter().sum::<usize>() + COLUMN_GAP.len() * last;
+            let available = max_width.saturating_sub(others);
+            if available >= MIN_TRUNCATED_WIDTH {
+                widths[last] = widths[last].min(available);
+            }
+        }
+
+        all_rows
+            .iter()
+            .map(|row| {
+                let fields: Vec<String> = (0..column_count)
+                    .map(|column| {
+                        let field = row.get(column).map(String::as_str).unwrap_or("");
+                        let field = truncate(field, widths[column]);
+                        let align = self.alignments.get(column).copied().unwrap_or(Align::Left);
+                        match align {
+                            Align::Left => format!("{:<width$}", field, width = widths[column]),
+                            Align::Right => format!("{:>width$}", field, width = widths[column]),
+                        }
+                    })
+                    .collect();
+                // Padding the last column would only leave trailing spaces
+                String::from(fields.join(COLUMN_GAP).trim_end())
+            })
+            .collect()
+    }
+}
+
+// Cuts `field` down to `width` characters, marking that it was cut with an ellipsis
+fn truncate(field: &str, width: usize) -> String {
+    if field.chars().count() <= width {
+        return String::from(field);
+    }
+    let mut truncated: String = field.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// How wide the terminal is, if stdout is one: as the terminal reports it, or else as the shell says through `COLUMNS`.
+/// Only Unix terminals are asked directly, so elsewhere it's `COLUMNS` or nothing.
+pub fn terminal_width() -> Option<usize> {
+    if !stdout().is_terminal() {
+        return None;
+    }
+    terminal_columns().or_else(|| env::var("COLUMNS").ok()?.parse().ok())
+}
+
+#[cfg(unix)]
+fn terminal_columns() -> Option<usize> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes a winsize to the pointer it's given, which points at `size`
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    // Some terminals (a serial console, say) answer with a size of zero
+    (result == 0 && size.ws_col > 0).then(|| usize::from(size.ws_col))
+}
+
+#[cfg(not(unix))]
+fn terminal_columns() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|field| String::from(*field)).collect()
+    }
+
+    #[test]
+    fn columns_line_up_and_right_aligned_ones_pad_on_the_left() {
+        let mut table = Table::new()
+            .headers(&["NAME", "SIZE", "CLASS"])
+            .align(1, Align::Right);
+        table.push(row(&["a.txt", "5", "STANDARD"]));
+        table.push(row(&["longer-name.csv", "12345", "GLACIER"]));
+        assert_eq!(
+            table.render(),
+            vec![
+                "NAME              SIZE  CLASS",
+                "a.txt                5  STANDARD",
+                "longer-name.csv  12345  GLACIER",
+            ]
+        );
+    }
+
+    #[test]
+    fn short_rows_are_padded_with_blank_fields() {
+        let mut table = Table::new();
+        table.push(row(&["a", "b", "c"]));
+        table.push(row(&["dd"]));
+        table.push(row(&["e", "ffff"]));
+        assert_eq!(table.render(), vec!["a   b     c", "dd", "e   ffff"]);
+    }
+
+    #[test]
+    fn the_last_column_is_cut_short_to_fit() {
+        let mut table = Table::new().max_width(Some(20));
+        table.push(row(&["1", "a description that goes on"]));
+        table.push(row(&["22", "short"]));
+        assert_eq!(table.render(), vec!["1   a description t…", "22  short"]);
+        assert!(table.render().iter().all(|line| line.chars().count() <= 20));
+    }
+
+    #[test]
+    fn a_last_column_too_narrow_to_read_is_left_whole() {
+        let mut table = Table::new().max_width(Some(10));
+        table.push(row(&["a long first field", "a description"]));
+        assert_eq!(table.render(), vec!["a long first field  a description"]);
+    }
+}
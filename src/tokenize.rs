@@ -0,0 +1,228 @@
+use crate::error::{ErrorKind, RBError};
+
+use std::collections::HashMap;
+use std::env;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Tracks whether the end of `input` is still inside a quoted string or right after an unescaped backslash. Inside
+// single quotes, backslashes are just backslashes, like in a POSIX shell.
+fn scan_state(input: &str) -> (Option<char>, bool) {
+    let mut open_quote = None;
+    let mut escaped = false;
+    for c in input.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (open_quote, c) {
+            (Some('\''), '\'') => open_quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some('"'), '"') => open_quote = None,
+            (None, '\'') | (None, '"') => open_quote = Some(c),
+            _ => {}
+        }
+    }
+    (open_quote, escaped)
+}
+
+/// Whether a command needs more lines before it can be run: it either ends with a `\` line continuation or has a quote
+/// that hasn't been closed yet
+pub fn is_incomplete(input: &str) -> bool {
+    let (open_quote, escaped) = scan_state(input);
+    open_quote.is_some() || escaped
+}
+
+/// Splits a command at its first `|` that isn't quoted or escaped, returning what comes before and after it
+pub fn split_pipe(input: &str) -> (&str, Option<&str>) {
+    let pipe_index = input
+        .match_indices('|')
+        .map(|(index, _)| index)
+        .find(|&index| scan_state(&input[..index]) == (None, false));
+    match pipe_index {
+        Some(index) => (&input[..index], Some(&input[index + 1..])),
+        None => (input, None),
+    }
+}
+
+/// Splits a line of commands at each `;` that isn't quoted or escaped, so `ls; get "a;b"` is two commands
+pub fn split_commands(input: &str) -> Vec<&str> {
+    let mut commands = Vec::new();
+    let mut start = 0;
+    for (index, _) in input.match_indices(';') {
+        if scan_state(&input[start..index]) == (None, false) {
+            commands.push(&input[start..index]);
+            start = index + 1;
+        }
+    }
+    commands.push(&input[start..]);
+    commands
+}
+
+/// Removes backslash-newline line continuations, joining the lines they split up, like a shell does
+pub fn join_continuations(input: &str) -> String {
+    input.replace("\\\r\n", "").replace("\\\n", "")
+}
+
+// Reads the variable reference after a `$`, either `NAME` or `{NAME}`, and returns what it expands to: the session
+// variable with that name, or else the environment variable. References to variables that aren't set are left as they
+// are, so that a `$` in an object key doesn't have to be escaped.
+fn expand_variable(chars: &mut Peekable<Chars>, variables: &HashMap<String, String>) -> String {
+    let mut reference = String::from("$");
+    let mut var_name = String::new();
+    if chars.peek() == Some(&'{') {
+        reference.extend(chars.next());
+        for c in chars.by_ref() {
+            reference.push(c);
+            if c == '}' {
+                break;
+            }
+            var_name.push(c);
+        }
+        if !reference.ends_with('}') {
+            return reference;
+        }
+    } else {
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            reference.push(c);
+            var_name.push(c);
+        }
+    }
+
+    if var_name.is_empty() {
+        return reference;
+    }
+    match variables.get(&var_name) {
+        Some(value) => value.clone(),
+        None => env::var(&var_name).unwrap_or(reference),
+    }
+}
+
+/// One word of a command, after quotes and escapes have been removed from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub text: String,
+    /// Whether the word's first character was quoted or escaped, which makes a leading `%` or `>` literal: `"%1"` and
+    /// `\>x` aren't a listing reference or a redirect, but `>'out file'` is still a redirect
+    pub leading_quoted: bool,
+}
+
+/// Splits a command into words like a POSIX shell would: whitespace separates words unless it's inside single or double
+/// quotes, and a backslash escapes the character after it (except inside single quotes, and inside double quotes it
+/// only escapes `"`, `\`, and `$`). Quotes themselves are removed, so `get "monthly reports/jan.xlsx"` has two words.
+/// `$VAR` and `${VAR}` are expanded from `variables` or the environment, except inside single quotes.
+pub fn tokenize(input: &str, variables: &HashMap<String, String>) -> Result<Vec<Word>, RBError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // Tracked separately from `word.is_empty()` so that "" can be given as an empty argument
+    let mut in_word = false;
+    let mut leading_quoted = false;
+    let mut open_quote = None;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let was_empty = word.is_empty();
+        let mut literal = open_quote.is_some();
+        match (open_quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => open_quote = None,
+            (Some('"'), '\\') if matches!(chars.peek(), Some('"') | Some('\\') | Some('$')) => {
+                word.extend(chars.next());
+            }
+            (Some('"'), '$') => word.push_str(&expand_variable(&mut chars, variables)),
+            (Some(_), _) => word.push(c),
+            (None, '\'') | (None, '"') => {
+                open_quote = Some(c);
+                in_word = true;
+            }
+            (None, '\\') => {
+                word.extend(chars.next());
+                in_word = true;
+                literal = true;
+            }
+            (None, '$') => {
+                word.push_str(&expand_variable(&mut chars, variables));
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(Word {
+                        text: std::mem::take(&mut word),
+                        leading_quoted,
+                    });
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+        if was_empty && !word.is_empty() {
+            leading_quoted = literal;
+        }
+    }
+
+    if open_quote.is_some() {
+        return Err(RBError::new(ErrorKind::InvalidCommand));
+    }
+    if in_word {
+        words.push(Word {
+            text: word,
+            leading_quoted,
+        });
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_commands_ignores_quoted_and_escaped_semicolons() {
+        assert_eq!(
+            split_commands(r#"cd bucket; get "a;b" 'c;d' e\;f;ls"#),
+            vec!["cd bucket", r#" get "a;b" 'c;d' e\;f"#, "ls"]
+        );
+        assert_eq!(split_commands("ls"), vec!["ls"]);
+    }
+
+    #[test]
+    fn tokenize_expands_variables_except_in_single_quotes() {
+        let variables = HashMap::from([(String::from("dir"), String::from("2024 reports"))]);
+        let words = tokenize(r#"get "$dir/a" '$dir/b' ${dir}c \$dir"#, &variables).unwrap();
+        let texts: Vec<&str> = words.iter().map(|word| word.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec!["get", "2024 reports/a", "$dir/b", "2024 reportsc", "$dir"]
+        );
+    }
+
+    #[test]
+    fn tokenize_records_which_words_start_quoted() {
+        let words = tokenize(r#"get %1 "%2" \>x >'y' a"b"c '%'3"#, &HashMap::new()).unwrap();
+        let marked: Vec<(&str, bool)> = words
+            .iter()
+            .map(|word| (word.text.as_str(), word.leading_quoted))
+            .collect();
+        assert_eq!(
+            marked,
+            vec![
+                ("get", false),
+                ("%1", false),
+                ("%2", true),
+                (">x", true),
+                (">y", false),
+                ("abc", false),
+                ("%3", true)
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_rejects_unclosed_quotes() {
+        assert!(tokenize(r#"get "unfinished"#, &HashMap::new()).is_err());
+        assert_eq!(tokenize(r#"get """#, &HashMap::new()).unwrap().len(), 2);
+    }
+}